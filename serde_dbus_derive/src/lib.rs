@@ -0,0 +1,213 @@
+//! Derive macro for `serde_dbus::dbus_type::DbusType`.
+//!
+//! This is re-exported by `serde_dbus` when its `derive` feature is
+//! enabled, and should be used from there. The derived signature follows
+//! what `serde_dbus` serializes the type's derived `Serialize` instance
+//! to, including the `rename`, `transparent`, `skip` and
+//! `skip_serializing` serde attributes. Serde attributes that change the
+//! signature in ways this cannot follow, such as `flatten`, are rejected.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{
+    parse_macro_input, parse_quote, token, Attribute, Data, DeriveInput, Error, Expr, Fields,
+    GenericParam, LitStr, Result, Token,
+};
+
+/// Derives `DbusType` for a type that also derives `Serialize`.
+///
+/// Enums with both unit and non-unit variants are rejected, as unit
+/// variants serialize with a different signature unless the policy
+/// serializes the enum as tagged structs.
+///
+/// ```compile_fail
+/// #[derive(serde::Serialize, serde_dbus::dbus_type::DbusType)]
+/// enum Mixed {
+///     A,
+///     B(u8),
+/// }
+/// ```
+///
+/// So are structs without fields, as DBus has no empty struct, and the
+/// serde attributes `flatten`, `skip_serializing_if`, `with`,
+/// `serialize_with`, `untagged`, `tag` and `content`.
+///
+/// ```compile_fail
+/// #[derive(serde::Serialize, serde_dbus::dbus_type::DbusType)]
+/// struct Empty;
+/// ```
+///
+/// ```compile_fail
+/// #[derive(serde::Serialize, serde_dbus::dbus_type::DbusType)]
+/// struct Outer {
+///     #[serde(flatten)]
+///     inner: std::collections::HashMap<String, u8>,
+/// }
+/// ```
+#[proc_macro_derive(DbusType)]
+pub fn derive_dbus_type(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+// Serde attributes that change the signature in ways that depend on
+// values, or that cannot be seen from here
+const UNSUPPORTED: &[&str] = &[
+    "flatten",
+    "skip_serializing_if",
+    "with",
+    "serialize_with",
+    "untagged",
+    "tag",
+    "content",
+];
+
+#[derive(Default)]
+struct SerdeAttrs {
+    rename: Option<String>,
+    transparent: bool,
+    skip: bool,
+}
+
+fn serde_attrs(attrs: &[Attribute]) -> Result<SerdeAttrs> {
+    let mut res = SerdeAttrs::default();
+    for attr in attrs {
+        if !attr.path().is_ident("serde") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                // Either `rename = ".."` or `rename(serialize = "..")`
+                if meta.input.peek(Token![=]) {
+                    res.rename = Some(meta.value()?.parse::<LitStr>()?.value());
+                } else {
+                    meta.parse_nested_meta(|meta| {
+                        let value = meta.value()?.parse::<LitStr>()?.value();
+                        if meta.path.is_ident("serialize") {
+                            res.rename = Some(value);
+                        }
+                        Ok(())
+                    })?;
+                }
+            } else if meta.path.is_ident("transparent") {
+                res.transparent = true;
+            } else if meta.path.is_ident("skip") || meta.path.is_ident("skip_serializing") {
+                res.skip = true;
+            } else if UNSUPPORTED.iter().any(|name| meta.path.is_ident(name)) {
+                return Err(meta.error("DbusType cannot follow this serde attribute"));
+            } else if meta.input.peek(Token![=]) {
+                // Ignore every other attribute, and its value.
+                meta.value()?.parse::<Expr>()?;
+            } else if meta.input.peek(token::Paren) {
+                meta.parse_nested_meta(|meta| {
+                    if meta.input.peek(Token![=]) {
+                        meta.value()?.parse::<Expr>()?;
+                    }
+                    Ok(())
+                })?;
+            }
+            Ok(())
+        })?;
+    }
+    Ok(res)
+}
+
+// The code to write the signatures of the serialized fields, in order
+fn field_signatures(fields: &Fields) -> Result<Vec<TokenStream2>> {
+    let mut res = Vec::new();
+    for field in fields.iter() {
+        if serde_attrs(&field.attrs)?.skip {
+            continue;
+        }
+        let ty = &field.ty;
+        res.push(quote! {
+            <#ty as ::serde_dbus::dbus_type::DbusType>::write_signature(policy, sig);
+        });
+    }
+    Ok(res)
+}
+
+fn expand(mut input: DeriveInput) -> Result<TokenStream2> {
+    let attrs = serde_attrs(&input.attrs)?;
+    let name = attrs.rename.unwrap_or_else(|| input.ident.to_string());
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let fields = field_signatures(&data.fields)?;
+            if fields.is_empty() {
+                return Err(Error::new_spanned(
+                    &input.ident,
+                    "DbusType cannot be derived for structs without fields",
+                ));
+            }
+            match &data.fields {
+                _ if attrs.transparent => quote! { #(#fields)* },
+                Fields::Named(_) => quote! {
+                    ::serde_dbus::dbus_type::write_struct_signature(policy, #name, sig, |sig| {
+                        #(#fields)*
+                    });
+                },
+                Fields::Unnamed(unnamed) if unnamed.unnamed.len() == 1 => {
+                    quote! { #(#fields)* }
+                }
+                Fields::Unnamed(_) | Fields::Unit => quote! {
+                    sig.push(b'(');
+                    #(#fields)*
+                    sig.push(b')');
+                },
+            }
+        }
+        Data::Enum(data) => {
+            // Unit variants serialize with a different signature than
+            // other variants, unless the policy tags them all.
+            let mut unit = None;
+            for variant in &data.variants {
+                if serde_attrs(&variant.attrs)?.skip {
+                    continue;
+                }
+                let variant_unit = matches!(variant.fields, Fields::Unit);
+                if *unit.get_or_insert(variant_unit) != variant_unit {
+                    return Err(Error::new_spanned(
+                        variant,
+                        "DbusType cannot be derived for enums with both unit and non-unit variants",
+                    ));
+                }
+            }
+            let unit = unit.unwrap_or(true);
+            quote! {
+                ::serde_dbus::dbus_type::write_enum_signature(policy, #name, #unit, sig);
+            }
+        }
+        Data::Union(data) => {
+            return Err(Error::new(
+                data.union_token.span,
+                "DbusType cannot be derived for unions",
+            ))
+        }
+    };
+
+    for param in &mut input.generics.params {
+        if let GenericParam::Type(param) = param {
+            param
+                .bounds
+                .push(parse_quote!(::serde_dbus::dbus_type::DbusType));
+        }
+    }
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::serde_dbus::dbus_type::DbusType for #ident #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn write_signature<P: ::serde_dbus::ser::serializer_policy::SerializerPolicy>(
+                policy: &P,
+                sig: &mut ::std::vec::Vec<u8>,
+            ) {
+                #body
+            }
+        }
+    })
+}
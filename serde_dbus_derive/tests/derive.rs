@@ -0,0 +1,160 @@
+use serde::Serialize;
+use serde_dbus::dbus_type::{check_signature, typed_seq, DbusType};
+use serde_dbus::error::Result;
+use serde_dbus::ser::serializer_policy::{
+    DefaultSerializerPolicy, FieldContext, SeqSerializationStyle, SerializerPolicy,
+    StronglyTypedSerializerPolicy, StructSerializationStyle,
+};
+use serde_dbus::ser::{serialize, serialize_with_policy};
+
+#[derive(Clone, Debug, Serialize, DbusType)]
+struct Device {
+    name: String,
+    #[serde(default = "Default::default", rename = "Id")]
+    id: u32,
+    #[serde(skip)]
+    _cache: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Serialize, DbusType)]
+struct Devices {
+    devices: Vec<Device>,
+    owner: (String, i64),
+}
+
+#[derive(Clone, Debug, Serialize, DbusType)]
+struct Newtype(Vec<String>);
+
+#[derive(Clone, Debug, Serialize, DbusType)]
+struct Pair<T>(T, T);
+
+#[derive(Clone, Debug, Serialize, DbusType)]
+#[serde(rename = "Renamed")]
+struct RenamedStruct {
+    a: u8,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Debug, Serialize, DbusType)]
+enum State {
+    On,
+    Off,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Debug, Serialize, DbusType)]
+enum Shape {
+    Circle(f64),
+    Rect { w: f64, h: f64 },
+}
+
+#[derive(Clone, Debug)]
+struct MixedPolicy;
+
+impl SerializerPolicy for MixedPolicy {
+    fn query_struct_name(&self, name: &str) -> StructSerializationStyle {
+        match name {
+            "Device" => StructSerializationStyle::StronglyTyped,
+            _ => StructSerializationStyle::Dict,
+        }
+    }
+
+    fn query_seq(&self, field: Option<&FieldContext>) -> SeqSerializationStyle {
+        match field.map(|field| field.field_name) {
+            Some("devices") => typed_seq::<Device, _>(self),
+            _ => SeqSerializationStyle::Inferred,
+        }
+    }
+}
+
+#[test]
+fn struct_signatures() {
+    assert_eq!(Device::signature(), b"a{sv}".to_vec());
+    assert_eq!(
+        Device::signature_with_policy(&StronglyTypedSerializerPolicy),
+        b"(su)".to_vec()
+    );
+    assert_eq!(
+        Devices::signature_with_policy(&StronglyTypedSerializerPolicy),
+        b"(a(su)(sx))".to_vec()
+    );
+    assert_eq!(
+        Devices::signature_with_policy(&MixedPolicy),
+        b"a{sv}".to_vec()
+    );
+    assert_eq!(Newtype::signature(), b"as".to_vec());
+    assert_eq!(Pair::<i32>::signature(), b"(ii)".to_vec());
+}
+
+#[test]
+fn renamed_struct() {
+    #[derive(Clone, Debug)]
+    struct RenamedPolicy;
+
+    impl SerializerPolicy for RenamedPolicy {
+        fn query_struct_name(&self, name: &str) -> StructSerializationStyle {
+            match name {
+                "Renamed" => StructSerializationStyle::StronglyTyped,
+                _ => StructSerializationStyle::Dict,
+            }
+        }
+    }
+
+    assert_eq!(
+        RenamedStruct::signature_with_policy(&RenamedPolicy),
+        b"(y)".to_vec()
+    );
+}
+
+#[test]
+fn enum_signatures() {
+    assert_eq!(State::signature(), b"u".to_vec());
+    assert_eq!(Shape::signature(), b"a{sv}".to_vec());
+}
+
+#[test]
+fn matches_serializer() -> Result<()> {
+    let data = Devices {
+        devices: vec![Device {
+            name: "a".to_owned(),
+            id: 3,
+            _cache: vec![],
+        }],
+        owner: ("b".to_owned(), 4),
+    };
+    let message = serialize_with_policy(&data, StronglyTypedSerializerPolicy)?;
+    assert_eq!(
+        message.signature,
+        Devices::signature_with_policy(&StronglyTypedSerializerPolicy)
+    );
+
+    let message = serialize(&data)?;
+    check_signature::<Devices, _>(&DefaultSerializerPolicy, "a{sv}")?;
+    assert_eq!(message.signature, Devices::signature());
+    Ok(())
+}
+
+#[test]
+fn empty_typed_seq() -> Result<()> {
+    #[derive(Clone, Debug, Serialize)]
+    struct Wrapper {
+        devices: Vec<Device>,
+    }
+
+    let data = Wrapper { devices: vec![] };
+    let message = serialize_with_policy(&data, MixedPolicy)?;
+    assert_eq!(message.signature, b"a{sv}".to_vec());
+    assert_eq!(
+        message.data,
+        vec![
+            24, 0, 0, 0, // 24 bytes of array
+            0, 0, 0, 0, // padding(8)
+            7, 0, 0, 0, // 7 byte string
+            100, 101, 118, 105, 99, 101, 115, 0, // "devices"
+            5, 97, 40, 115, 117, 41, 0, // signature "a(su)"
+            0, // padding(4)
+            0, 0, 0, 0, // empty array
+        ]
+    );
+    Ok(())
+}
@@ -0,0 +1,406 @@
+//! Static DBus signatures for Rust types.
+//!
+//! The serializer learns signatures from values as it serializes them,
+//! which means that it cannot know the item signature of an empty
+//! array, and that a mismatch with an interface's declared signature
+//! is only discovered once a message is sent. The [`DbusType`] trait
+//! instead computes a signature for a type from the type alone.
+//!
+//! Values serialize to this signature by themselves only when the
+//! serializer can learn it from them. Empty sequences with the inferred
+//! style are serialized as `av`, empty maps without the typed style as
+//! `a{sv}`, and a `None` that is serialized as an empty array as `av`. Serializing values with
+//! [`serialize_with_signature`] and the computed signature makes them
+//! all fit it.
+//!
+//! With the `derive` feature, `DbusType` can be derived for structs and
+//! enums that also derive `Serialize`. Signatures are computed with
+//! respect to a [`SerializerPolicy`], so that structs serialize to a
+//! struct or a dict as the policy decides.
+//!
+//! [`SerializerPolicy`]: crate::ser::serializer_policy::SerializerPolicy
+//! [`serialize_with_signature`]: crate::ser::serialize_with_signature
+
+use crate::error::{Error, Result};
+use crate::ser::serializer_policy::{
+    DefaultSerializerPolicy, EnumSerializationStyle, MapSerializationStyle,
+    OptionSerializationStyle, SeqSerializationStyle, SerializerPolicy, StructSerializationStyle,
+};
+#[cfg(unix)]
+use crate::types::{BorrowedUnixFd, UnixFd};
+use crate::types::{ObjectPath, Signature, UnixFdIndex};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+use std::sync::Arc;
+
+#[cfg(feature = "derive")]
+pub use serde_dbus_derive::DbusType;
+
+/// A type with a signature that does not depend on its value.
+pub trait DbusType {
+    /// Appends the signature of this type, as serialized with `policy`,
+    /// to `sig`.
+    fn write_signature<P: SerializerPolicy>(policy: &P, sig: &mut Vec<u8>);
+
+    fn signature_with_policy<P: SerializerPolicy>(policy: &P) -> Vec<u8> {
+        let mut sig = Vec::new();
+        Self::write_signature(policy, &mut sig);
+        sig
+    }
+
+    fn signature() -> Vec<u8> {
+        Self::signature_with_policy(&DefaultSerializerPolicy)
+    }
+}
+
+/// Checks that `T` serializes with `policy` to the `expected` signature,
+/// such as one taken from an interface's introspection data.
+pub fn check_signature<T: DbusType, P: SerializerPolicy>(policy: &P, expected: &str) -> Result<()> {
+    let sig = T::signature_with_policy(policy);
+    if sig != expected.as_bytes() {
+        return Err(Error::MismatchSignature(expected.as_bytes().to_vec(), sig));
+    }
+    Ok(())
+}
+
+/// A sequence style for use in [`SerializerPolicy::query_seq`], for
+/// sequences of `T`.
+pub fn typed_seq<T: DbusType, P: SerializerPolicy>(policy: &P) -> SeqSerializationStyle {
+    SeqSerializationStyle::Typed(T::signature_with_policy(policy))
+}
+
+/// A map style for use in [`SerializerPolicy::query_map`], for maps
+/// from `K` to `V`.
+pub fn typed_map<K: DbusType, V: DbusType, P: SerializerPolicy>(
+    policy: &P,
+) -> MapSerializationStyle {
+    MapSerializationStyle::Typed(
+        K::signature_with_policy(policy),
+        V::signature_with_policy(policy),
+    )
+}
+
+// Used by the derive macro for structs with named fields
+#[doc(hidden)]
+pub fn write_struct_signature<P: SerializerPolicy>(
+    policy: &P,
+    name: &str,
+    sig: &mut Vec<u8>,
+    write_fields: impl FnOnce(&mut Vec<u8>),
+) {
+    match policy.query_struct_name(name) {
+        StructSerializationStyle::Dict => sig.extend_from_slice(b"a{sv}"),
+        StructSerializationStyle::StronglyTyped => {
+            sig.push(b'(');
+            write_fields(sig);
+            sig.push(b')');
+        }
+    }
+}
+
+// Used by the derive macro for enums, which rejects enums with both
+// unit and non-unit variants. Variants serialize as a tagged struct if
+// the policy says so. Otherwise unit variants serialize as their index
+// or name, and other variants as a single-entry dict.
+#[doc(hidden)]
+pub fn write_enum_signature<P: SerializerPolicy>(
+    policy: &P,
+    name: &str,
+    unit: bool,
+    sig: &mut Vec<u8>,
+) {
+    match policy.query_enum(name) {
+        EnumSerializationStyle::NameTaggedStruct => sig.extend_from_slice(b"(sv)"),
+        EnumSerializationStyle::IndexTaggedStruct => sig.extend_from_slice(b"(uv)"),
+        style if unit => sig.push(if style.tags_by_name() { b's' } else { b'u' }),
+        _ => sig.extend_from_slice(b"a{sv}"),
+    }
+}
+
+macro_rules! basic_dbus_type {
+    ($type:ty, $sig:expr) => {
+        impl DbusType for $type {
+            fn write_signature<P: SerializerPolicy>(_: &P, sig: &mut Vec<u8>) {
+                sig.extend_from_slice($sig);
+            }
+        }
+    };
+}
+
+basic_dbus_type!(bool, b"b");
+basic_dbus_type!(u8, b"y");
+basic_dbus_type!(i8, b"n");
+basic_dbus_type!(i16, b"n");
+basic_dbus_type!(u16, b"q");
+basic_dbus_type!(i32, b"i");
+basic_dbus_type!(u32, b"u");
+basic_dbus_type!(i64, b"x");
+basic_dbus_type!(u64, b"t");
+basic_dbus_type!(f32, b"d");
+basic_dbus_type!(f64, b"d");
+basic_dbus_type!(char, b"u");
+basic_dbus_type!(str, b"s");
+basic_dbus_type!(String, b"s");
+basic_dbus_type!(ObjectPath, b"o");
+basic_dbus_type!(Signature, b"g");
+basic_dbus_type!(UnixFdIndex, b"h");
+#[cfg(unix)]
+basic_dbus_type!(UnixFd, b"h");
+#[cfg(unix)]
+basic_dbus_type!(BorrowedUnixFd<'_>, b"h");
+
+macro_rules! pointer_dbus_type {
+    ($($type:ty),*) => {
+        $(
+            impl<T: DbusType + ?Sized> DbusType for $type {
+                fn write_signature<P: SerializerPolicy>(policy: &P, sig: &mut Vec<u8>) {
+                    T::write_signature(policy, sig);
+                }
+            }
+        )*
+    };
+}
+
+pointer_dbus_type!(&T, &mut T, Box<T>, Rc<T>, Arc<T>);
+
+// When a `None` is omitted from dicts or replaced by a default, only
+// the `Some` case has a signature.
+impl<T: DbusType> DbusType for Option<T> {
+    fn write_signature<P: SerializerPolicy>(policy: &P, sig: &mut Vec<u8>) {
+        match policy.query_option(None) {
+            OptionSerializationStyle::Omit | OptionSerializationStyle::Default(_) => {
+                T::write_signature(policy, sig)
+            }
+            OptionSerializationStyle::Array => {
+                sig.push(b'a');
+                T::write_signature(policy, sig);
+            }
+            OptionSerializationStyle::Variant => sig.push(b'v'),
+        }
+    }
+}
+
+// The policy is asked about sequences and maps with no field, as a
+// type's signature cannot depend on where it is used.
+macro_rules! seq_dbus_type {
+    ($($type:ty),*) => {
+        $(
+            impl<T: DbusType> DbusType for $type {
+                fn write_signature<P: SerializerPolicy>(policy: &P, sig: &mut Vec<u8>) {
+                    sig.push(b'a');
+                    match policy.query_seq(None) {
+                        SeqSerializationStyle::Variant => sig.push(b'v'),
+                        SeqSerializationStyle::Typed(item_sig) => sig.extend_from_slice(&item_sig),
+                        SeqSerializationStyle::Inferred => T::write_signature(policy, sig),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+seq_dbus_type!([T], Vec<T>, VecDeque<T>, BTreeSet<T>, HashSet<T>);
+
+macro_rules! map_dbus_type {
+    ($($type:ty),*) => {
+        $(
+            impl<K: DbusType, V: DbusType> DbusType for $type {
+                fn write_signature<P: SerializerPolicy>(policy: &P, sig: &mut Vec<u8>) {
+                    sig.extend_from_slice(b"a{");
+                    match policy.query_map(None) {
+                        MapSerializationStyle::Variant => {
+                            K::write_signature(policy, sig);
+                            sig.push(b'v');
+                        }
+                        MapSerializationStyle::Typed(key_sig, value_sig) => {
+                            sig.extend_from_slice(&key_sig);
+                            sig.extend_from_slice(&value_sig);
+                        }
+                        MapSerializationStyle::Inferred => {
+                            K::write_signature(policy, sig);
+                            V::write_signature(policy, sig);
+                        }
+                    }
+                    sig.push(b'}');
+                }
+            }
+        )*
+    };
+}
+
+map_dbus_type!(BTreeMap<K, V>, HashMap<K, V>);
+
+// Fixed-size arrays are serialized by serde as tuples.
+impl<T: DbusType, const N: usize> DbusType for [T; N] {
+    fn write_signature<P: SerializerPolicy>(policy: &P, sig: &mut Vec<u8>) {
+        sig.push(b'(');
+        for _ in 0..N {
+            T::write_signature(policy, sig);
+        }
+        sig.push(b')');
+    }
+}
+
+macro_rules! tuple_dbus_type {
+    ($($name:ident)+) => {
+        impl<$($name: DbusType),+> DbusType for ($($name,)+) {
+            fn write_signature<P: SerializerPolicy>(policy: &P, sig: &mut Vec<u8>) {
+                sig.push(b'(');
+                $($name::write_signature(policy, sig);)+
+                sig.push(b')');
+            }
+        }
+    };
+}
+
+tuple_dbus_type!(A);
+tuple_dbus_type!(A B);
+tuple_dbus_type!(A B C);
+tuple_dbus_type!(A B C D);
+tuple_dbus_type!(A B C D E);
+tuple_dbus_type!(A B C D E F);
+tuple_dbus_type!(A B C D E F G);
+tuple_dbus_type!(A B C D E F G H);
+tuple_dbus_type!(A B C D E F G H I);
+tuple_dbus_type!(A B C D E F G H I J);
+tuple_dbus_type!(A B C D E F G H I J K);
+tuple_dbus_type!(A B C D E F G H I J K L);
+
+#[cfg(test)]
+mod tests {
+    use crate::dbus_type::{check_signature, write_enum_signature, DbusType};
+    use crate::error::Result;
+    use crate::ser::serializer_policy::{
+        DefaultSerializerPolicy, EnumSerializationStyle, FieldContext, MapSerializationStyle,
+        OptionSerializationStyle, SeqSerializationStyle, SerializerPolicy,
+        StructSerializationStyle,
+    };
+    use crate::ser::{serialize, serialize_with_policy, serialize_with_signature_and_policy};
+    use crate::types::{ObjectPath, Signature, UnixFdIndex};
+    use serde::Serialize;
+    use std::collections::{BTreeMap, HashMap};
+
+    #[test]
+    fn builtin_signatures() -> Result<()> {
+        assert_eq!(<(i32, String, f64)>::signature(), b"(isd)".to_vec());
+        assert_eq!(<Vec<Vec<u8>>>::signature(), b"aay".to_vec());
+        assert_eq!(
+            <HashMap<u32, Vec<(String, bool)>>>::signature(),
+            b"a{ua(sb)}".to_vec()
+        );
+        assert_eq!(<[i16; 3]>::signature(), b"(nnn)".to_vec());
+        assert_eq!(
+            <(ObjectPath, Vec<Signature>, UnixFdIndex)>::signature(),
+            b"(oagh)".to_vec()
+        );
+        check_signature::<Vec<(String, u64)>, _>(&DefaultSerializerPolicy, "a(st)")
+    }
+
+    #[test]
+    fn matches_serializer() -> Result<()> {
+        let data = (vec![(1u8, "a".to_owned())], 3i64, true);
+        let message = serialize(&data)?;
+        assert_eq!(
+            message.signature,
+            <(Vec<(u8, String)>, i64, bool)>::signature()
+        );
+        Ok(())
+    }
+
+    #[derive(Clone)]
+    struct ArrayOptionPolicy;
+
+    impl SerializerPolicy for ArrayOptionPolicy {
+        fn query_struct_name(&self, _: &str) -> StructSerializationStyle {
+            StructSerializationStyle::StronglyTyped
+        }
+
+        fn query_option(&self, _: Option<&FieldContext>) -> OptionSerializationStyle {
+            OptionSerializationStyle::Array
+        }
+    }
+
+    // Serializes the value by itself, and to fit its type's signature
+    fn serialize_both<T: DbusType + Serialize>(value: T) -> Result<(Vec<u8>, Vec<u8>)> {
+        let sig = T::signature_with_policy(&ArrayOptionPolicy);
+        let sig_str = String::from_utf8(sig.clone()).expect("signatures are ASCII");
+        let fitted = serialize_with_signature_and_policy(&value, &sig_str, ArrayOptionPolicy)?;
+        assert_eq!(fitted.signature, sig);
+        let message = serialize_with_policy(&value, ArrayOptionPolicy)?;
+        Ok((sig, message.signature))
+    }
+
+    #[test]
+    fn matches_serializer_empty() -> Result<()> {
+        let (sig, serialized) = serialize_both(vec![vec![1u32]])?;
+        assert_eq!(sig, serialized);
+        let (sig, serialized) = serialize_both(Vec::<Vec<u32>>::new())?;
+        assert_eq!((sig, serialized), (b"aau".to_vec(), b"av".to_vec()));
+
+        let mut map = BTreeMap::new();
+        map.insert(1u8, (true, "a".to_owned()));
+        let (sig, serialized) = serialize_both(map)?;
+        assert_eq!(sig, serialized);
+        let (sig, serialized) = serialize_both(BTreeMap::<u8, (bool, String)>::new())?;
+        assert_eq!((sig, serialized), (b"a{y(bs)}".to_vec(), b"a{sv}".to_vec()));
+
+        let (sig, serialized) = serialize_both(Some(2i16))?;
+        assert_eq!(sig, serialized);
+        let (sig, serialized) = serialize_both(None::<i16>)?;
+        assert_eq!((sig, serialized), (b"an".to_vec(), b"av".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn seq_and_map_styles() {
+        #[derive(Clone)]
+        struct VariantPolicy;
+
+        impl SerializerPolicy for VariantPolicy {
+            fn query_struct_name(&self, _: &str) -> StructSerializationStyle {
+                StructSerializationStyle::Dict
+            }
+
+            fn query_seq(&self, _: Option<&FieldContext>) -> SeqSerializationStyle {
+                SeqSerializationStyle::Variant
+            }
+
+            fn query_map(&self, _: Option<&FieldContext>) -> MapSerializationStyle {
+                MapSerializationStyle::Typed(b"s".to_vec(), b"x".to_vec())
+            }
+        }
+
+        assert_eq!(
+            <Vec<u32>>::signature_with_policy(&VariantPolicy),
+            b"av".to_vec()
+        );
+        assert_eq!(
+            <HashMap<String, u8>>::signature_with_policy(&VariantPolicy),
+            b"a{sx}".to_vec()
+        );
+    }
+
+    #[test]
+    fn enum_signatures() {
+        #[derive(Clone)]
+        struct TaggedPolicy;
+
+        impl SerializerPolicy for TaggedPolicy {
+            fn query_struct_name(&self, _: &str) -> StructSerializationStyle {
+                StructSerializationStyle::Dict
+            }
+
+            fn query_enum(&self, _: &str) -> EnumSerializationStyle {
+                EnumSerializationStyle::NameTaggedStruct
+            }
+        }
+
+        let mut sig = Vec::new();
+        write_enum_signature(&TaggedPolicy, "E", true, &mut sig);
+        write_enum_signature(&TaggedPolicy, "E", false, &mut sig);
+        write_enum_signature(&DefaultSerializerPolicy, "E", false, &mut sig);
+        write_enum_signature(&DefaultSerializerPolicy, "E", true, &mut sig);
+        assert_eq!(sig, b"(sv)(sv)a{sv}u".to_vec());
+    }
+}
@@ -1,25 +1,169 @@
 use crate::error::{Error, Result};
-use crate::message::Message;
+use crate::message::header::MAX_MESSAGE_LEN;
+use crate::message::{Endianness, Message, MAX_ARRAY_LEN, MAX_VARIANT_DEPTH};
 use crate::primitives::DbusPrimitive;
-use byteorder::{ByteOrder, LE};
-
+use crate::ser::serializer_policy::{FieldContext, FieldNameCase, OptionSerializationStyle};
+use crate::signature::{MAX_ARRAY_DEPTH, MAX_STRUCT_DEPTH};
+use crate::types::{newtype_signature, UnixFdIndex};
+#[cfg(unix)]
+use crate::types::{with_received_fd, UNIX_FD_NAME};
+use crate::value::{VALUE_NAME, VARIANT_NAME};
+use byteorder::{ByteOrder, BE, LE};
+
+use std::collections::HashMap;
 use std::convert::TryInto;
-
-use serde::de::{self, DeserializeSeed, EnumAccess, MapAccess, SeqAccess, VariantAccess, Visitor};
+#[cfg(unix)]
+use std::os::unix::io::BorrowedFd;
+use std::str::from_utf8;
+
+use serde::de::{
+    self, value::BorrowedStrDeserializer, DeserializeSeed, EnumAccess, IgnoredAny,
+    IntoDeserializer, MapAccess, SeqAccess, Unexpected, VariantAccess, Visitor,
+};
 use serde::Deserialize;
 
 mod internal;
-use internal::{ArrayDeserializer, DataBuffer, Deserializer};
+use internal::{ArrayDeserializer, DataBuffer, Deserializer, PathSegment};
 
 /// This is the entry point to the deserializer and
 /// it converts from a [`Message`] to any type that implements
 /// Serde's `Deserialize` trait.
 ///
+/// Errors while deserializing the value are wrapped in
+/// [`Error::InValue`], which gives the path through the value to where
+/// the error happened, such as `.devices[3].Name`, the byte offset in
+/// the message's data of the last value read, and the index in the
+/// signature of the value being deserialized. Within variants, that is
+/// the index of the outermost `v`.
+///
 /// [`Message`]: crate::message::Message
 pub fn from_message<'a, T: Deserialize<'a>>(mesg: &'a Message) -> Result<T> {
-    let mut buff = DataBuffer::from_message(mesg);
-    let de = Deserializer::<LE>::from_message_and_buffer(&mut buff, mesg);
-    let t = T::deserialize(de)?;
+    from_message_with_options(mesg, DeserializerOptions::default())
+}
+
+/// Settings for the deserializer, for where the message alone does not
+/// say how a value was serialized. These must match how the message was
+/// serialized.
+#[derive(Clone, Debug, Default)]
+pub struct DeserializerOptions {
+    /// How `Option`s were serialized, as decided by
+    /// [`SerializerPolicy::query_option`], by the names of the struct
+    /// and the field that they are the value of. `Option`s that are not
+    /// listed, including those that are not the value of a struct field,
+    /// use `default_option_style`.
+    ///
+    /// [`SerializerPolicy::query_option`]: crate::ser::serializer_policy::SerializerPolicy::query_option
+    pub option_styles: HashMap<(String, String), OptionSerializationStyle>,
+
+    /// How `Option`s not listed in `option_styles` were serialized.
+    pub default_option_style: OptionSerializationStyle,
+
+    /// Whether to accept variants with an empty signature, as
+    /// serialized for `None` with [`OptionSerializationStyle::Variant`]
+    /// when [`SerializerPolicy::allow_empty_variants`] is set.
+    ///
+    /// [`SerializerPolicy::allow_empty_variants`]: crate::ser::serializer_policy::SerializerPolicy::allow_empty_variants
+    pub allow_empty_variants: bool,
+
+    /// The naming conventions for the dict keys of structs serialized as
+    /// dicts, by the name of the struct, and of the struct variants of
+    /// enums, by the name of the enum, as decided by
+    /// [`SerializerPolicy::query_field_case`]. Those that are not
+    /// listed use `default_field_case`. Keys are matched to fields by
+    /// converting the field names, so custom conventions need not be
+    /// reversible.
+    ///
+    /// [`SerializerPolicy::query_field_case`]: crate::ser::serializer_policy::SerializerPolicy::query_field_case
+    pub field_cases: HashMap<String, FieldNameCase>,
+
+    /// The naming convention for the dict keys of structs and enums not
+    /// listed in `field_cases`.
+    pub default_field_case: FieldNameCase,
+
+    /// Limits on the data accepted, for messages from untrusted sources.
+    pub limits: DeserializerLimits,
+}
+
+/// Limits on the data the deserializer accepts, so that a hostile
+/// message fails with an error rather than using up memory or the
+/// stack. These default to the maximums of the DBus specification.
+/// Container depths count the containers of enclosing variants.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeserializerLimits {
+    /// The longest array, in bytes. Longer arrays fail with
+    /// [`Error::ArrayTooLong`].
+    pub max_array_len: usize,
+
+    /// The longest message, in bytes. Longer messages fail with
+    /// [`Error::MessageTooLong`].
+    pub max_message_len: usize,
+
+    /// The deepest nesting of arrays. Deeper arrays fail with
+    /// [`Error::ArrayNestingTooDeep`].
+    pub max_array_depth: usize,
+
+    /// The deepest nesting of structs and dict entries. Deeper ones fail
+    /// with [`Error::StructNestingTooDeep`].
+    pub max_struct_depth: usize,
+
+    /// The deepest nesting of variants. Deeper variants fail with
+    /// [`Error::VariantNestingTooDeep`].
+    pub max_variant_depth: usize,
+}
+
+impl Default for DeserializerLimits {
+    fn default() -> Self {
+        Self {
+            max_array_len: MAX_ARRAY_LEN,
+            max_message_len: MAX_MESSAGE_LEN,
+            max_array_depth: MAX_ARRAY_DEPTH,
+            max_struct_depth: MAX_STRUCT_DEPTH,
+            max_variant_depth: MAX_VARIANT_DEPTH,
+        }
+    }
+}
+
+impl DeserializerOptions {
+    fn field_case(&self, name: &str) -> FieldNameCase {
+        match self.field_cases.get(name) {
+            Some(case) => *case,
+            None => self.default_field_case,
+        }
+    }
+
+    fn option_style(&self, field: Option<&FieldContext>) -> &OptionSerializationStyle {
+        let style = field.and_then(|field| {
+            let key = (field.struct_name.to_owned(), field.field_name.to_owned());
+            self.option_styles.get(&key)
+        });
+        style.unwrap_or(&self.default_option_style)
+    }
+}
+
+/// Like [`from_message`], but with the given [`DeserializerOptions`].
+pub fn from_message_with_options<'a, T: Deserialize<'a>>(
+    mesg: &'a Message,
+    options: DeserializerOptions,
+) -> Result<T> {
+    match mesg.endianness {
+        Endianness::Little => deserialize_message::<LE, T>(mesg, options),
+        Endianness::Big => deserialize_message::<BE, T>(mesg, options),
+    }
+}
+
+fn deserialize_message<'a, B: ByteOrder, T: Deserialize<'a>>(
+    mesg: &'a Message,
+    options: DeserializerOptions,
+) -> Result<T> {
+    if mesg.data.len() > options.limits.max_message_len {
+        return Err(Error::MessageTooLong(
+            mesg.data.len() as u64,
+            options.limits.max_message_len,
+        ));
+    }
+    let mut buff = DataBuffer::from_message(mesg, options);
+    let de = Deserializer::<B>::from_message_and_buffer(&mut buff, mesg);
+    let t = T::deserialize(de).map_err(|err| buff.context(err))?;
     buff.complete()?;
     Ok(t)
 }
@@ -56,9 +200,11 @@ impl<'de, 'a, B: ByteOrder> de::Deserializer<'de> for Deserializer<'a, 'de, B> {
                 de.deserialize_any(visitor)
             }
             b'{' => self.deserialize_seq(visitor),
-            b'h' => Err(Error::UnsupportedSignatureCharacter(sig[0])), // UNIX_FD
-            b'o' => Err(Error::UnsupportedSignatureCharacter(sig[0])), // OBJECT_PATH
-            b'g' => Err(Error::UnsupportedSignatureCharacter(sig[0])), // SIGNATURE
+            b'o' | b'g' => self.deserialize_str(visitor),
+            b'h' => {
+                let i = B::read_u32(&self.read_align_signature_value::<4>(b'h')?);
+                visitor.visit_u32(i)
+            }
             _ => Err(Error::UnrecognizedSignatureCharacter(sig[0])),
         }
     }
@@ -68,7 +214,7 @@ impl<'de, 'a, B: ByteOrder> de::Deserializer<'de> for Deserializer<'a, 'de, B> {
         V: Visitor<'de>,
     {
         let mut de = self.possible_variant()?;
-        let i = B::read_u32(&de.read_align_signature_value::<4>(bool::signature(), 4)?);
+        let i = B::read_u32(&de.read_align_signature_value::<4>(bool::signature())?);
         if i > 1 {
             return Err(Error::InvalidBoolValue(i));
         }
@@ -80,7 +226,7 @@ impl<'de, 'a, B: ByteOrder> de::Deserializer<'de> for Deserializer<'a, 'de, B> {
         V: Visitor<'de>,
     {
         let mut de = self.possible_variant()?;
-        let i = B::read_i16(&de.read_align_signature_value::<2>(i16::signature(), 2)?);
+        let i = B::read_i16(&de.read_align_signature_value::<2>(i16::signature())?);
         visitor.visit_i8(i as i8)
     }
 
@@ -89,7 +235,7 @@ impl<'de, 'a, B: ByteOrder> de::Deserializer<'de> for Deserializer<'a, 'de, B> {
         V: Visitor<'de>,
     {
         let mut de = self.possible_variant()?;
-        let i = B::read_i16(&de.read_align_signature_value::<2>(i16::signature(), 2)?);
+        let i = B::read_i16(&de.read_align_signature_value::<2>(i16::signature())?);
         visitor.visit_i16(i)
     }
 
@@ -98,7 +244,7 @@ impl<'de, 'a, B: ByteOrder> de::Deserializer<'de> for Deserializer<'a, 'de, B> {
         V: Visitor<'de>,
     {
         let mut de = self.possible_variant()?;
-        let i = B::read_i32(&de.read_align_signature_value::<4>(i32::signature(), 4)?);
+        let i = B::read_i32(&de.read_align_signature_value::<4>(i32::signature())?);
         visitor.visit_i32(i)
     }
 
@@ -107,7 +253,7 @@ impl<'de, 'a, B: ByteOrder> de::Deserializer<'de> for Deserializer<'a, 'de, B> {
         V: Visitor<'de>,
     {
         let mut de = self.possible_variant()?;
-        let i = B::read_i64(&de.read_align_signature_value::<8>(i64::signature(), 8)?);
+        let i = B::read_i64(&de.read_align_signature_value::<8>(i64::signature())?);
         visitor.visit_i64(i)
     }
 
@@ -116,7 +262,7 @@ impl<'de, 'a, B: ByteOrder> de::Deserializer<'de> for Deserializer<'a, 'de, B> {
         V: Visitor<'de>,
     {
         let mut de = self.possible_variant()?;
-        let i = de.read_align_signature_value::<1>(u8::signature(), 1)?[0];
+        let i = de.read_align_signature_value::<1>(u8::signature())?[0];
         visitor.visit_u8(i)
     }
 
@@ -125,7 +271,7 @@ impl<'de, 'a, B: ByteOrder> de::Deserializer<'de> for Deserializer<'a, 'de, B> {
         V: Visitor<'de>,
     {
         let mut de = self.possible_variant()?;
-        let i = B::read_u16(&de.read_align_signature_value::<2>(u16::signature(), 2)?);
+        let i = B::read_u16(&de.read_align_signature_value::<2>(u16::signature())?);
         visitor.visit_u16(i)
     }
 
@@ -134,7 +280,7 @@ impl<'de, 'a, B: ByteOrder> de::Deserializer<'de> for Deserializer<'a, 'de, B> {
         V: Visitor<'de>,
     {
         let mut de = self.possible_variant()?;
-        let i = B::read_u32(&de.read_align_signature_value::<4>(u32::signature(), 4)?);
+        let i = B::read_u32(&de.read_align_signature_value::<4>(u32::signature())?);
         visitor.visit_u32(i)
     }
 
@@ -143,7 +289,7 @@ impl<'de, 'a, B: ByteOrder> de::Deserializer<'de> for Deserializer<'a, 'de, B> {
         V: Visitor<'de>,
     {
         let mut de = self.possible_variant()?;
-        let i = B::read_u64(&de.read_align_signature_value::<8>(u64::signature(), 8)?);
+        let i = B::read_u64(&de.read_align_signature_value::<8>(u64::signature())?);
         visitor.visit_u64(i)
     }
 
@@ -152,7 +298,7 @@ impl<'de, 'a, B: ByteOrder> de::Deserializer<'de> for Deserializer<'a, 'de, B> {
         V: Visitor<'de>,
     {
         let mut de = self.possible_variant()?;
-        let i = B::read_f64(&de.read_align_signature_value::<8>(f64::signature(), 4)?);
+        let i = B::read_f64(&de.read_align_signature_value::<8>(f64::signature())?);
         visitor.visit_f32(i as f32)
     }
 
@@ -161,7 +307,7 @@ impl<'de, 'a, B: ByteOrder> de::Deserializer<'de> for Deserializer<'a, 'de, B> {
         V: Visitor<'de>,
     {
         let mut de = self.possible_variant()?;
-        let i = B::read_f64(&de.read_align_signature_value::<8>(f64::signature(), 8)?);
+        let i = B::read_f64(&de.read_align_signature_value::<8>(f64::signature())?);
         visitor.visit_f64(i)
     }
 
@@ -170,7 +316,7 @@ impl<'de, 'a, B: ByteOrder> de::Deserializer<'de> for Deserializer<'a, 'de, B> {
         V: Visitor<'de>,
     {
         let mut de = self.possible_variant()?;
-        let i = B::read_u32(&de.read_align_signature_value::<4>(u32::signature(), 2)?);
+        let i = B::read_u32(&de.read_align_signature_value::<4>(u32::signature())?);
         visitor.visit_char(i.try_into()?)
     }
 
@@ -180,7 +326,7 @@ impl<'de, 'a, B: ByteOrder> de::Deserializer<'de> for Deserializer<'a, 'de, B> {
     {
         let mut de = self.possible_variant()?;
         let string = de.deserialize_str_basic()?;
-        visitor.visit_str(string)
+        visitor.visit_borrowed_str(string)
     }
 
     fn deserialize_string<V>(mut self, visitor: V) -> Result<V::Value>
@@ -197,8 +343,13 @@ impl<'de, 'a, B: ByteOrder> de::Deserializer<'de> for Deserializer<'a, 'de, B> {
         V: Visitor<'de>,
     {
         let mut de = self.possible_variant()?;
-        let bytes = de.deserialize_bytes_basic()?;
-        visitor.visit_bytes(bytes)
+        if de.probe_signature_bytes("ay".as_bytes()) {
+            let bytes = de.deserialize_byte_array_basic()?;
+            visitor.visit_borrowed_bytes(bytes)
+        } else {
+            let bytes = de.deserialize_bytes_basic()?;
+            visitor.visit_borrowed_bytes(bytes)
+        }
     }
 
     fn deserialize_byte_buf<V>(mut self, visitor: V) -> Result<V::Value>
@@ -206,8 +357,13 @@ impl<'de, 'a, B: ByteOrder> de::Deserializer<'de> for Deserializer<'a, 'de, B> {
         V: Visitor<'de>,
     {
         let mut de = self.possible_variant()?;
-        let bytes = de.deserialize_bytes_basic()?;
-        visitor.visit_byte_buf(bytes.into())
+        if de.probe_signature_bytes("ay".as_bytes()) {
+            let bytes = de.deserialize_byte_array_basic()?;
+            visitor.visit_byte_buf(bytes.into())
+        } else {
+            let bytes = de.deserialize_bytes_basic()?;
+            visitor.visit_byte_buf(bytes.into())
+        }
     }
 
     fn deserialize_option<V>(mut self, visitor: V) -> Result<V::Value>
@@ -215,21 +371,44 @@ impl<'de, 'a, B: ByteOrder> de::Deserializer<'de> for Deserializer<'a, 'de, B> {
         V: Visitor<'de>,
     {
         let mut de = self.possible_variant()?;
-        if de.probe_signature_bytes("()".as_bytes()) {
-            de.align_reader(8)?;
-            visitor.visit_none()
-        } else {
-            visitor.visit_some(de)
+        let field = de.field();
+        match de.options().option_style(field.as_ref()).clone() {
+            // Fields left out of dicts are `None` without reaching here.
+            OptionSerializationStyle::Omit => visitor.visit_some(de),
+            OptionSerializationStyle::Array => {
+                let mut array = ArrayDeserializer::new(de)?;
+                let value = match array.next_item_deserializer()? {
+                    Some(item) => visitor.visit_some(item.with_field(field))?,
+                    None => return visitor.visit_none(),
+                };
+                if array.data_buffer.data_ix != array.end_ix {
+                    return Err(Error::OptionArrayTooLong(array.data_buffer.data_ix));
+                }
+                Ok(value)
+            }
+            // The variant was read by `possible_variant`.
+            OptionSerializationStyle::Variant => {
+                if de.signature_is_empty() {
+                    visitor.visit_none()
+                } else {
+                    visitor.visit_some(de)
+                }
+            }
+            OptionSerializationStyle::Default(default) => {
+                if de.probe_value(default.message())? {
+                    visitor.visit_none()
+                } else {
+                    visitor.visit_some(de)
+                }
+            }
         }
     }
 
-    fn deserialize_unit<V>(mut self, visitor: V) -> Result<V::Value>
+    // Units are serialized as nothing.
+    fn deserialize_unit<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        let mut de = self.possible_variant()?;
-        de.expect_signature_str("()".as_bytes())?;
-        de.align_reader(8)?;
         visitor.visit_unit()
     }
 
@@ -240,11 +419,52 @@ impl<'de, 'a, B: ByteOrder> de::Deserializer<'de> for Deserializer<'a, 'de, B> {
         self.deserialize_unit(visitor)
     }
 
-    fn deserialize_newtype_struct<V>(self, _: &'static str, visitor: V) -> Result<V::Value>
+    // The types in `types` must have their own type code, and Unix fds
+    // are looked up by their index and duplicated for the `UnixFd`.
+    fn deserialize_newtype_struct<V>(mut self, name: &'static str, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        visitor.visit_newtype_struct(self)
+        #[cfg(unix)]
+        if name == UNIX_FD_NAME {
+            let mut de = self.possible_variant()?;
+            let fd = de.deserialize_unix_fd()?;
+            // Safety: the message's fds are open while it is deserialized,
+            // as it owns those the serializer wrote, and `Message::with_fds`
+            // requires it of its caller for others. This one is only
+            // borrowed for long enough to duplicate it.
+            let fd = unsafe { BorrowedFd::borrow_raw(fd) }
+                .try_clone_to_owned()
+                .map_err(|err| Error::Io(err.to_string()))?;
+            return with_received_fd(fd, || visitor.visit_unit());
+        }
+        // A `Value` is given its signature before its contents, which
+        // are not unwrapped from a variant, so that it can hold one.
+        if name == VALUE_NAME {
+            let sig = from_utf8(self.peek_single_sig()?)?;
+            return visitor.visit_enum(SignedValueAccess(sig, self));
+        }
+        if name == VARIANT_NAME {
+            let sig = self.peek_single_sig()?;
+            if sig != b"v" {
+                return Err(Error::SignatureError(b'v', sig[0]));
+            }
+            let de = self.possible_variant()?;
+            return visitor.visit_newtype_struct(de);
+        }
+        match newtype_signature(name) {
+            Some(b'h') => {
+                let mut de = self.possible_variant()?;
+                let i = B::read_u32(&de.read_align_signature_value::<4>(UnixFdIndex::signature())?);
+                visitor.visit_u32(i)
+            }
+            Some(code) => {
+                let mut de = self.possible_variant()?;
+                let string = de.deserialize_str_typed(code)?;
+                visitor.visit_borrowed_str(string)
+            }
+            None => visitor.visit_newtype_struct(self),
+        }
     }
 
     fn deserialize_seq<V>(mut self, visitor: V) -> Result<V::Value>
@@ -254,7 +474,7 @@ impl<'de, 'a, B: ByteOrder> de::Deserializer<'de> for Deserializer<'a, 'de, B> {
         let mut de = self.possible_variant()?;
         if de.probe_signature_bytes("(".as_bytes()) {
             de.align_reader(8)?;
-            visitor.visit_seq(StructDeserializer(de))
+            visitor.visit_seq(StructDeserializer::new(de, "", &[]))
         } else {
             // Expect 'a' included in ArrayDeserializer::new
             visitor.visit_seq(ArrayDeserializer::new(de)?)
@@ -268,7 +488,7 @@ impl<'de, 'a, B: ByteOrder> de::Deserializer<'de> for Deserializer<'a, 'de, B> {
         let mut de = self.possible_variant()?;
         de.expect_signature_byte(b'(')?;
         de.align_reader(8)?;
-        visitor.visit_seq(StructDeserializer(de))
+        visitor.visit_seq(StructDeserializer::new(de, "", &[]))
     }
 
     fn deserialize_tuple_struct<V>(self, _: &'static str, sz: usize, visitor: V) -> Result<V::Value>
@@ -287,39 +507,59 @@ impl<'de, 'a, B: ByteOrder> de::Deserializer<'de> for Deserializer<'a, 'de, B> {
     }
 
     fn deserialize_struct<V>(
-        mut self,
-        _: &'static str,
-        _: &'static [&'static str],
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
         visitor: V,
     ) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        let mut de = self.possible_variant()?;
-        if de.probe_signature_bytes("(".as_bytes()) {
-            visitor.visit_seq(StructDeserializer(de))
-        } else {
-            visitor.visit_map(ArrayDeserializer::new(de)?)
-        }
+        let case = self.options().field_case(name);
+        self.deserialize_fields(name, case, fields, visitor)
     }
 
+    // Enums can be serialized as just the variant's index or name, as
+    // a dict with a single entry from the variant's name to its value,
+    // or as a struct of the variant's name or index and its value.
     fn deserialize_enum<V>(
-        self,
+        mut self,
         name: &'static str,
-        _: &'static [&'static str],
+        variants: &'static [&'static str],
         visitor: V,
     ) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        visitor.visit_enum(EnumDeserializer { de: self, name })
+        let case = self.options().field_case(name);
+        let mut de = self.possible_variant()?;
+        match de.peek_single_sig()?[0] {
+            b'a' => visitor.visit_enum(DictEnumAccess {
+                array: ArrayDeserializer::new(de)?,
+                variants,
+                variant: "",
+                case,
+            }),
+            b'(' => {
+                de.expect_signature_byte(b'(')?;
+                de.align_reader(8)?;
+                visitor.visit_enum(TaggedEnumAccess {
+                    de,
+                    variants,
+                    variant: "",
+                    case,
+                })
+            }
+            _ => visitor.visit_enum(UnitEnumAccess(de)),
+        }
     }
 
+    // Identifiers can be names or, for enum variants, indices.
     fn deserialize_identifier<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        self.deserialize_str(visitor)
+        self.deserialize_any(visitor)
     }
 
     fn deserialize_ignored_any<V>(self, visitor: V) -> Result<V::Value>
@@ -330,13 +570,68 @@ impl<'de, 'a, B: ByteOrder> de::Deserializer<'de> for Deserializer<'a, 'de, B> {
     }
 }
 
+impl<'de, 'a, B: ByteOrder> Deserializer<'a, 'de, B> {
+    // Deserializes a struct or struct variant, which was serialized
+    // either as a struct or as a dict with keys in the given case.
+    // The fields of the struct, or struct variant, named `name`
+    fn deserialize_fields<V>(
+        mut self,
+        name: &'static str,
+        case: FieldNameCase,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let mut de = self.possible_variant()?;
+        if de.probe_signature_bytes("(".as_bytes()) {
+            de.align_reader(8)?;
+            visitor.visit_seq(StructDeserializer::new(de, name, fields))
+        } else {
+            visitor.visit_map(CasedFieldsAccess {
+                array: ArrayDeserializer::new(de)?,
+                name,
+                case,
+                fields,
+                field: None,
+            })
+        }
+    }
+}
+
 impl<'de, 'a, B: ByteOrder> ArrayDeserializer<'a, 'de, B> {
     fn new(mut de: Deserializer<'a, 'de, B>) -> Result<Self> {
         de.expect_signature_byte(b'a')?;
         de.align_reader(4)?;
-        let len = B::read_u32(de.read(4)?) as usize;
+        let len = B::read_u32(de.read(4)?);
         de.array_deserializer(len)
     }
+
+    // The next dict value, which is the value of `field` if the dict is
+    // a struct.
+    fn next_field_value_seed<V>(&mut self, seed: V, field: Option<FieldContext>) -> Result<V::Value>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        let segment = match self.take_key() {
+            Some((code, ix)) => PathSegment::Key(code, ix),
+            None => PathSegment::Item(self.items),
+        };
+        if let Some(de) = self.next_value_deserializer()? {
+            let mut de = de.with_field(field);
+            let depth = de.enter(segment);
+            let value = seed.deserialize(de)?;
+            self.data_buffer.leave(depth);
+            self.items += 1;
+            Ok(value)
+        } else {
+            Err(Error::ArrayElementOverrun(
+                self.data_buffer.data_ix,
+                self.end_ix,
+            ))
+        }
+    }
 }
 
 impl<'de, 'a, B: ByteOrder> SeqAccess<'de> for ArrayDeserializer<'a, 'de, B> {
@@ -346,8 +641,13 @@ impl<'de, 'a, B: ByteOrder> SeqAccess<'de> for ArrayDeserializer<'a, 'de, B> {
     where
         T: DeserializeSeed<'de>,
     {
-        if let Some(de) = self.next_item_deserializer()? {
-            Ok(Some(seed.deserialize(de)?))
+        let item = self.items;
+        if let Some(mut de) = self.next_item_deserializer()? {
+            let depth = de.enter(PathSegment::Item(item));
+            let value = seed.deserialize(de)?;
+            self.data_buffer.leave(depth);
+            self.items += 1;
+            Ok(Some(value))
         } else {
             Ok(None)
         }
@@ -372,18 +672,136 @@ impl<'de, 'a, B: ByteOrder> MapAccess<'de> for ArrayDeserializer<'a, 'de, B> {
     where
         V: DeserializeSeed<'de>,
     {
-        if let Some(de) = self.next_value_deserializer()? {
-            Ok(seed.deserialize(de)?)
-        } else {
-            Err(Error::ArrayElementOverrun(
-                self.data_buffer.data_ix,
-                self.end_ix,
-            ))
+        self.next_field_value_seed(seed, None)
+    }
+}
+
+// For a struct serialized as a dict with keys converted from its field
+// names, which maps the keys back to the field names. Keys that match
+// no field are passed on as they are.
+struct CasedFieldsAccess<'a, 'de, B: ByteOrder> {
+    array: ArrayDeserializer<'a, 'de, B>,
+    name: &'static str,
+    case: FieldNameCase,
+    fields: &'static [&'static str],
+    // The field of the last key
+    field: Option<&'static str>,
+}
+
+impl<'de, 'a, B: ByteOrder> MapAccess<'de> for CasedFieldsAccess<'a, 'de, B> {
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
+    where
+        K: DeserializeSeed<'de>,
+    {
+        let de = match self.array.next_key_deserializer()? {
+            Some(de) => de,
+            None => return Ok(None),
+        };
+        let key = String::deserialize(de)?;
+        let case = self.case;
+        let field = self.fields.iter().find(|field| case.convert(field) == key);
+        self.field = field.copied();
+        match field {
+            Some(field) => seed.deserialize(IntoDeserializer::<Error>::into_deserializer(*field)),
+            None => seed.deserialize(IntoDeserializer::<Error>::into_deserializer(key)),
         }
+        .map(Some)
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        let field = self.field.take().map(|field_name| FieldContext {
+            struct_name: self.name,
+            field_name,
+        });
+        self.array.next_field_value_seed(seed, field)
+    }
+}
+
+// A `Value` is given as an enum variant named by its signature, which
+// other formats never give for it.
+struct SignedValueAccess<'a, 'de, B: ByteOrder>(&'de str, Deserializer<'a, 'de, B>);
+
+impl<'de, 'a, B: ByteOrder> EnumAccess<'de> for SignedValueAccess<'a, 'de, B> {
+    type Error = Error;
+    type Variant = SignedValueContents<'a, 'de, B>;
+
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self::Variant)>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        let sig = seed.deserialize(BorrowedStrDeserializer::<Error>::new(self.0))?;
+        Ok((sig, SignedValueContents(self.1)))
+    }
+}
+
+struct SignedValueContents<'a, 'de, B: ByteOrder>(Deserializer<'a, 'de, B>);
+
+impl<'de, 'a, B: ByteOrder> VariantAccess<'de> for SignedValueContents<'a, 'de, B> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        Err(de::Error::invalid_type(
+            Unexpected::UnitVariant,
+            &"a DBus value",
+        ))
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        seed.deserialize(self.0)
+    }
+
+    fn tuple_variant<V>(self, _: usize, _: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        Err(de::Error::invalid_type(
+            Unexpected::TupleVariant,
+            &"a DBus value",
+        ))
+    }
+
+    fn struct_variant<V>(self, _: &'static [&'static str], _: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        Err(de::Error::invalid_type(
+            Unexpected::StructVariant,
+            &"a DBus value",
+        ))
     }
 }
 
-struct StructDeserializer<'a, 'de, B: ByteOrder>(Deserializer<'a, 'de, B>);
+// The fields of a struct, which are named by `fields` for Rust structs,
+// and by their index otherwise.
+struct StructDeserializer<'a, 'de, B: ByteOrder> {
+    de: Deserializer<'a, 'de, B>,
+    name: &'static str,
+    fields: &'static [&'static str],
+    ix: usize,
+}
+
+impl<'de, 'a, B: ByteOrder> StructDeserializer<'a, 'de, B> {
+    fn new(
+        de: Deserializer<'a, 'de, B>,
+        name: &'static str,
+        fields: &'static [&'static str],
+    ) -> Self {
+        Self {
+            de,
+            name,
+            fields,
+            ix: 0,
+        }
+    }
+}
 
 impl<'de, 'a, B: ByteOrder> SeqAccess<'de> for StructDeserializer<'a, 'de, B> {
     type Error = Error;
@@ -392,20 +810,183 @@ impl<'de, 'a, B: ByteOrder> SeqAccess<'de> for StructDeserializer<'a, 'de, B> {
     where
         T: DeserializeSeed<'de>,
     {
-        if self.0.probe_signature_bytes(")".as_bytes()) {
-            Ok(None)
-        } else {
-            Ok(Some(seed.deserialize(self.0.subsidiary()?)?))
+        if self.de.probe_signature_bytes(")".as_bytes()) {
+            return Ok(None);
         }
+        let field = self.fields.get(self.ix).map(|field_name| FieldContext {
+            struct_name: self.name,
+            field_name,
+        });
+        let segment = match field {
+            Some(field) => PathSegment::Field(field.field_name),
+            None => PathSegment::TupleField(self.ix),
+        };
+        self.ix += 1;
+        let mut de = self.de.subsidiary()?.with_field(field);
+        let depth = de.enter(segment);
+        let value = seed.deserialize(de)?;
+        self.de.leave(depth);
+        Ok(Some(value))
     }
 }
 
-struct EnumDeserializer<'a, 'de: 'a, B: ByteOrder> {
+// For an enum serialized as just the variant's index or name, which
+// must then be a unit variant.
+struct UnitEnumAccess<'a, 'de, B: ByteOrder>(Deserializer<'a, 'de, B>);
+
+impl<'de, 'a, B: ByteOrder> EnumAccess<'de> for UnitEnumAccess<'a, 'de, B> {
+    type Error = Error;
+    type Variant = UnitVariantAccess;
+
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self::Variant)>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        Ok((seed.deserialize(self.0)?, UnitVariantAccess))
+    }
+}
+
+struct UnitVariantAccess;
+
+impl<'de> VariantAccess<'de> for UnitVariantAccess {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T>(self, _: T) -> Result<T::Value>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        Err(de::Error::invalid_type(
+            Unexpected::UnitVariant,
+            &"newtype variant",
+        ))
+    }
+
+    fn tuple_variant<V>(self, _: usize, _: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        Err(de::Error::invalid_type(
+            Unexpected::UnitVariant,
+            &"tuple variant",
+        ))
+    }
+
+    fn struct_variant<V>(self, _: &'static [&'static str], _: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        Err(de::Error::invalid_type(
+            Unexpected::UnitVariant,
+            &"struct variant",
+        ))
+    }
+}
+
+// For an enum serialized as a dict with a single entry, from the
+// variant's name to its value.
+struct DictEnumAccess<'a, 'de, B: ByteOrder> {
+    array: ArrayDeserializer<'a, 'de, B>,
+    variants: &'static [&'static str],
+    // The name of the variant, once it is read
+    variant: &'static str,
+    // The naming convention for the fields of struct variants
+    case: FieldNameCase,
+}
+
+impl<'de, 'a, B: ByteOrder> DictEnumAccess<'a, 'de, B> {
+    fn value<T>(
+        mut self,
+        deserialize: impl FnOnce(Deserializer<'_, 'de, B>) -> Result<T>,
+    ) -> Result<T> {
+        let array = &mut self.array;
+        let value = match array.next_value_deserializer()? {
+            Some(de) => deserialize(de)?,
+            None => return Err(Error::InvalidEnumDict(array.data_buffer.data_ix)),
+        };
+        if array.data_buffer.data_ix != array.end_ix {
+            return Err(Error::InvalidEnumDict(array.data_buffer.data_ix));
+        }
+        Ok(value)
+    }
+}
+
+impl<'de, 'a, B: ByteOrder> EnumAccess<'de> for DictEnumAccess<'a, 'de, B> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V>(mut self, seed: V) -> Result<(V::Value, Self::Variant)>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        let name = match self.array.next_key_deserializer()? {
+            Some(de) => String::deserialize(de)?,
+            None => return Err(Error::InvalidEnumDict(self.array.data_buffer.data_ix)),
+        };
+        if let Some(variant) = self.variants.iter().find(|variant| **variant == name) {
+            self.variant = variant;
+        }
+        let variant = seed.deserialize(IntoDeserializer::<Error>::into_deserializer(name))?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de, 'a, B: ByteOrder> VariantAccess<'de> for DictEnumAccess<'a, 'de, B> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        self.value(|de| IgnoredAny::deserialize(de).map(|_| ()))
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        self.value(|de| seed.deserialize(de))
+    }
+
+    fn tuple_variant<V>(self, len: usize, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.value(|de| de::Deserializer::deserialize_tuple(de, len, visitor))
+    }
+
+    fn struct_variant<V>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let (variant, case) = (self.variant, self.case);
+        self.value(|de| de.deserialize_fields(variant, case, fields, visitor))
+    }
+}
+
+// For an enum serialized as a struct of the variant's name or index
+// and its value, after the opening of the struct.
+struct TaggedEnumAccess<'a, 'de, B: ByteOrder> {
     de: Deserializer<'a, 'de, B>,
-    name: &'static str,
+    variants: &'static [&'static str],
+    // The name of the variant, once it is read
+    variant: &'static str,
+    // The naming convention for the fields of struct variants
+    case: FieldNameCase,
+}
+
+impl<'de, 'a, B: ByteOrder> TaggedEnumAccess<'a, 'de, B> {
+    fn value<T>(
+        mut self,
+        deserialize: impl FnOnce(Deserializer<'_, 'de, B>) -> Result<T>,
+    ) -> Result<T> {
+        let value = deserialize(self.de.subsidiary()?)?;
+        self.de.expect_signature_byte(b')')?;
+        Ok(value)
+    }
 }
 
-impl<'de, 'a, B: ByteOrder> EnumAccess<'de> for EnumDeserializer<'a, 'de, B> {
+impl<'de, 'a, B: ByteOrder> EnumAccess<'de> for TaggedEnumAccess<'a, 'de, B> {
     type Error = Error;
     type Variant = Self;
 
@@ -413,49 +994,85 @@ impl<'de, 'a, B: ByteOrder> EnumAccess<'de> for EnumDeserializer<'a, 'de, B> {
     where
         V: DeserializeSeed<'de>,
     {
-        seed.deserialize(self.de.subsidiary()?).map(|v| (v, self))
+        let mut de = self.de.subsidiary()?;
+        let variant = if de.peek_single_sig()?[0] == b's' {
+            let name = String::deserialize(de)?;
+            if let Some(variant) = self.variants.iter().find(|variant| **variant == name) {
+                self.variant = variant;
+            }
+            seed.deserialize(IntoDeserializer::<Error>::into_deserializer(name))?
+        } else {
+            let index = u32::deserialize(de)?;
+            if let Some(variant) = self.variants.get(index as usize) {
+                self.variant = variant;
+            }
+            seed.deserialize(IntoDeserializer::<Error>::into_deserializer(index))?
+        };
+        Ok((variant, self))
     }
 }
 
-impl<'de, 'a, B: ByteOrder> VariantAccess<'de> for EnumDeserializer<'a, 'de, B> {
+impl<'de, 'a, B: ByteOrder> VariantAccess<'de> for TaggedEnumAccess<'a, 'de, B> {
     type Error = Error;
 
     fn unit_variant(self) -> Result<()> {
-        Ok(())
+        self.value(|de| IgnoredAny::deserialize(de).map(|_| ()))
     }
 
     fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value>
     where
         T: DeserializeSeed<'de>,
     {
-        seed.deserialize(self.de)
+        self.value(|de| seed.deserialize(de))
     }
 
-    fn tuple_variant<V>(self, _len: usize, visitor: V) -> Result<V::Value>
+    fn tuple_variant<V>(self, len: usize, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        de::Deserializer::deserialize_struct(self.de, self.name, &[], visitor)
+        self.value(|de| de::Deserializer::deserialize_tuple(de, len, visitor))
     }
 
     fn struct_variant<V>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        de::Deserializer::deserialize_struct(self.de, self.name, fields, visitor)
+        let (variant, case) = (self.variant, self.case);
+        self.value(|de| de.deserialize_fields(variant, case, fields, visitor))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::de::from_message;
-    use crate::error::Result;
-    use crate::ser::serialize;
-    use serde::de::DeserializeOwned;
+    use crate::de::{
+        from_message, from_message_with_options, DeserializerLimits, DeserializerOptions,
+    };
+    use crate::error::{Error, Result};
+    use crate::message::{Endianness, Message};
+    use crate::ser::serializer_policy::{
+        EnumSerializationStyle, FieldContext, FieldNameCase, OptionDefault,
+        OptionSerializationStyle, SerializerPolicy, StronglyTypedSerializerPolicy,
+        StructSerializationStyle,
+    };
+    use crate::ser::{serialize, serialize_with_policy, serialize_with_signature};
+    use crate::types::{ObjectPath, Signature, UnixFdIndex};
+    use crate::value::{Array, Value};
+    use serde::de::{DeserializeOwned, IgnoredAny};
     use serde::{Deserialize, Serialize};
+    use std::borrow::Cow;
+    use std::collections::BTreeMap;
     use std::fmt::Debug;
     use test_log::test;
 
+    fn in_value<T>(err: Error, path: &str, offset: usize, sig_ix: usize) -> Result<T> {
+        Err(Error::InValue(
+            Box::new(err),
+            path.to_owned(),
+            offset,
+            sig_ix,
+        ))
+    }
+
     fn round_trip<T: DeserializeOwned + Debug + Serialize + PartialEq>(val: T) -> Result<()> {
         let b = val;
         let message = serialize(&b)?;
@@ -479,15 +1096,22 @@ mod tests {
         round_trip(3.14)
     }
 
+    #[test]
+    fn round_trip_float_and_char_aligned() -> Result<()> {
+        round_trip((1u8, 2.5f32))?;
+        round_trip((1u16, 'x'))
+    }
+
     #[test]
     fn round_trip_some_string() -> Result<()> {
         round_trip(Some("string".to_owned()))
     }
 
     #[test]
-    fn round_trip_none_string() -> Result<()> {
+    fn none_string_outside_dict() {
+        // `None` can only be left out of dicts.
         let b: Option<String> = None;
-        round_trip(b)
+        assert_eq!(serialize(b), Err(Error::NoneOutsideDict));
     }
 
     #[test]
@@ -501,6 +1125,9 @@ mod tests {
         struct Foo;
 
         let foo = Foo;
+        let message = serialize(&foo)?;
+        assert_eq!(message.signature, b"");
+        assert!(message.data.is_empty());
         round_trip(foo)
     }
 
@@ -509,6 +1136,74 @@ mod tests {
         round_trip(vec![1, 3, 5, 6])
     }
 
+    #[test]
+    fn round_trip_array1() -> Result<()> {
+        round_trip(vec![vec![], vec![("a".to_owned(), 2.5)], vec![]])
+    }
+
+    #[test]
+    fn round_trip_bytes() -> Result<()> {
+        round_trip(serde_bytes::ByteBuf::from(vec![0, 1, 2, 255]))
+    }
+
+    #[test]
+    fn borrowed_bytes() -> Result<()> {
+        let data = serde_bytes::Bytes::new(&[0xfe, 0xed]);
+        let message = serialize(data)?;
+        let bytes: &serde_bytes::Bytes = from_message(&message)?;
+        assert_eq!(bytes, data);
+
+        let vec: Vec<u8> = from_message(&message)?;
+        assert_eq!(vec, vec![0xfe, 0xed]);
+        Ok(())
+    }
+
+    #[test]
+    fn borrowed_strs() -> Result<()> {
+        #[derive(Debug, Deserialize, PartialEq, Serialize)]
+        struct Properties<'a> {
+            name: &'a str,
+            #[serde(borrow)]
+            vendor: Cow<'a, str>,
+            path: &'a str,
+            tags: Vec<&'a str>,
+        }
+
+        let val = Properties {
+            name: "sda",
+            vendor: Cow::Borrowed("ACME"),
+            path: "/org/a",
+            tags: vec!["disk", "usb"],
+        };
+        let message = serialize(&val)?;
+        let val2: Properties = from_message(&message)?;
+        assert_eq!(val, val2);
+        assert!(matches!(val2.vendor, Cow::Borrowed(_)));
+        let data = message.data.as_ptr_range();
+        assert!(data.contains(&val2.name.as_ptr()));
+
+        // Object paths and signatures, and strings as bytes
+        let message = serialize_with_signature(("/org/a", "a{sv}"), "og")?;
+        let (path, sig): (&str, &str) = from_message(&message)?;
+        assert_eq!((path, sig), ("/org/a", "a{sv}"));
+        let message = serialize("ab")?;
+        let bytes: &serde_bytes::Bytes = from_message(&message)?;
+        assert_eq!(&bytes[..], b"ab");
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip_map() -> Result<()> {
+        let mut inner = BTreeMap::new();
+        inner.insert("a".to_owned(), 1);
+        inner.insert("b".to_owned(), 2);
+
+        let mut outer = BTreeMap::new();
+        outer.insert(3u32, inner);
+        outer.insert(4u32, BTreeMap::new());
+        round_trip(outer)
+    }
+
     #[test]
     fn round_trip_struct0() -> Result<()> {
         #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -565,4 +1260,932 @@ mod tests {
 
         Ok(())
     }
+
+    #[derive(Clone, Debug)]
+    struct OptionPolicy {
+        style: OptionSerializationStyle,
+        strongly_typed: bool,
+        endianness: Endianness,
+    }
+
+    impl SerializerPolicy for OptionPolicy {
+        fn query_struct_name(&self, _: &str) -> StructSerializationStyle {
+            if self.strongly_typed {
+                StructSerializationStyle::StronglyTyped
+            } else {
+                StructSerializationStyle::Dict
+            }
+        }
+
+        fn query_option(&self, _: Option<&FieldContext>) -> OptionSerializationStyle {
+            self.style.clone()
+        }
+
+        fn allow_empty_variants(&self) -> bool {
+            true
+        }
+
+        fn endianness(&self) -> Endianness {
+            self.endianness
+        }
+    }
+
+    // The policies for `style`, with structs as dicts and as structs, in
+    // both byte orders
+    fn option_policies(style: &OptionSerializationStyle) -> Vec<OptionPolicy> {
+        let mut policies = Vec::new();
+        for strongly_typed in [false, true] {
+            for endianness in [Endianness::Little, Endianness::Big] {
+                policies.push(OptionPolicy {
+                    style: style.clone(),
+                    strongly_typed,
+                    endianness,
+                });
+            }
+        }
+        policies
+    }
+
+    fn round_trip_options_with<T>(val: &T, policy: OptionPolicy) -> Result<()>
+    where
+        T: DeserializeOwned + Debug + Serialize + PartialEq,
+    {
+        let options = DeserializerOptions {
+            default_option_style: policy.style.clone(),
+            ..Default::default()
+        };
+        let message = serialize_with_policy(val, policy)?;
+        crate::message::validate(&message)?;
+        let val2: T = from_message_with_options(&message, options)?;
+        assert_eq!(*val, val2);
+        Ok(())
+    }
+
+    fn round_trip_options<T>(val: T, style: OptionSerializationStyle) -> Result<()>
+    where
+        T: DeserializeOwned + Debug + Serialize + PartialEq,
+    {
+        for policy in option_policies(&style) {
+            round_trip_options_with(&val, policy)?;
+        }
+        Ok(())
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+    struct WithOptions {
+        a: Option<String>,
+        b: Option<u32>,
+        c: Option<Vec<i32>>,
+        d: u8,
+    }
+
+    fn with_options_values() -> Vec<WithOptions> {
+        vec![
+            WithOptions {
+                a: None,
+                b: None,
+                c: None,
+                d: 1,
+            },
+            WithOptions {
+                a: Some("a".to_owned()),
+                b: Some(2),
+                c: Some(vec![3, 4]),
+                d: 5,
+            },
+            WithOptions {
+                a: Some("".to_owned()),
+                b: None,
+                c: Some(vec![]),
+                d: 6,
+            },
+        ]
+    }
+
+    #[test]
+    fn round_trip_option_omit() -> Result<()> {
+        let style = OptionSerializationStyle::Omit;
+        for val in with_options_values() {
+            let has_none = val.a.is_none() || val.b.is_none() || val.c.is_none();
+            for policy in option_policies(&style) {
+                if has_none && policy.strongly_typed {
+                    // `None` can only be left out of dicts.
+                    let res = serialize_with_policy(&val, policy);
+                    assert_eq!(res, Err(Error::NoneOutsideDict));
+                } else {
+                    round_trip_options_with(&val, policy)?;
+                }
+            }
+        }
+        assert_eq!(serialize(None::<u32>), Err(Error::NoneOutsideDict));
+        assert_eq!(serialize(vec![None::<u32>]), Err(Error::NoneOutsideDict));
+        round_trip_options(Some(3u32), style.clone())?;
+        round_trip_options(vec![Some(3u32)], style)
+    }
+
+    #[test]
+    fn round_trip_option_array() -> Result<()> {
+        for val in with_options_values() {
+            round_trip_options(val, OptionSerializationStyle::Array)?;
+        }
+        round_trip_options(Some(Some(3)), OptionSerializationStyle::Array)?;
+        round_trip_options(Some(None::<i32>), OptionSerializationStyle::Array)?;
+        round_trip_options(vec![None, Some(4u8)], OptionSerializationStyle::Array)
+    }
+
+    // Empty variants are not valid DBus, so these skip `round_trip_options`.
+    #[test]
+    fn round_trip_option_variant() -> Result<()> {
+        let style = OptionSerializationStyle::Variant;
+        for policy in option_policies(&style) {
+            let mut options = DeserializerOptions {
+                default_option_style: style.clone(),
+                allow_empty_variants: true,
+                ..Default::default()
+            };
+            let vals = (with_options_values(), None::<String>, vec![None, Some(4u8)]);
+            let message = serialize_with_policy(&vals, policy)?;
+            let vals2: (Vec<WithOptions>, Option<String>, Vec<Option<u8>>) =
+                from_message_with_options(&message, options.clone())?;
+            assert_eq!(vals, vals2);
+
+            options.allow_empty_variants = false;
+            assert!(
+                from_message_with_options::<(Vec<WithOptions>, Option<String>, Vec<Option<u8>>)>(
+                    &message, options
+                )
+                .is_err()
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip_option_default() -> Result<()> {
+        #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+        struct WithPaths {
+            a: Option<String>,
+            b: Option<String>,
+        }
+
+        let style = OptionSerializationStyle::Default(OptionDefault::new("/")?);
+        round_trip_options(
+            WithPaths {
+                a: None,
+                b: Some("/org".to_owned()),
+            },
+            style.clone(),
+        )?;
+        round_trip_options(vec![None, Some("/a".to_owned())], style.clone())?;
+
+        // A value equal to the default comes back as `None`.
+        let message = serialize_with_policy(
+            Some("/"),
+            OptionPolicy {
+                style: style.clone(),
+                strongly_typed: true,
+                endianness: Endianness::Little,
+            },
+        )?;
+        let options = DeserializerOptions {
+            default_option_style: style,
+            ..Default::default()
+        };
+        let val: Option<String> = from_message_with_options(&message, options)?;
+        assert_eq!(val, None);
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip_option_per_field() -> Result<()> {
+        #[derive(Clone)]
+        struct FieldOptionPolicy;
+
+        impl SerializerPolicy for FieldOptionPolicy {
+            fn query_struct_name(&self, _: &str) -> StructSerializationStyle {
+                StructSerializationStyle::StronglyTyped
+            }
+
+            fn query_option(&self, field: Option<&FieldContext>) -> OptionSerializationStyle {
+                match field.map(|field| (field.struct_name, field.field_name)) {
+                    Some(("WithOptions", "a")) => OptionSerializationStyle::Variant,
+                    Some(("WithOptions", "b")) => {
+                        OptionSerializationStyle::Default(OptionDefault::new(&0u32).unwrap())
+                    }
+                    Some(("Struct", "b")) => OptionSerializationStyle::Variant,
+                    _ => OptionSerializationStyle::Array,
+                }
+            }
+
+            fn allow_empty_variants(&self) -> bool {
+                true
+            }
+        }
+
+        let mut options = DeserializerOptions {
+            default_option_style: OptionSerializationStyle::Array,
+            allow_empty_variants: true,
+            ..Default::default()
+        };
+        let styles = [
+            ("WithOptions", "a", OptionSerializationStyle::Variant),
+            (
+                "WithOptions",
+                "b",
+                OptionSerializationStyle::Default(OptionDefault::new(&0u32)?),
+            ),
+            ("Struct", "b", OptionSerializationStyle::Variant),
+        ];
+        for (struct_name, field_name, style) in styles {
+            options
+                .option_styles
+                .insert((struct_name.to_owned(), field_name.to_owned()), style);
+        }
+
+        let vals = (
+            with_options_values(),
+            Kinds::Struct { a: 1, b: None },
+            vec![Some(Some(2u8)), Some(None), None],
+        );
+        let message = serialize_with_policy(&vals, FieldOptionPolicy)?;
+        assert_eq!(message.signature, b"(ava{sv}av)");
+        let vals2 = from_message_with_options(&message, options.clone())?;
+        assert_eq!(vals, vals2);
+
+        // Without the per-field styles, the fields are misread.
+        options.option_styles.clear();
+        assert!(
+            from_message_with_options::<(Vec<WithOptions>, Kinds, Vec<Option<Option<u8>>>)>(
+                &message, options
+            )
+            .is_err()
+        );
+        Ok(())
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+    enum Kinds {
+        Unit,
+        Newtype(String),
+        Tuple(u8, i64),
+        Struct { a: u32, b: Option<String> },
+        Nested(Box<Kinds>),
+    }
+
+    fn kinds_values() -> Vec<Kinds> {
+        vec![
+            Kinds::Unit,
+            Kinds::Newtype("a".to_owned()),
+            Kinds::Tuple(1, -2),
+            Kinds::Struct {
+                a: 3,
+                b: Some("b".to_owned()),
+            },
+            Kinds::Struct { a: 4, b: None },
+            Kinds::Nested(Box::new(Kinds::Tuple(5, 6))),
+        ]
+    }
+
+    #[test]
+    fn round_trip_enum() -> Result<()> {
+        for val in kinds_values() {
+            round_trip(val.clone())?;
+            round_trip((val.clone(), 7u8, val))?;
+        }
+        round_trip(vec![Kinds::Unit, Kinds::Unit])
+    }
+
+    #[derive(Clone, Debug)]
+    struct EnumPolicy {
+        style: EnumSerializationStyle,
+        strongly_typed: bool,
+    }
+
+    impl SerializerPolicy for EnumPolicy {
+        fn query_struct_name(&self, _: &str) -> StructSerializationStyle {
+            if self.strongly_typed {
+                StructSerializationStyle::StronglyTyped
+            } else {
+                StructSerializationStyle::Dict
+            }
+        }
+
+        fn query_enum(&self, _: &str) -> EnumSerializationStyle {
+            self.style
+        }
+    }
+
+    #[test]
+    fn round_trip_enum_styles() -> Result<()> {
+        #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+        struct WithEnum {
+            kind: Kinds,
+            after: String,
+        }
+
+        let styles = [
+            EnumSerializationStyle::Index,
+            EnumSerializationStyle::Name,
+            EnumSerializationStyle::NameTaggedStruct,
+            EnumSerializationStyle::IndexTaggedStruct,
+        ];
+        for style in styles {
+            for strongly_typed in [false, true] {
+                let policy = EnumPolicy {
+                    style,
+                    strongly_typed,
+                };
+                for kind in kinds_values() {
+                    let val = WithEnum {
+                        kind,
+                        after: "after".to_owned(),
+                    };
+                    let message = serialize_with_policy(&val, policy.clone())?;
+                    let val2: WithEnum = from_message(&message)?;
+                    assert_eq!(val, val2, "{:?} {:?}", style, message);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn enum_dict_entries() -> Result<()> {
+        let mut data = BTreeMap::new();
+        data.insert("Newtype".to_owned(), "a");
+        data.insert("Other".to_owned(), "b");
+        let message = serialize(&data)?;
+        let res = from_message::<Kinds>(&message);
+        assert!(
+            matches!(&res, Err(Error::InValue(err, _, _, _)) if matches!(**err, Error::InvalidEnumDict(_))),
+            "{:?}",
+            res
+        );
+
+        let data: BTreeMap<String, String> = BTreeMap::new();
+        let message = serialize(&data)?;
+        let res = from_message::<Kinds>(&message);
+        assert!(
+            matches!(&res, Err(Error::InValue(err, _, _, _)) if matches!(**err, Error::InvalidEnumDict(_))),
+            "{:?}",
+            res
+        );
+        Ok(())
+    }
+
+    #[derive(Clone)]
+    struct CasePolicy;
+
+    impl SerializerPolicy for CasePolicy {
+        fn query_struct_name(&self, _: &str) -> StructSerializationStyle {
+            StructSerializationStyle::Dict
+        }
+
+        fn query_field_case(&self, name: &str) -> FieldNameCase {
+            match name {
+                "Inner" => FieldNameCase::Kebab,
+                _ => FieldNameCase::Pascal,
+            }
+        }
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+    struct Inner {
+        max_retries: u32,
+        name: String,
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+    struct Cased {
+        max_retries: u32,
+        inner: Inner,
+        io_ready: bool,
+    }
+
+    #[test]
+    fn round_trip_field_case() -> Result<()> {
+        let val = Cased {
+            max_retries: 3,
+            inner: Inner {
+                max_retries: 4,
+                name: "inner".to_owned(),
+            },
+            io_ready: true,
+        };
+        let message = serialize_with_policy(&val, CasePolicy)?;
+        let keys: BTreeMap<String, IgnoredAny> = from_message(&message)?;
+        let keys: Vec<&str> = keys.keys().map(String::as_str).collect();
+        assert_eq!(keys, vec!["Inner", "IoReady", "MaxRetries"]);
+
+        let mut options = DeserializerOptions {
+            default_field_case: FieldNameCase::Pascal,
+            ..Default::default()
+        };
+        options
+            .field_cases
+            .insert("Inner".to_owned(), FieldNameCase::Kebab);
+        let val2: Cased = from_message_with_options(&message, options)?;
+        assert_eq!(val, val2);
+
+        let res = from_message::<Cased>(&message);
+        assert!(
+            matches!(&res, Err(Error::InValue(err, _, _, _)) if matches!(**err, Error::Deserializing(_))),
+            "{:?}",
+            res
+        );
+        Ok(())
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+    enum CasedEnum {
+        Settings { max_retries: u32, io_ready: bool },
+    }
+
+    #[derive(Clone)]
+    struct TaggedCasePolicy;
+
+    impl SerializerPolicy for TaggedCasePolicy {
+        fn query_struct_name(&self, _: &str) -> StructSerializationStyle {
+            StructSerializationStyle::Dict
+        }
+
+        fn query_field_case(&self, _: &str) -> FieldNameCase {
+            FieldNameCase::Pascal
+        }
+
+        fn query_enum(&self, _: &str) -> EnumSerializationStyle {
+            EnumSerializationStyle::IndexTaggedStruct
+        }
+    }
+
+    #[test]
+    fn round_trip_field_case_variants() -> Result<()> {
+        let val = CasedEnum::Settings {
+            max_retries: 3,
+            io_ready: true,
+        };
+        let message = serialize_with_policy(&val, CasePolicy)?;
+        let dict: BTreeMap<String, BTreeMap<String, IgnoredAny>> = from_message(&message)?;
+        let keys: Vec<&str> = dict["Settings"].keys().map(String::as_str).collect();
+        assert_eq!(keys, vec!["IoReady", "MaxRetries"]);
+
+        let tagged = serialize_with_policy(&val, TaggedCasePolicy)?;
+        for message in [message, tagged] {
+            let mut options = DeserializerOptions::default();
+            options
+                .field_cases
+                .insert("CasedEnum".to_owned(), FieldNameCase::Pascal);
+            let val2: CasedEnum = from_message_with_options(&message, options)?;
+            assert_eq!(val, val2);
+            assert!(from_message::<CasedEnum>(&message).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn field_name_cases() {
+        let convert = |case: FieldNameCase| case.convert("max_retries").into_owned();
+        assert_eq!(convert(FieldNameCase::Snake), "max_retries");
+        assert_eq!(convert(FieldNameCase::Camel), "maxRetries");
+        assert_eq!(convert(FieldNameCase::Pascal), "MaxRetries");
+        assert_eq!(convert(FieldNameCase::Kebab), "max-retries");
+        assert_eq!(
+            convert(FieldNameCase::Custom(|name| name.to_uppercase())),
+            "MAX_RETRIES"
+        );
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+    struct WithTypes {
+        path: ObjectPath,
+        sig: Signature,
+        fd: UnixFdIndex,
+        paths: Vec<ObjectPath>,
+    }
+
+    #[test]
+    fn round_trip_types() -> Result<()> {
+        let val = WithTypes {
+            path: ObjectPath::new("/org/freedesktop/DBus")?,
+            sig: Signature::new("a{sv}")?,
+            fd: UnixFdIndex(2),
+            paths: vec![ObjectPath::new("/")?, ObjectPath::new("/a/b")?],
+        };
+        round_trip(val.clone())?;
+
+        let message = serialize_with_policy(&val, StronglyTypedSerializerPolicy)?;
+        assert_eq!(message.signature, b"(oghao)".to_vec());
+        let val2: WithTypes = from_message(&message)?;
+        assert_eq!(val, val2);
+
+        let message = serialize_with_signature(&val, "a{sv}")?;
+        let val2: WithTypes = from_message(&message)?;
+        assert_eq!(val, val2);
+        Ok(())
+    }
+
+    #[test]
+    fn types_as_plain_values() -> Result<()> {
+        let message = serialize(ObjectPath::new("/a")?)?;
+        assert_eq!(message.signature, b"o".to_vec());
+        assert_eq!(from_message::<String>(&message)?, "/a");
+
+        let message = serialize(Signature::new("ai")?)?;
+        assert_eq!(message.data, vec![2, b'a', b'i', 0]);
+        assert_eq!(from_message::<String>(&message)?, "ai");
+
+        // Without their own type codes, they are not accepted.
+        let message = serialize("/a")?;
+        assert!(from_message::<ObjectPath>(&message).is_err());
+        let message = serialize(2u32)?;
+        assert!(from_message::<UnixFdIndex>(&message).is_err());
+
+        // An invalid path fails even when the type code is right.
+        let message = serialize_with_signature("/a/", "o");
+        assert_eq!(message, Err(Error::InvalidObjectPath("/a/".to_owned())));
+        Ok(())
+    }
+
+    #[test]
+    fn types_in_dicts() -> Result<()> {
+        let val = WithTypes {
+            path: ObjectPath::new("/a")?,
+            sig: Signature::new("")?,
+            fd: UnixFdIndex(0),
+            paths: vec![],
+        };
+        let message = serialize(&val)?;
+        let map: BTreeMap<String, IgnoredAny> = from_message(&message)?;
+        assert_eq!(map.len(), 4);
+
+        let mut dict = BTreeMap::new();
+        dict.insert("path".to_owned(), ObjectPath::new("/a/b")?);
+        let message = serialize(&dict)?;
+        assert_eq!(message.signature, b"a{so}".to_vec());
+        let dict2: BTreeMap<String, ObjectPath> = from_message(&message)?;
+        assert_eq!(dict, dict2);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn round_trip_unix_fds() -> Result<()> {
+        use crate::types::{BorrowedUnixFd, UnixFd};
+        use serde::de::value::{Error as ValueError, I32Deserializer};
+        use std::io::{Read, Write};
+        use std::os::unix::io::{AsFd, AsRawFd, OwnedFd};
+        use std::os::unix::net::UnixStream;
+
+        #[derive(Serialize)]
+        struct Sent<'a> {
+            owned: UnixFd,
+            borrowed: Vec<BorrowedUnixFd<'a>>,
+        }
+
+        #[derive(Deserialize)]
+        struct Received {
+            owned: UnixFd,
+            borrowed: Vec<UnixFd>,
+        }
+
+        let (a, mut a_peer) = UnixStream::pair().unwrap();
+        let (b, mut b_peer) = UnixStream::pair().unwrap();
+        let sent = Sent {
+            owned: UnixFd::new(OwnedFd::from(a)),
+            borrowed: vec![b.as_fd().into(), b.as_fd().into()],
+        };
+        for message in [
+            serialize(&sent)?,
+            serialize_with_policy(&sent, StronglyTypedSerializerPolicy)?,
+        ] {
+            // Each fd is only sent once, however often it is serialized,
+            // and the message holds duplicates of them.
+            let a_raw = sent.owned.as_raw_fd();
+            let fds = message.fds();
+            assert_eq!(fds.len(), 2);
+            assert!(!fds.contains(&a_raw) && !fds.contains(&b.as_raw_fd()));
+
+            let received: Received = from_message(&message)?;
+            assert_ne!(received.owned.as_raw_fd(), a_raw);
+            let mut a2 = UnixStream::from(received.owned.into_inner());
+            a2.write_all(b"a").unwrap();
+            let mut buf = [0u8];
+            a_peer.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"a");
+
+            assert_eq!(received.borrowed.len(), 2);
+            let mut b2 =
+                UnixStream::from(OwnedFd::from(received.borrowed.into_iter().next().unwrap()));
+            b2.write_all(b"b").unwrap();
+            b_peer.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"b");
+        }
+
+        // Other deserializers can't give a `UnixFd`, as they can't check
+        // that the fd is open.
+        let res = UnixFd::deserialize(I32Deserializer::<ValueError>::new(0));
+        assert!(res.is_err());
+
+        let mut message = serialize(&sent)?;
+        message.fds.pop();
+        let res = from_message::<Received>(&message);
+        assert!(
+            matches!(&res, Err(Error::InValue(err, _, _, _)) if **err == Error::UnixFdIndexOutOfRange(1, 1)),
+            "{:?}",
+            res.map(|_| ())
+        );
+
+        // The message keeps its fds open after those it was serialized
+        // from are closed.
+        let message = serialize(BorrowedUnixFd::from(b.as_fd()))?;
+        drop(b);
+        let received: UnixFd = from_message(&message)?;
+        let mut b2 = UnixStream::from(received.into_inner());
+        b2.write_all(b"c").unwrap();
+        let mut buf = [0u8];
+        b_peer.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"c");
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip_big_endian() -> Result<()> {
+        #[derive(Clone)]
+        struct BigEndianPolicy;
+
+        impl SerializerPolicy for BigEndianPolicy {
+            fn query_struct_name(&self, _: &str) -> StructSerializationStyle {
+                StructSerializationStyle::StronglyTyped
+            }
+
+            fn query_option(&self, _: Option<&FieldContext>) -> OptionSerializationStyle {
+                OptionSerializationStyle::Default(OptionDefault::new(&0u32).unwrap())
+            }
+
+            fn endianness(&self) -> Endianness {
+                Endianness::Big
+            }
+        }
+
+        #[derive(Debug, Deserialize, PartialEq, Serialize)]
+        struct Data {
+            name: String,
+            small: i16,
+            large: u64,
+            real: f64,
+            items: Vec<(u8, u32)>,
+            dict: BTreeMap<String, i32>,
+            path: ObjectPath,
+            sig: Signature,
+            missing: Option<u32>,
+            present: Option<u32>,
+        }
+
+        let data = Data {
+            name: "name".to_owned(),
+            small: -2,
+            large: 1 << 40,
+            real: 1.5,
+            items: vec![(1, 2), (3, 4)],
+            dict: vec![("a".to_owned(), 5)].into_iter().collect(),
+            path: ObjectPath::new("/a/b")?,
+            sig: Signature::new("a{sv}")?,
+            missing: None,
+            present: Some(6),
+        };
+        let message = serialize_with_policy(&data, BigEndianPolicy)?;
+        assert_eq!(message.endianness, Endianness::Big);
+
+        let options = DeserializerOptions {
+            default_option_style: BigEndianPolicy.query_option(None),
+            ..Default::default()
+        };
+        let data2: Data = from_message_with_options(&message, options.clone())?;
+        assert_eq!(data, data2);
+
+        let little = message.to_endianness(Endianness::Little)?;
+        assert_ne!(little.data, message.data);
+        let data3: Data = from_message_with_options(&little, options)?;
+        assert_eq!(data, data3);
+        assert_eq!(little.to_endianness(Endianness::Big)?, message);
+
+        let message = serialize_with_policy((1u32, "ab"), BigEndianPolicy)?;
+        assert_eq!(message.data, vec![0, 0, 0, 1, 0, 0, 0, 2, b'a', b'b', 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn hostile_input() -> Result<()> {
+        // Variants nested far deeper than the stack would allow
+        let mut data = Vec::new();
+        for _ in 0..100_000 {
+            data.extend_from_slice(&[1, b'v', 0]);
+        }
+        data.extend_from_slice(&[1, b'y', 0, 7]);
+        let message = Message {
+            data,
+            signature: b"v".to_vec(),
+            fds: Vec::new(),
+            endianness: Endianness::Little,
+        };
+        assert_eq!(
+            from_message::<IgnoredAny>(&message),
+            in_value(Error::VariantNestingTooDeep(65), ".", 192, 0)
+        );
+
+        let message = Message {
+            data: vec![0xff, 0xff, 0xff, 0xff],
+            signature: b"ay".to_vec(),
+            fds: Vec::new(),
+            endianness: Endianness::Little,
+        };
+        assert_eq!(
+            from_message::<Vec<u8>>(&message),
+            in_value(Error::ArrayTooLong(0xffff_ffff, 1 << 26, 0), ".", 0, 0)
+        );
+        let message = Message {
+            data: vec![0, 0, 0, 1],
+            ..message
+        };
+        assert_eq!(
+            from_message::<Vec<u8>>(&message),
+            in_value(Error::IndexOutOfBounds(0x0100_0004), ".", 0, 0)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn configured_limits() -> Result<()> {
+        let options = |limits: DeserializerLimits| DeserializerOptions {
+            limits,
+            ..Default::default()
+        };
+
+        let message = serialize(vec![vec![vec![1u8]]])?;
+        let limits = DeserializerLimits {
+            max_array_depth: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            from_message_with_options::<Vec<Vec<Vec<u8>>>>(&message, options(limits)),
+            in_value(Error::ArrayNestingTooDeep(3), "[0][0]", 8, 2)
+        );
+
+        let message = serialize(((1u8,),))?;
+        let limits = DeserializerLimits {
+            max_struct_depth: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            from_message_with_options::<((u8,),)>(&message, options(limits)),
+            in_value(Error::StructNestingTooDeep(2), ".0", 0, 1)
+        );
+
+        let message = serialize(vec![0u8; 100])?;
+        let limits = DeserializerLimits {
+            max_array_len: 99,
+            ..Default::default()
+        };
+        assert_eq!(
+            from_message_with_options::<Vec<u8>>(&message, options(limits)),
+            in_value(Error::ArrayTooLong(100, 99, 0), ".", 0, 0)
+        );
+        let limits = DeserializerLimits {
+            max_message_len: 100,
+            ..Default::default()
+        };
+        assert_eq!(
+            from_message_with_options::<Vec<u8>>(&message, options(limits)),
+            Err(Error::MessageTooLong(104, 100))
+        );
+
+        // Dict entries are structs, and variants keep the depth of the
+        // containers they are in.
+        let mut dict = BTreeMap::new();
+        let items = vec![Value::Struct(vec![Value::from(1u8)])];
+        let array = Array::from_items(Signature::new("(y)")?, items)?;
+        dict.insert("a", Value::Array(array).into_variant());
+        let message = serialize(vec![dict])?;
+        let limits = DeserializerLimits {
+            max_struct_depth: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            from_message_with_options::<Vec<BTreeMap<String, Value>>>(&message, options(limits)),
+            in_value(Error::StructNestingTooDeep(2), "[0].a[0]", 24, 4)
+        );
+        let limits = DeserializerLimits {
+            max_array_depth: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            from_message_with_options::<Vec<BTreeMap<String, Value>>>(&message, options(limits)),
+            in_value(Error::ArrayNestingTooDeep(3), "[0].a", 20, 4)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_signatures() -> Result<()> {
+        let mut message = serialize(vec![1i32, 2])?;
+        message.signature = b"a[i]".to_vec();
+        assert_eq!(
+            from_message::<Vec<i32>>(&message),
+            in_value(Error::UnrecognizedSignatureCharacter(b'['), ".", 0, 0)
+        );
+        message.signature = b"a(i".to_vec();
+        assert_eq!(
+            from_message::<Vec<(i32,)>>(&message),
+            in_value(Error::MismatchedSignatureBracketing(1), ".", 0, 0)
+        );
+
+        // Signatures inside variants are checked as they are read.
+        let message = Message::new(
+            vec![1, b'[', 0, 0, 1, 0, 0, 0],
+            b"v".to_vec(),
+            Endianness::Little,
+        );
+        assert_eq!(
+            from_message::<Value>(&message),
+            in_value(Error::UnrecognizedSignatureCharacter(b'['), ".", 0, 0)
+        );
+        let message = Message::new(
+            vec![2, b'u', b'u', 0, 1, 0, 0, 0, 2, 0, 0, 0],
+            b"v".to_vec(),
+            Endianness::Little,
+        );
+        assert_eq!(
+            from_message::<u32>(&message),
+            in_value(Error::NotSingleType(b"uu".to_vec()), ".", 0, 0)
+        );
+        let message = Message::new(vec![0, 0], b"v".to_vec(), Endianness::Little);
+        assert_eq!(
+            from_message::<u32>(&message),
+            in_value(Error::NotSingleType(Vec::new()), ".", 0, 0)
+        );
+        Ok(())
+    }
+    #[test]
+    fn error_context() -> Result<()> {
+        #[derive(Serialize)]
+        struct Device {
+            #[serde(rename = "Name")]
+            name: Value,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct NamedDevice {
+            #[serde(rename = "Name")]
+            _name: String,
+        }
+
+        #[derive(Debug, Deserialize, Serialize)]
+        struct Reply<T> {
+            devices: Vec<T>,
+        }
+
+        let names = vec![Value::from("a"), "b".into(), "c".into(), 3u32.into()];
+        let devices = names.into_iter().map(|name| Device { name }).collect();
+        let message = serialize(Reply { devices })?;
+        // The variant holding the last name
+        assert_eq!(&message.data[121..124], &[1, b'u', 0]);
+        let err = from_message::<Reply<NamedDevice>>(&message).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "at .devices[3].Name (byte 121, signature index 3): signature: expected 's' got 'u'"
+        );
+        assert_eq!(
+            Err(err),
+            in_value::<()>(
+                Error::SignatureError(b's', b'u'),
+                ".devices[3].Name",
+                121,
+                3
+            )
+        );
+
+        let mut message = serialize((1u8, (2u8, true)))?;
+        assert_eq!(message.signature, b"(y(yb))".to_vec());
+        message.data[12] = 3;
+        assert_eq!(
+            from_message::<(u8, (u8, bool))>(&message),
+            in_value(Error::InvalidBoolValue(3), ".1.1", 12, 4)
+        );
+        let mut dict = BTreeMap::new();
+        dict.insert(7u16, vec![true, false]);
+        let mut message = serialize(&dict)?;
+        message.data[20] = 2;
+        assert_eq!(
+            from_message::<BTreeMap<u16, Vec<bool>>>(&message),
+            in_value(Error::InvalidBoolValue(2), "[7][1]", 20, 4)
+        );
+
+        let message = serialize(BTreeMap::<String, u8>::new())?;
+        let err = from_message::<Reply<u8>>(&message).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "at . (byte 8, signature index 0): deserializing: missing field `devices`"
+        );
+        Ok(())
+    }
 }
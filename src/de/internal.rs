@@ -1,22 +1,126 @@
+use super::DeserializerOptions;
 use crate::align::align;
 use crate::error::{Error, Result};
-use crate::message::Message;
-use byteorder::ByteOrder;
+use crate::message::{Endianness, Message, MessageFd, RawFd};
+use crate::ser::serializer_policy::FieldContext;
+use crate::signature::{alignment, single_type_end, validate};
+use byteorder::{ByteOrder, BE, LE};
 use log::{error, trace};
 use std::cmp::Ordering;
+use std::fmt::Write;
 use std::marker::PhantomData;
 use std::str::from_utf8;
 
 pub(super) struct DataBuffer<'de> {
     pub(super) data: &'de [u8],
     pub(super) data_ix: usize,
+    pub(super) fds: &'de [MessageFd],
+    pub(super) endianness: Endianness,
+    pub(super) options: DeserializerOptions,
+
+    // Where the last value read started, and the path to the value
+    // being deserialized, for errors.
+    value_ix: usize,
+    path: Vec<PathFrame>,
+}
+
+// A step in the path from the message to the value being deserialized.
+#[derive(Clone, Copy)]
+pub(super) enum PathSegment {
+    // A struct field, by name
+    Field(&'static str),
+    // A struct field without a name, by index
+    TupleField(usize),
+    // An array item, by index
+    Item(usize),
+    // A dict value, by the type code of its key and where the key is
+    Key(u8, usize),
+}
+
+struct PathFrame {
+    segment: PathSegment,
+    sig_ix: usize,
 }
 
 impl<'de> DataBuffer<'de> {
-    pub(super) fn from_message(mesg: &'de Message) -> Self {
+    pub(super) fn from_message(mesg: &'de Message, options: DeserializerOptions) -> Self {
         Self {
             data: &mesg.data,
             data_ix: 0,
+            fds: &mesg.fds,
+            endianness: mesg.endianness,
+            options,
+            value_ix: 0,
+            path: Vec::new(),
+        }
+    }
+
+    // Leaves the values entered since the path was `depth` long.
+    pub(super) fn leave(&mut self, depth: usize) {
+        self.path.truncate(depth);
+    }
+
+    // Adds where deserialization had got to when `err` happened. The
+    // values that were being deserialized are left in the path, as the
+    // error skipped leaving them.
+    pub(super) fn context(&self, err: Error) -> Error {
+        let mut path = String::new();
+        for frame in &self.path {
+            match frame.segment {
+                PathSegment::Field(name) => write!(path, ".{}", name),
+                PathSegment::TupleField(ix) => write!(path, ".{}", ix),
+                PathSegment::Item(ix) => write!(path, "[{}]", ix),
+                PathSegment::Key(code, ix) => match self.key_text(code, ix) {
+                    Some(key) if matches!(code, b's' | b'o' | b'g') => write!(path, ".{}", key),
+                    Some(key) => write!(path, "[{}]", key),
+                    None => write!(path, "[?]"),
+                },
+            }
+            .expect("writing to a String");
+        }
+        if path.is_empty() {
+            path.push('.');
+        }
+        let sig_ix = self.path.last().map_or(0, |frame| frame.sig_ix);
+        Error::InValue(Box::new(err), path, self.value_ix, sig_ix)
+    }
+
+    // The dict key of type `code` that starts at `ix`, before alignment,
+    // if it can be read.
+    fn key_text(&self, code: u8, ix: usize) -> Option<String> {
+        let start = align(ix, alignment(code).ok()?);
+        let bytes = |len: usize| self.data.get(start..start + len);
+        let uint = |len: usize| {
+            let bytes = bytes(len)?;
+            Some(match self.endianness {
+                Endianness::Little => LE::read_uint(bytes, len),
+                Endianness::Big => BE::read_uint(bytes, len),
+            })
+        };
+        let int = |len: usize| {
+            let bytes = bytes(len)?;
+            Some(match self.endianness {
+                Endianness::Little => LE::read_int(bytes, len),
+                Endianness::Big => BE::read_int(bytes, len),
+            })
+        };
+        let string = |len_size: usize| {
+            let len = uint(len_size)? as usize;
+            let bytes = self.data.get(start + len_size..start + len_size + len)?;
+            Some(String::from_utf8_lossy(bytes).into_owned())
+        };
+        match code {
+            b's' | b'o' => string(4),
+            b'g' => string(1),
+            b'y' => uint(1).map(|key| key.to_string()),
+            b'q' => uint(2).map(|key| key.to_string()),
+            b'b' | b'u' | b'h' => uint(4).map(|key| key.to_string()),
+            b't' => uint(8).map(|key| key.to_string()),
+            b'n' => int(2).map(|key| key.to_string()),
+            b'i' => int(4).map(|key| key.to_string()),
+            b'x' => int(8).map(|key| key.to_string()),
+            b'd' => uint(8).map(|key| f64::from_bits(key).to_string()),
+            _ => None,
         }
     }
 
@@ -30,10 +134,27 @@ impl<'de> DataBuffer<'de> {
     }
 }
 
+// How many containers the value being deserialized is nested in,
+// counting those of enclosing variants.
+#[derive(Clone, Copy, Default)]
+pub(super) struct Depth {
+    arrays: usize,
+    structs: usize,
+    variants: usize,
+}
+
 pub(super) struct Deserializer<'a, 'de, B: ByteOrder> {
     data_buffer: &'a mut DataBuffer<'de>,
     sig: &'de [u8],
     sig_ix: usize,
+    // Where `sig` starts in the message's signature, or inside variants,
+    // where the outermost variant is
+    sig_base: usize,
+    in_variant: bool,
+    depth: Depth,
+    // The struct field this is the value of, as for
+    // `SerializerPolicy::query_option`
+    field: Option<FieldContext>,
     phantom: PhantomData<B>,
 }
 
@@ -41,6 +162,14 @@ pub(super) struct ArrayDeserializer<'a, 'de, B: ByteOrder> {
     pub(super) data_buffer: &'a mut DataBuffer<'de>,
     pub(super) end_ix: usize,
     pub(super) item_sig: &'de [u8],
+    item_sig_base: usize,
+    in_variant: bool,
+    // The depth of the items
+    pub(super) depth: Depth,
+    // How many items have been deserialized, and the type code and
+    // position of the last key
+    pub(super) items: usize,
+    key: Option<(u8, usize)>,
     pub(super) phantom: PhantomData<B>,
 }
 
@@ -60,6 +189,10 @@ impl<'a, 'de, B: ByteOrder> ArrayDeserializer<'a, 'de, B> {
                     data_buffer: self.data_buffer,
                     sig,
                     sig_ix: 0,
+                    sig_base: self.item_sig_base,
+                    in_variant: self.in_variant,
+                    depth: self.depth,
+                    field: None,
                     phantom: PhantomData,
                 }))
             }
@@ -69,14 +202,22 @@ impl<'a, 'de, B: ByteOrder> ArrayDeserializer<'a, 'de, B> {
     pub(super) fn next_key_deserializer<'b>(
         &'b mut self,
     ) -> Result<Option<Deserializer<'b, 'de, B>>> {
+        let key_ix = align(self.data_buffer.data_ix, 8);
         if let Some(mut de) = self.next_item_deserializer()? {
             de.align_reader(8)?;
             de.expect_signature_byte(b'{')?;
+            let depth = de.struct_depth()?;
+            let sig_base = de.sig_position();
             let sig = de.grab_single_sig()?;
+            self.key = Some((sig[0], key_ix));
             Ok(Some(Deserializer {
-                data_buffer: de.data_buffer,
+                data_buffer: &mut *self.data_buffer,
                 sig,
                 sig_ix: 0,
+                sig_base,
+                in_variant: self.in_variant,
+                depth,
+                field: None,
                 phantom: PhantomData,
             }))
         } else {
@@ -84,18 +225,28 @@ impl<'a, 'de, B: ByteOrder> ArrayDeserializer<'a, 'de, B> {
         }
     }
 
+    pub(super) fn take_key(&mut self) -> Option<(u8, usize)> {
+        self.key.take()
+    }
+
     pub(super) fn next_value_deserializer<'b>(
         &'b mut self,
     ) -> Result<Option<Deserializer<'b, 'de, B>>> {
         if let Some(mut de) = self.next_item_deserializer()? {
             de.expect_signature_byte(b'{')?;
+            let depth = de.struct_depth()?;
             let _ = de.grab_single_sig()?;
+            let sig_base = de.sig_position();
             let sig = de.grab_single_sig()?;
             de.expect_signature_byte(b'}')?;
             Ok(Some(Deserializer {
                 data_buffer: de.data_buffer,
                 sig,
                 sig_ix: 0,
+                sig_base,
+                in_variant: de.in_variant,
+                depth,
+                field: None,
                 phantom: PhantomData,
             }))
         } else {
@@ -104,58 +255,78 @@ impl<'a, 'de, B: ByteOrder> ArrayDeserializer<'a, 'de, B> {
     }
 }
 
-// TODO: This information is repeated in too many places
-// Clean that up.
-fn sig_alignment(item_sig: u8) -> Result<usize> {
-    match item_sig {
-        b'y' => Ok(1), // BYTE
-        b'b' => Ok(4), // BOOLEAN
-        b'n' => Ok(2), // INT16
-        b'q' => Ok(2), // UINT16
-        b'i' => Ok(4), // INT32
-        b'u' => Ok(4), // UINT32
-        b'x' => Ok(8), // INT64
-        b't' => Ok(8), // UINT64
-        b'd' => Ok(8), // DOUBLE
-        b's' => Ok(4), // STRING
-        b'o' => Ok(4), // OBJECT_PATH
-        b'g' => Ok(1), // SIGNATURE
-        b'a' => Ok(4), // ARRAY
-        b'(' => Ok(8), // STRUCT
-        b'v' => Ok(1), // VARIANT
-        b'{' => Ok(8), // DICT_ENTRY
-        b'h' => Ok(4), // UNIX_FD
-        _ => Err(Error::UnrecognizedSignatureCharacter(item_sig)),
-    }
-}
-
 impl<'a, 'de, B: ByteOrder> Deserializer<'a, 'de, B> {
     // Must have already processed the 'a' sig side, and the size data side.
     pub(super) fn array_deserializer(
         mut self,
-        array_size: usize,
+        array_size: u32,
     ) -> Result<ArrayDeserializer<'a, 'de, B>> {
+        let limits = &self.data_buffer.options.limits;
+        if array_size as usize > limits.max_array_len {
+            let len_ix = self.data_buffer.data_ix - 4;
+            return Err(Error::ArrayTooLong(
+                array_size as usize,
+                limits.max_array_len,
+                len_ix,
+            ));
+        }
+        let depth = Depth {
+            arrays: self.depth.arrays + 1,
+            ..self.depth
+        };
+        if depth.arrays > limits.max_array_depth {
+            return Err(Error::ArrayNestingTooDeep(depth.arrays));
+        }
+        let item_sig_base = self.sig_position();
         let item_sig = self.grab_single_sig()?;
-        self.align_reader(sig_alignment(item_sig[0])?)?;
-        let end_ix = self.data_buffer.data_ix + array_size;
+        let item_alignment = alignment(item_sig[0])?;
+        let end_ix = align(self.data_buffer.data_ix, item_alignment) + array_size as usize;
+        if end_ix > self.data_buffer.data.len() {
+            return Err(Error::IndexOutOfBounds(end_ix));
+        }
+        self.align_reader(item_alignment)?;
         Ok(ArrayDeserializer {
             data_buffer: &mut *self.data_buffer,
             end_ix,
             item_sig,
+            item_sig_base,
+            in_variant: self.in_variant,
+            depth,
+            items: 0,
+            key: None,
             phantom: PhantomData,
         })
     }
 
+    // A deserializer for the next field of the struct being deserialized.
     pub(super) fn subsidiary<'b>(&'b mut self) -> Result<Deserializer<'b, 'de, B>> {
+        let depth = self.struct_depth()?;
+        let sig_base = self.sig_position();
         let item_sig = self.grab_single_sig()?;
         Ok(Deserializer {
             data_buffer: &mut *self.data_buffer,
             sig: item_sig,
             sig_ix: 0,
+            sig_base,
+            in_variant: self.in_variant,
+            depth,
+            field: None,
             phantom: PhantomData,
         })
     }
 
+    // The depth of the fields of a struct or dict entry at this depth.
+    fn struct_depth(&self) -> Result<Depth> {
+        let depth = Depth {
+            structs: self.depth.structs + 1,
+            ..self.depth
+        };
+        if depth.structs > self.data_buffer.options.limits.max_struct_depth {
+            return Err(Error::StructNestingTooDeep(depth.structs));
+        }
+        Ok(depth)
+    }
+
     pub(super) fn from_message_and_buffer(
         buff: &'a mut DataBuffer<'de>,
         mesg: &'de Message,
@@ -166,31 +337,139 @@ impl<'a, 'de, B: ByteOrder> Deserializer<'a, 'de, B> {
             data_buffer: buff,
             sig,
             sig_ix,
+            sig_base: 0,
+            in_variant: false,
+            depth: Depth::default(),
+            field: None,
             phantom: PhantomData,
         }
     }
 
+    // The index in the message's signature of the next value, or inside
+    // variants, of the outermost variant.
+    pub(super) fn sig_position(&self) -> usize {
+        if self.in_variant {
+            self.sig_base
+        } else {
+            self.sig_base + self.sig_ix
+        }
+    }
+
+    // Records that the next value is deserialized for `segment`, and
+    // returns the depth to leave it at with `DataBuffer::leave`.
+    pub(super) fn enter(&mut self, segment: PathSegment) -> usize {
+        let depth = self.data_buffer.path.len();
+        let sig_ix = self.sig_position();
+        self.data_buffer.path.push(PathFrame { segment, sig_ix });
+        depth
+    }
+
+    pub(super) fn leave(&mut self, depth: usize) {
+        self.data_buffer.leave(depth);
+    }
+
     pub(super) fn possible_variant<'b>(&'b mut self) -> Result<Deserializer<'b, 'de, B>> {
         let mut sig;
         let sig_ix;
+        let sig_base = self.sig_position();
+        let in_variant;
+        let mut depth = self.depth;
         if self.probe_signature_bytes("v".as_bytes()) {
+            self.data_buffer.value_ix = self.data_buffer.data_ix;
+            depth.variants += 1;
+            if depth.variants > self.data_buffer.options.limits.max_variant_depth {
+                return Err(Error::VariantNestingTooDeep(depth.variants));
+            }
             let sig_len = self.read(1)?[0] as usize;
             sig = self.read(sig_len + 1)?;
             sig = &sig[..sig_len]; // cut terminating nul byte
+            validate(sig)?;
+            // The `Variant` option style may write `None` as an empty
+            // variant, when asked to.
+            let single_type = if sig.is_empty() {
+                self.data_buffer.options.allow_empty_variants
+            } else {
+                single_type_end(sig, 0)? == sig.len()
+            };
+            if !single_type {
+                return Err(Error::NotSingleType(sig.to_vec()));
+            }
             sig_ix = 0;
+            in_variant = true;
         } else {
             sig = self.sig;
             sig_ix = self.sig_ix;
+            in_variant = self.in_variant;
         }
 
         Ok(Deserializer {
             data_buffer: &mut *self.data_buffer,
             sig,
             sig_ix,
+            // Outside variants, `sig` carries on from here.
+            sig_base: if in_variant { sig_base } else { self.sig_base },
+            in_variant,
+            depth,
+            field: self.field,
             phantom: PhantomData,
         })
     }
 
+    pub(super) fn field(&self) -> Option<FieldContext> {
+        self.field
+    }
+
+    pub(super) fn with_field(mut self, field: Option<FieldContext>) -> Self {
+        self.field = field;
+        self
+    }
+
+    // Reads the index of a Unix fd, and looks it up in the message's fds.
+    #[cfg(unix)]
+    pub(super) fn deserialize_unix_fd(&mut self) -> Result<RawFd> {
+        let index = B::read_u32(&self.read_align_signature_value::<4>(b'h')?);
+        match self.data_buffer.fds.get(index as usize) {
+            Some(fd) => Ok(fd.raw()),
+            None => Err(Error::UnixFdIndexOutOfRange(
+                index,
+                self.data_buffer.fds.len(),
+            )),
+        }
+    }
+
+    pub(super) fn options(&self) -> &DeserializerOptions {
+        &self.data_buffer.options
+    }
+
+    // True for the contents of a variant with an empty signature
+    pub(super) fn signature_is_empty(&self) -> bool {
+        self.sig_ix >= self.sig.len()
+    }
+
+    // If the next value is byte-for-byte the same as the value in
+    // `mesg`, skips over it and returns true.
+    pub(super) fn probe_value(&mut self, mesg: &Message) -> Result<bool> {
+        let converted;
+        let mesg = if mesg.endianness == self.data_buffer.endianness {
+            mesg
+        } else {
+            converted = mesg.to_endianness(self.data_buffer.endianness)?;
+            &converted
+        };
+        let sig = self.peek_single_sig()?;
+        if sig != mesg.signature.as_slice() {
+            return Ok(false);
+        }
+        let start = align(self.data_buffer.data_ix, alignment(sig[0])?);
+        let end = start + mesg.data.len();
+        if self.data_buffer.data.get(start..end) != Some(mesg.data.as_slice()) {
+            return Ok(false);
+        }
+        self.sig_ix += sig.len();
+        self.data_buffer.data_ix = end;
+        Ok(true)
+    }
+
     pub(super) fn peek_single_sig(&mut self) -> Result<&'de [u8]> {
         let start = self.sig_ix;
         let res = self.grab_single_sig()?;
@@ -200,26 +479,9 @@ impl<'a, 'de, B: ByteOrder> Deserializer<'a, 'de, B> {
 
     pub(super) fn grab_single_sig(&mut self) -> Result<&'de [u8]> {
         let start = self.sig_ix;
-        let mut nesting = 0;
-        for i in self.sig_ix..self.sig.len() {
-            match self.sig[i] {
-                b'(' | b'[' | b'{' => {
-                    nesting += 1;
-                }
-                b')' | b']' | b'}' => {
-                    nesting -= 1;
-                }
-                b'a' => continue,
-                _ => (),
-            }
-
-            if nesting == 0 {
-                self.sig_ix = i + 1;
-                return Ok(&self.sig[start..=i]);
-            }
-        }
-
-        Err(Error::MismatchedSignatureBracketing(start))
+        let end = single_type_end(self.sig, start)?;
+        self.sig_ix = end;
+        Ok(&self.sig[start..end])
     }
 
     pub(super) fn probe_signature_bytes(&mut self, expected: &[u8]) -> bool {
@@ -252,14 +514,6 @@ impl<'a, 'de, B: ByteOrder> Deserializer<'a, 'de, B> {
         Ok(())
     }
 
-    pub(super) fn expect_signature_str(&mut self, expected: &[u8]) -> Result<()> {
-        if self.probe_signature_bytes(expected) {
-            Ok(())
-        } else {
-            Err(Error::SignatureErrorIx(expected.to_vec(), self.sig_ix))
-        }
-    }
-
     // Index after read must be valid for read to be valid
     fn validate_ix(&mut self) -> Result<()> {
         // Strict inequality
@@ -271,8 +525,11 @@ impl<'a, 'de, B: ByteOrder> Deserializer<'a, 'de, B> {
         Ok(())
     }
 
+    // Every value is aligned before it is read, so this is where values
+    // start.
     pub(super) fn align_reader(&mut self, alignment: usize) -> Result<()> {
         self.data_buffer.data_ix = align(self.data_buffer.data_ix, alignment);
+        self.data_buffer.value_ix = self.data_buffer.data_ix;
         self.validate_ix()?;
         Ok(())
     }
@@ -288,10 +545,9 @@ impl<'a, 'de, B: ByteOrder> Deserializer<'a, 'de, B> {
     pub(super) fn read_align_signature_value<const S: usize>(
         &mut self,
         signature: u8,
-        alignment: usize,
     ) -> Result<[u8; S]> {
         self.expect_signature_byte(signature)?;
-        self.align_reader(alignment)?;
+        self.align_reader(alignment(signature)?)?;
         trace!(
             "Read signature '{}', {} bytes, at {}",
             signature as char,
@@ -304,17 +560,44 @@ impl<'a, 'de, B: ByteOrder> Deserializer<'a, 'de, B> {
         Ok(array)
     }
 
-    pub(super) fn deserialize_bytes_basic(&mut self) -> Result<&[u8]> {
+    // Reads a string, object path or signature, as `code` says.
+    pub(super) fn deserialize_bytes_typed(&mut self, code: u8) -> Result<&'de [u8]> {
         trace!("read string at {}", self.data_buffer.data_ix);
-        let size = u32::from_le_bytes(self.read_align_signature_value(b's', 4)?);
+        let size = match code {
+            b'g' => self.read_align_signature_value::<1>(code)?[0] as u32,
+            _ => B::read_u32(&self.read_align_signature_value::<4>(code)?),
+        };
         trace!("size is {}", size);
         let res = self.read((size as usize) + 1)?;
         Ok(&res[..size as usize])
     }
 
-    pub(super) fn deserialize_str_basic(&mut self) -> Result<&str> {
+    // Object paths and signatures are accepted as strings as well.
+    pub(super) fn deserialize_bytes_basic(&mut self) -> Result<&'de [u8]> {
+        let code = match self.sig.get(self.sig_ix) {
+            Some(&code) if code == b'o' || code == b'g' => code,
+            _ => b's',
+        };
+        self.deserialize_bytes_typed(code)
+    }
+
+    // Must have already processed the "ay" signature.
+    pub(super) fn deserialize_byte_array_basic(&mut self) -> Result<&'de [u8]> {
+        self.align_reader(4)?;
+        let size = B::read_u32(self.read(4)?);
+        trace!("read {} byte array at {}", size, self.data_buffer.data_ix);
+        self.read(size as usize)
+    }
+
+    pub(super) fn deserialize_str_basic(&mut self) -> Result<&'de str> {
         let str = from_utf8(self.deserialize_bytes_basic()?)?;
         trace!("string is {}", str);
         Ok(str)
     }
+
+    pub(super) fn deserialize_str_typed(&mut self, code: u8) -> Result<&'de str> {
+        let str = from_utf8(self.deserialize_bytes_typed(code)?)?;
+        trace!("string is {}", str);
+        Ok(str)
+    }
 }
@@ -0,0 +1,187 @@
+use crate::align::align;
+use crate::error::{Error, Result};
+use crate::message::Endianness;
+use crate::signature::{alignment, single_type_end};
+
+use byteorder::{ByteOrder, BE, LE};
+
+// Converts message data with the given signature, in place, from one byte
+// order to the other. Only the numbers need to be reversed, but lengths
+// are read as they were written to find their way through the data.
+pub(crate) fn swap_endianness(data: &mut [u8], signature: &[u8], from: Endianness) -> Result<()> {
+    let mut swapper = Swapper { data, ix: 0, from };
+    let mut sig_ix = 0;
+    while sig_ix < signature.len() {
+        let end = single_type_end(signature, sig_ix)?;
+        swapper.swap_value(&signature[sig_ix..end])?;
+        sig_ix = end;
+    }
+    if swapper.ix != swapper.data.len() {
+        return Err(Error::LeftoverData(swapper.data.len() - swapper.ix));
+    }
+    Ok(())
+}
+
+struct Swapper<'a> {
+    data: &'a mut [u8],
+    ix: usize,
+    from: Endianness,
+}
+
+impl<'a> Swapper<'a> {
+    fn align(&mut self, alignment: usize) -> Result<()> {
+        self.ix = align(self.ix, alignment);
+        if self.ix > self.data.len() {
+            return Err(Error::IndexOutOfBounds(self.ix));
+        }
+        Ok(())
+    }
+
+    fn skip(&mut self, len: usize) -> Result<&mut [u8]> {
+        let start = self.ix;
+        self.ix += len;
+        self.data
+            .get_mut(start..start + len)
+            .ok_or(Error::IndexOutOfBounds(start + len))
+    }
+
+    fn swap_number(&mut self, size: usize) -> Result<()> {
+        self.align(size)?;
+        self.skip(size)?.reverse();
+        Ok(())
+    }
+
+    // Reads a length in the original byte order, then reverses it.
+    fn swap_length(&mut self) -> Result<usize> {
+        self.align(4)?;
+        let from = self.from;
+        let bytes = self.skip(4)?;
+        let len = match from {
+            Endianness::Little => LE::read_u32(bytes),
+            Endianness::Big => BE::read_u32(bytes),
+        };
+        bytes.reverse();
+        Ok(len as usize)
+    }
+
+    // `sig` is a single complete type.
+    fn swap_value(&mut self, sig: &[u8]) -> Result<()> {
+        match sig[0] {
+            b'y' => {
+                self.skip(1)?;
+            }
+            b'n' | b'q' => self.swap_number(2)?,
+            b'b' | b'i' | b'u' | b'h' => self.swap_number(4)?,
+            b'x' | b't' | b'd' => self.swap_number(8)?,
+            b's' | b'o' => {
+                let len = self.swap_length()?;
+                self.skip(len + 1)?;
+            }
+            b'g' => {
+                let len = self.skip(1)?[0] as usize;
+                self.skip(len + 1)?;
+            }
+            b'v' => {
+                let len = self.skip(1)?[0] as usize;
+                let inner = self.skip(len + 1)?[..len].to_vec();
+                // The `Variant` option style writes `None` as a variant
+                // with an empty signature and no value.
+                if inner.is_empty() {
+                    return Ok(());
+                }
+                if single_type_end(&inner, 0)? != inner.len() {
+                    return Err(Error::SignatureErrorIx(inner, 0));
+                }
+                self.swap_value(&inner)?;
+            }
+            b'a' => {
+                let len = self.swap_length()?;
+                let item_sig = &sig[1..];
+                self.align(alignment(item_sig[0])?)?;
+                let end = self.ix + len;
+                while self.ix < end {
+                    self.swap_value(item_sig)?;
+                }
+                if self.ix != end {
+                    return Err(Error::ArrayElementOverrun(self.ix, end));
+                }
+            }
+            b'(' | b'{' => {
+                self.align(8)?;
+                let mut ix = 1;
+                while ix < sig.len() - 1 {
+                    let end = single_type_end(sig, ix)?;
+                    self.swap_value(&sig[ix..end])?;
+                    ix = end;
+                }
+            }
+            other => return Err(Error::UnrecognizedSignatureCharacter(other)),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::endian::swap_endianness;
+    use crate::error::Result;
+    use crate::message::Endianness;
+    use crate::ser::serializer_policy::{
+        FieldContext, OptionSerializationStyle, SerializerPolicy, StructSerializationStyle,
+    };
+    use crate::ser::{serialize_with_policy, serialize_with_signature_and_policy};
+
+    #[test]
+    fn swap_nested() -> Result<()> {
+        let mut data = vec![
+            0, 0, 0, 12, // array length
+            0, 0, 0, 0, // padding
+            0, 7, // struct of i16
+            1, b'u', 0, 0, 0, 0, // and variant of u32
+            0, 0, 0, 9,
+        ];
+        swap_endianness(&mut data, b"a(nv)", Endianness::Big)?;
+        assert_eq!(
+            data,
+            vec![12, 0, 0, 0, 0, 0, 0, 0, 7, 0, 1, b'u', 0, 0, 0, 0, 9, 0, 0, 0]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn swap_empty_variant() -> Result<()> {
+        #[derive(Clone)]
+        struct VariantPolicy(Endianness);
+
+        impl SerializerPolicy for VariantPolicy {
+            fn query_struct_name(&self, _: &str) -> StructSerializationStyle {
+                StructSerializationStyle::StronglyTyped
+            }
+
+            fn query_option(&self, _: Option<&FieldContext>) -> OptionSerializationStyle {
+                OptionSerializationStyle::Variant
+            }
+
+            fn allow_empty_variants(&self) -> bool {
+                true
+            }
+
+            fn endianness(&self) -> Endianness {
+                self.0
+            }
+        }
+
+        let data = (None::<u32>, Some(7u32), vec![None::<i16>, Some(-2)]);
+        let little = serialize_with_policy(&data, VariantPolicy(Endianness::Little))?;
+        let big = serialize_with_policy(&data, VariantPolicy(Endianness::Big))?;
+        assert_eq!(big.signature, b"(vvav)");
+        assert_eq!(big.endianness, Endianness::Big);
+        assert_eq!(big.to_endianness(Endianness::Little)?, little);
+        assert_eq!(little.to_endianness(Endianness::Big)?, big);
+
+        let none =
+            serialize_with_signature_and_policy(None::<u32>, "v", VariantPolicy(Endianness::Big))?;
+        assert_eq!(none.data, vec![0, 0]);
+        Ok(())
+    }
+}
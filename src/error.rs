@@ -12,13 +12,20 @@ pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug, PartialEq, thiserror::Error)]
 pub enum Error {
-    #[error("serde error serializing")]
+    #[error("serializing: {0}")]
     Serializing(String),
 
-    #[error("serde error deserializing")]
+    #[error("deserializing: {0}")]
     Deserializing(String),
 
-    #[error("mismatch signature in array: {0:?}, {1:?}")]
+    #[error("at {1} (byte {2}, signature index {3}): {0}")]
+    InValue(Box<Error>, String, usize, usize),
+
+    #[error(
+        "mismatch signature in array: {:?}, {:?}",
+        String::from_utf8_lossy(.0),
+        String::from_utf8_lossy(.1)
+    )]
     MismatchSignature(Vec<u8>, Vec<u8>),
 
     #[error("converting from bytes to string: {0}")]
@@ -30,16 +37,16 @@ pub enum Error {
     #[error("leftover signature to deserialize: {0}")]
     LeftoverSignature(usize),
 
-    #[error("signature: unrecognized {0:X}")]
+    #[error("signature: unrecognized {:?}", char::from(*.0))]
     UnrecognizedSignatureCharacter(u8),
 
-    #[error("signature: unsupported {0:X}")]
+    #[error("signature: unsupported {:?}", char::from(*.0))]
     UnsupportedSignatureCharacter(u8),
 
-    #[error("signature: expected {0:X} got {1:X}")]
+    #[error("signature: expected {:?} got {:?}", char::from(*.0), char::from(*.1))]
     SignatureError(u8, u8),
 
-    #[error("signature: expected {0:?} at {1}")]
+    #[error("signature: expected {:?} at {}", String::from_utf8_lossy(.0), .1)]
     SignatureErrorIx(Vec<u8>, usize),
 
     #[error("out of signature")]
@@ -60,6 +67,105 @@ pub enum Error {
     #[error("Array element ended at {0} overrunning bound at {1}")]
     ArrayElementOverrun(usize, usize),
 
+    #[error("array at byte {2} is {0} bytes long, more than the limit of {1}")]
+    ArrayTooLong(usize, usize, usize),
+
+    #[error("variants nested {0} deep, more than allowed")]
+    VariantNestingTooDeep(usize),
+
+    #[error("padding is not zero")]
+    NonZeroPadding,
+
+    #[error("string is not nul-terminated")]
+    MissingNul,
+
+    #[error("string contains a nul byte")]
+    InteriorNul,
+
+    #[error("enum dict does not have exactly one entry, at {0}")]
+    InvalidEnumDict(usize),
+
+    #[error("array for optional value has more than one item, at {0}")]
+    OptionArrayTooLong(usize),
+
+    #[error("None can only be left out of structs serialized as dicts")]
+    NoneOutsideDict,
+
+    #[error("None would be serialized as an empty variant, which is not valid DBus")]
+    EmptyVariant,
+
+    #[error(
+        "value with signature {:?} does not fit signature {:?} at {}",
+        String::from_utf8_lossy(.2),
+        String::from_utf8_lossy(.0),
+        .1
+    )]
+    TargetSignatureMismatch(Vec<u8>, usize, Vec<u8>),
+
+    #[error("value {} out of range for signature {:?} at {}", .0, char::from(*.1), .2)]
+    TargetValueOutOfRange(String, u8, usize),
+
+    #[error(
+        "value has more fields than signature {:?} at {}",
+        String::from_utf8_lossy(.0),
+        .1
+    )]
+    TargetFieldsExhausted(Vec<u8>, usize),
+
+    #[error("invalid object path: {0:?}")]
+    InvalidObjectPath(String),
+
+    #[error("signature is {0} bytes long, more than 255")]
+    SignatureTooLong(usize),
+
+    #[error("arrays nested {0} deep, more than allowed")]
+    ArrayNestingTooDeep(usize),
+
+    #[error("structs nested {0} deep, more than allowed")]
+    StructNestingTooDeep(usize),
+
+    #[error("signature has a dict entry outside of an array, at {0}")]
+    DictEntryOutsideArray(usize),
+
+    #[error("Unix fd index {0} out of range for message with {1} fds")]
+    UnixFdIndexOutOfRange(u32, usize),
+
+    #[error("signature {:?} is not a single complete type", String::from_utf8_lossy(.0))]
+    NotSingleType(Vec<u8>),
+
+    #[error("dict key signature {:?} is not a basic type", String::from_utf8_lossy(.0))]
+    NonBasicDictKey(Vec<u8>),
+
+    #[error("unknown endianness marker {0:X}")]
+    UnknownEndianness(u8),
+
+    #[error("unknown message type {0}")]
+    UnknownMessageType(u8),
+
+    #[error("unsupported protocol version {0}")]
+    UnsupportedProtocolVersion(u8),
+
+    #[error("message serial is zero")]
+    ZeroSerial,
+
+    #[error("missing required header field {0}")]
+    MissingHeaderField(&'static str),
+
+    #[error("message needs {0} bytes, only {1} available")]
+    TruncatedMessage(usize, usize),
+
+    #[error("message is {0} bytes long, more than the limit of {1}")]
+    MessageTooLong(u64, usize),
+
+    #[error("I/O error: {0}")]
+    Io(String),
+
+    #[error("invalid message data at byte {1}, signature index {2}: {0}")]
+    InvalidMessage(Box<Error>, usize, usize),
+
+    #[error("invalid GVariant text at {1}: {0}")]
+    InvalidText(String, usize),
+
     #[cfg(feature = "zbus")]
     #[error("Error converting to or from ZBus message: {0}")]
     ZbusError(#[from] ZbusError),
@@ -24,11 +24,17 @@
 //! Messages are in the [`Message`] type, and currently
 //! that is the only format supported for serialization and
 //! deserialization directly. Conversion is available into
-//! [zbus] messages if the `zbus` feature is enabled.
+//! [zbus] messages if the `zbus` feature is enabled. Complete wire
+//! messages, with headers, can be encoded and decoded with the
+//! [`header`] module. Messages from untrusted sources can be checked
+//! against their signature with [`validate`] before deserializing them.
 //!
 //! Deserialization is handled by the [`de`] module, but
 //! really boils down to the [`from_message`] function,
 //! as deserialization cannot be substantially customized.
+//! Where the message alone is ambiguous, such as for the encoding
+//! of `Option`s, [`from_message_with_options`] takes the same
+//! choices the serializer was configured with.
 //!
 //! Serialization, on the other hand, is customizable.
 //! The [`ser`] module exposes basic serialization
@@ -38,20 +44,50 @@
 //! the [`serializer_policy`] module and by instead calling
 //! [`serialize_with_policy`].
 //!
+//! Object paths, signatures and Unix file descriptors, which serde has
+//! no types for, are in the [`types`] module. Signatures can be
+//! validated and parsed into type trees with the [`signature`] module.
+//!
+//! Values whose type is not known in advance can be deserialized into a
+//! [`Value`], which keeps their exact signature. Messages and values
+//! can be printed and parsed in GLib's GVariant text format, as used by
+//! `gdbus`, with the [`text`] module.
+//!
+//! The signature a type serializes to can also be computed from the
+//! type alone, with the [`DbusType`] trait in the [`dbus_type`] module.
+//! With the `derive` feature, it can be derived.
+//!
 //! [serde]: https://serde.rs
 //! [DBus]: https://www.freedesktop.org/wiki/Software/dbus/
 //! [zbus]: https://crates.io/crates/zbus
 //! [`Message`]: crate::message::Message
+//! [`DbusType`]: crate::dbus_type::DbusType
+//! [`dbus_type`]: crate::dbus_type
+//! [`header`]: crate::message::header
+//! [`validate`]: crate::message::validate()
 //! [`de`]: crate::de
 //! [`from_message`]: crate::de::from_message()
+//! [`from_message_with_options`]: crate::de::from_message_with_options()
 //! [`ser`]: crate::ser
 //! [`serialize`]: crate::ser::serialize()
 //! [`serializer_policy`]: crate::ser::serializer_policy
 //! [`serialize_with_policy`]: crate::ser::serialize_with_policy()
+//! [`signature`]: crate::signature
+//! [`types`]: crate::types
+//! [`Value`]: crate::value::Value
+//! [`text`]: crate::text
 
 mod align;
+pub mod dbus_type;
 pub mod de;
+mod endian;
 pub mod error;
 pub mod message;
 mod primitives;
 pub mod ser;
+pub mod signature;
+pub mod text;
+pub mod types;
+pub mod value;
+
+pub use value::Value;
@@ -1,11 +1,54 @@
+use crate::endian::swap_endianness;
 #[cfg(feature = "zbus")]
-use crate::error::{Error, Result};
+use crate::error::Error;
+use crate::error::Result;
 
 #[cfg(feature = "zbus")]
-use zbus::{Message as ZbusMessage, MessageBuilder as ZbusMessageBuilder};
+use zbus::{EndianSig, Message as ZbusMessage, MessageBuilder as ZbusMessageBuilder};
 
 #[cfg(feature = "zbus")]
 use std::convert::TryFrom;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, OwnedFd};
+#[cfg(unix)]
+use std::sync::Arc;
+
+pub mod header;
+pub mod reader;
+mod validate;
+
+pub use validate::{validate, MAX_ARRAY_LEN, MAX_VARIANT_DEPTH};
+
+#[cfg(unix)]
+pub(crate) use std::os::unix::io::RawFd;
+#[cfg(not(unix))]
+pub(crate) type RawFd = i32;
+
+// A Unix fd sent along with a message. The serializer writes duplicates
+// of the fds it is given, which are closed with the last copy of the
+// message. The caller of `Message::with_fds` keeps its fds open itself.
+#[derive(Clone, Debug)]
+pub(crate) enum MessageFd {
+    #[cfg(unix)]
+    Owned(Arc<OwnedFd>),
+    Raw(RawFd),
+}
+
+impl MessageFd {
+    pub(crate) fn raw(&self) -> RawFd {
+        match self {
+            #[cfg(unix)]
+            MessageFd::Owned(fd) => fd.as_raw_fd(),
+            MessageFd::Raw(fd) => *fd,
+        }
+    }
+}
+
+impl PartialEq for MessageFd {
+    fn eq(&self, other: &Self) -> bool {
+        self.raw() == other.raw()
+    }
+}
 
 /// This is the message type that is used for serialization
 /// and deserialization at this time. We will soon also support
@@ -17,25 +60,146 @@ use std::convert::TryFrom;
 /// and `)` at the top level if the top level is serialized struct
 /// style. These brackets will need to be removed before actually
 /// sending over DBus. This will be handled by the conversions to
-/// zbus messages.
+/// zbus messages, and is handled by [`header::encode_message`].
+///
+/// Unix file descriptors are sent alongside the message rather than in
+/// its data, which holds indices into [`fds`](Message::fds) instead.
+/// The serializer duplicates the fds it writes, and the message owns
+/// the duplicates. Deserializing a [`UnixFd`](crate::types::UnixFd)
+/// duplicates the fd again, so messages with fds they do not own can
+/// only be built with the unsafe [`with_fds`](Message::with_fds).
+///
+/// The data is in the byte order given by `endianness`, which is
+/// little-endian unless the serializer policy says otherwise.
 #[derive(Clone, Debug, PartialEq)]
 pub struct Message {
     pub data: Vec<u8>,
     pub signature: Vec<u8>,
+    pub(crate) fds: Vec<MessageFd>,
+    pub endianness: Endianness,
+}
+
+/// The byte order of a message's data, which DBus marks with `l` for
+/// little-endian and `B` for big-endian.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Endianness {
+    #[default]
+    Little,
+    Big,
+}
+
+impl Endianness {
+    /// The byte order of the machine this is running on.
+    pub const NATIVE: Endianness = if cfg!(target_endian = "big") {
+        Endianness::Big
+    } else {
+        Endianness::Little
+    };
+
+    /// The marker byte for this byte order in a DBus message header.
+    pub fn marker(self) -> u8 {
+        match self {
+            Endianness::Little => b'l',
+            Endianness::Big => b'B',
+        }
+    }
+
+    /// The byte order marked by `marker` in a DBus message header.
+    pub fn from_marker(marker: u8) -> Option<Endianness> {
+        match marker {
+            b'l' => Some(Endianness::Little),
+            b'B' => Some(Endianness::Big),
+            _ => None,
+        }
+    }
 }
 
 impl Message {
+    /// A message without Unix file descriptors.
+    pub fn new(data: Vec<u8>, signature: Vec<u8>, endianness: Endianness) -> Message {
+        Message {
+            data,
+            signature,
+            fds: Vec::new(),
+            endianness,
+        }
+    }
+
+    /// A message sent along with the Unix file descriptors `fds`.
+    ///
+    /// # Safety
+    ///
+    /// Every fd in `fds` must be open for as long as the message, or a
+    /// copy of it, is deserialized, because deserializing a
+    /// [`UnixFd`](crate::types::UnixFd) duplicates the fd at its index.
+    pub unsafe fn with_fds(
+        data: Vec<u8>,
+        signature: Vec<u8>,
+        fds: Vec<RawFd>,
+        endianness: Endianness,
+    ) -> Message {
+        Message {
+            data,
+            signature,
+            fds: fds.into_iter().map(MessageFd::Raw).collect(),
+            endianness,
+        }
+    }
+
+    /// The Unix file descriptors sent along with the message. Those the
+    /// serializer wrote are open for as long as the message, or a copy
+    /// of it, is.
+    pub fn fds(&self) -> Vec<RawFd> {
+        self.fds.iter().map(MessageFd::raw).collect()
+    }
+
+    /// Converts the data to the given byte order. This fails if the data
+    /// does not match the signature.
+    pub fn into_endianness(mut self, endianness: Endianness) -> Result<Message> {
+        if self.endianness != endianness {
+            swap_endianness(&mut self.data, &self.signature, self.endianness)?;
+            self.endianness = endianness;
+        }
+        Ok(self)
+    }
+
+    /// Like [`into_endianness`](Message::into_endianness), but copies the
+    /// message.
+    pub fn to_endianness(&self, endianness: Endianness) -> Result<Message> {
+        self.clone().into_endianness(endianness)
+    }
+
+    /// Builds a zbus message with this body. zbus does not take
+    /// ownership of the Unix fds it is given this way, so this message
+    /// must be kept until the zbus message is sent.
+    //
+    // zbus builds messages in the native byte order, so the data is
+    // converted to it first.
     #[cfg(feature = "zbus")]
     pub fn as_zbus_message(&self, builder: ZbusMessageBuilder) -> Result<ZbusMessage> {
+        if self.endianness != Endianness::NATIVE {
+            return self
+                .to_endianness(Endianness::NATIVE)?
+                .as_zbus_message(builder);
+        }
         // Safety: This is only an unsafe method because the DBus message
         // may be invalid. We constructed it with our serializer, so we
         // believe it to be a valid message.
         unsafe {
-            Ok(builder.build_raw_body(&self.data, self.signature.as_slice(), vec![])?)
+            Ok(builder.build_raw_body(
+                &self.data,
+                self.signature.as_slice(),
+                #[cfg(unix)]
+                self.fds(),
+            )?)
         }
     }
 }
 
+// zbus only gives up the file descriptors of a message along with their
+// ownership, so they are not carried over. They can be taken with
+// `ZbusMessage::take_fds` and their raw values given to
+// `Message::with_fds`.
 #[cfg(feature = "zbus")]
 impl TryFrom<&ZbusMessage> for Message {
     type Error = Error;
@@ -43,6 +207,10 @@ impl TryFrom<&ZbusMessage> for Message {
     fn try_from(value: &ZbusMessage) -> Result<Self> {
         let data = value.body_as_bytes()?.to_vec();
         let signature = value.body_signature()?.as_bytes().to_vec();
-        Ok(Message { data, signature })
+        let endianness = match value.primary_header().endian_sig() {
+            EndianSig::Little => Endianness::Little,
+            EndianSig::Big => Endianness::Big,
+        };
+        Ok(Message::new(data, signature, endianness))
     }
 }
@@ -0,0 +1,519 @@
+//! DBus message headers, for sending and receiving complete messages.
+//!
+//! A message on the wire starts with a fixed header, giving its byte
+//! order, type, flags, protocol version, body length and serial,
+//! followed by an array of header fields, of signature `a(yv)`. The
+//! header is padded to a multiple of 8 bytes, and the body follows it.
+//! Headers are encoded and decoded with this crate's own serializer and
+//! deserializer.
+//!
+//! Unix file descriptors are sent alongside the message, so
+//! [`decode_message`] leaves the body's `fds` empty, and the
+//! [`unix_fds`](HeaderFields::unix_fds) field says how many to expect.
+
+use crate::align::align;
+use crate::de::from_message;
+use crate::error::{Error, Result};
+use crate::message::{Endianness, Message};
+use crate::ser::serialize_with_signature;
+use crate::signature::single_type_end;
+use crate::types::{ObjectPath, Signature};
+
+use byteorder::{ByteOrder, BE, LE};
+use serde::de::{self, Deserialize, Deserializer, IgnoredAny, SeqAccess, Visitor};
+use serde::ser::{Serialize, Serializer};
+use std::fmt::{self, Formatter};
+
+/// The only version of the DBus protocol.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+// The fixed header, up to the length of the header field array.
+pub(super) const FIXED_HEADER_LEN: usize = 16;
+
+const HEADER_SIGNATURE: &str = "yyyyuua(yv)";
+
+/// The type of a DBus message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MessageType {
+    MethodCall,
+    MethodReturn,
+    Error,
+    Signal,
+}
+
+impl MessageType {
+    pub fn code(self) -> u8 {
+        match self {
+            MessageType::MethodCall => 1,
+            MessageType::MethodReturn => 2,
+            MessageType::Error => 3,
+            MessageType::Signal => 4,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Result<MessageType> {
+        match code {
+            1 => Ok(MessageType::MethodCall),
+            2 => Ok(MessageType::MethodReturn),
+            3 => Ok(MessageType::Error),
+            4 => Ok(MessageType::Signal),
+            _ => Err(Error::UnknownMessageType(code)),
+        }
+    }
+}
+
+/// The flags of a DBus message. Unknown flags are ignored.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Flags {
+    pub no_reply_expected: bool,
+    pub no_auto_start: bool,
+    pub allow_interactive_authorization: bool,
+}
+
+impl Flags {
+    pub fn bits(self) -> u8 {
+        (self.no_reply_expected as u8)
+            | (self.no_auto_start as u8) << 1
+            | (self.allow_interactive_authorization as u8) << 2
+    }
+
+    pub fn from_bits(bits: u8) -> Flags {
+        Flags {
+            no_reply_expected: bits & 1 != 0,
+            no_auto_start: bits & 2 != 0,
+            allow_interactive_authorization: bits & 4 != 0,
+        }
+    }
+}
+
+/// The header fields of a DBus message. Which of these are required
+/// depends on the [`MessageType`]. Unknown fields are ignored.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HeaderFields {
+    pub path: Option<ObjectPath>,
+    pub interface: Option<String>,
+    pub member: Option<String>,
+    pub error_name: Option<String>,
+    pub reply_serial: Option<u32>,
+    pub destination: Option<String>,
+    pub sender: Option<String>,
+    pub signature: Option<Signature>,
+    pub unix_fds: Option<u32>,
+}
+
+/// The header of a DBus message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    pub endianness: Endianness,
+    pub message_type: MessageType,
+    pub flags: Flags,
+    pub version: u8,
+    pub body_length: u32,
+    pub serial: u32,
+    pub fields: HeaderFields,
+}
+
+impl Header {
+    /// A header for a message with no body, with the default byte order
+    /// and flags, and no fields.
+    pub fn new(message_type: MessageType, serial: u32) -> Header {
+        Header {
+            endianness: Endianness::default(),
+            message_type,
+            flags: Flags::default(),
+            version: PROTOCOL_VERSION,
+            body_length: 0,
+            serial,
+            fields: HeaderFields::default(),
+        }
+    }
+
+    /// Encodes the header, with the padding that the body follows.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        self.check()?;
+        let f = &self.fields;
+        let fields: Vec<(u8, HeaderField)> = vec![
+            (1, f.path.as_ref().map(HeaderField::Path)),
+            (2, f.interface.as_deref().map(HeaderField::Str)),
+            (3, f.member.as_deref().map(HeaderField::Str)),
+            (4, f.error_name.as_deref().map(HeaderField::Str)),
+            (5, f.reply_serial.map(HeaderField::U32)),
+            (6, f.destination.as_deref().map(HeaderField::Str)),
+            (7, f.sender.as_deref().map(HeaderField::Str)),
+            (8, f.signature.as_ref().map(HeaderField::Signature)),
+            (9, f.unix_fds.map(HeaderField::U32)),
+        ]
+        .into_iter()
+        .filter_map(|(code, value)| Some((code, value?)))
+        .collect();
+
+        let header = (
+            self.endianness.marker(),
+            self.message_type.code(),
+            self.flags.bits(),
+            self.version,
+            self.body_length,
+            self.serial,
+            fields,
+        );
+        let mut data = serialize_with_signature(header, HEADER_SIGNATURE)?
+            .into_endianness(self.endianness)?
+            .data;
+        data.resize(align(data.len(), 8), 0);
+        Ok(data)
+    }
+
+    /// Decodes the header at the start of `data`, returning it along
+    /// with the index at which the body starts.
+    pub fn decode(data: &[u8]) -> Result<(Header, usize)> {
+        let len = header_fields_end(data)?;
+        let (endianness, _, _) = fixed_header_lengths(data)?;
+        let message = Message {
+            data: data[..len].to_vec(),
+            signature: format!("({})", HEADER_SIGNATURE).into_bytes(),
+            fds: Vec::new(),
+            endianness,
+        };
+        let (_, message_type, flags, version, body_length, serial, raw_fields): RawHeader =
+            from_message(&message)?;
+        if version != PROTOCOL_VERSION {
+            return Err(Error::UnsupportedProtocolVersion(version));
+        }
+
+        let mut fields = HeaderFields::default();
+        for field in raw_fields {
+            match field {
+                RawHeaderField::Path(path) => fields.path = Some(path),
+                RawHeaderField::Interface(s) => fields.interface = Some(s),
+                RawHeaderField::Member(s) => fields.member = Some(s),
+                RawHeaderField::ErrorName(s) => fields.error_name = Some(s),
+                RawHeaderField::ReplySerial(i) => fields.reply_serial = Some(i),
+                RawHeaderField::Destination(s) => fields.destination = Some(s),
+                RawHeaderField::Sender(s) => fields.sender = Some(s),
+                RawHeaderField::Signature(sig) => fields.signature = Some(sig),
+                RawHeaderField::UnixFds(i) => fields.unix_fds = Some(i),
+                RawHeaderField::Unknown => {}
+            }
+        }
+
+        let header = Header {
+            endianness,
+            message_type: MessageType::from_code(message_type)?,
+            flags: Flags::from_bits(flags),
+            version,
+            body_length,
+            serial,
+            fields,
+        };
+        header.check()?;
+        Ok((header, align(len, 8)))
+    }
+
+    // Checks the serial, and that the fields required for the message
+    // type are present.
+    fn check(&self) -> Result<()> {
+        if self.serial == 0 {
+            return Err(Error::ZeroSerial);
+        }
+        let f = &self.fields;
+        let missing = match self.message_type {
+            MessageType::MethodCall if f.path.is_none() => Some("path"),
+            MessageType::MethodCall if f.member.is_none() => Some("member"),
+            MessageType::MethodReturn if f.reply_serial.is_none() => Some("reply_serial"),
+            MessageType::Error if f.error_name.is_none() => Some("error_name"),
+            MessageType::Error if f.reply_serial.is_none() => Some("reply_serial"),
+            MessageType::Signal if f.path.is_none() => Some("path"),
+            MessageType::Signal if f.interface.is_none() => Some("interface"),
+            MessageType::Signal if f.member.is_none() => Some("member"),
+            _ => None,
+        };
+        match missing {
+            Some(field) => Err(Error::MissingHeaderField(field)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The longest message allowed by the DBus specification, 128 MiB.
+pub const MAX_MESSAGE_LEN: usize = 1 << 27;
+
+// The byte order and the lengths of the header field array and the body
+// of the message at the start of `data`, from its fixed header.
+fn fixed_header_lengths(data: &[u8]) -> Result<(Endianness, usize, usize)> {
+    if data.len() < FIXED_HEADER_LEN {
+        return Err(Error::TruncatedMessage(FIXED_HEADER_LEN, data.len()));
+    }
+    let endianness = match Endianness::from_marker(data[0]) {
+        Some(endianness) => endianness,
+        None => return Err(Error::UnknownEndianness(data[0])),
+    };
+    let (body_len, fields_len) = match endianness {
+        Endianness::Little => (LE::read_u32(&data[4..8]), LE::read_u32(&data[12..16])),
+        Endianness::Big => (BE::read_u32(&data[4..8]), BE::read_u32(&data[12..16])),
+    };
+    Ok((endianness, fields_len as usize, body_len as usize))
+}
+
+// The end of the header field array at the start of `data`, before the
+// padding that follows it.
+fn header_fields_end(data: &[u8]) -> Result<usize> {
+    let (_, fields_len, _) = fixed_header_lengths(data)?;
+    let len = FIXED_HEADER_LEN + fields_len;
+    if data.len() < len {
+        return Err(Error::TruncatedMessage(len, data.len()));
+    }
+    Ok(len)
+}
+
+/// The total length of the message at the start of `data`, worked out
+/// from its fixed header, or `None` if `data` is too short to hold the
+/// fixed header. Messages longer than [`MAX_MESSAGE_LEN`] are rejected.
+pub fn message_length(data: &[u8]) -> Result<Option<usize>> {
+    if data.len() < FIXED_HEADER_LEN {
+        return Ok(None);
+    }
+    let (_, fields_len, body_len) = fixed_header_lengths(data)?;
+    // The header is padded to a multiple of 8 bytes.
+    let header_len = (FIXED_HEADER_LEN as u64 + fields_len as u64 + 7) & !7;
+    let len = header_len + body_len as u64;
+    if len > MAX_MESSAGE_LEN as u64 {
+        return Err(Error::MessageTooLong(len, MAX_MESSAGE_LEN));
+    }
+    Ok(Some(len as usize))
+}
+
+/// Encodes a complete message with the given header and body. The body
+/// length, signature and number of Unix fds in the header are filled in
+/// from the body, which is converted to the header's byte order.
+pub fn encode_message(header: &Header, body: &Message) -> Result<Vec<u8>> {
+    let mut header = header.clone();
+    header.body_length = body.data.len() as u32;
+    // The top-level struct of a message holds its arguments, and is not
+    // part of its signature on the wire.
+    let sig = body.signature.as_slice();
+    let sig = match sig {
+        [b'(', inner @ .., b')'] if single_type_end(sig, 0)? == sig.len() => inner,
+        _ => sig,
+    };
+    header.fields.signature = match sig {
+        [] => None,
+        sig => Some(Signature::new(String::from_utf8_lossy(sig))?),
+    };
+    header.fields.unix_fds = match body.fds.len() {
+        0 => None,
+        len => Some(len as u32),
+    };
+    let mut data = header.encode()?;
+    data.extend_from_slice(&body.to_endianness(header.endianness)?.data);
+    Ok(data)
+}
+
+/// Decodes a complete message from the start of `data`, returning its
+/// header and body, along with the number of bytes it took up.
+pub fn decode_message(data: &[u8]) -> Result<(Header, Message, usize)> {
+    let (header, body_start) = Header::decode(data)?;
+    let end = body_start + header.body_length as usize;
+    let body = data
+        .get(body_start..end)
+        .ok_or(Error::TruncatedMessage(end, data.len()))?;
+    // Several arguments are bracketed as a struct, as the serializer
+    // does.
+    let mut signature = match &header.fields.signature {
+        Some(sig) => sig.as_str().as_bytes().to_vec(),
+        None => Vec::new(),
+    };
+    if !signature.is_empty() && single_type_end(&signature, 0)? != signature.len() {
+        signature.insert(0, b'(');
+        signature.push(b')');
+    }
+    let body = Message {
+        data: body.to_vec(),
+        signature,
+        fds: Vec::new(),
+        endianness: header.endianness,
+    };
+    Ok((header, body, end))
+}
+
+// The value of a header field being encoded, which is serialized in a
+// variant with its own signature.
+enum HeaderField<'a> {
+    Path(&'a ObjectPath),
+    Str(&'a str),
+    U32(u32),
+    Signature(&'a Signature),
+}
+
+impl Serialize for HeaderField<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            HeaderField::Path(path) => path.serialize(serializer),
+            HeaderField::Str(s) => s.serialize(serializer),
+            HeaderField::U32(i) => i.serialize(serializer),
+            HeaderField::Signature(sig) => sig.serialize(serializer),
+        }
+    }
+}
+
+type RawHeader = (u8, u8, u8, u8, u32, u32, Vec<RawHeaderField>);
+
+// A decoded header field, whose type depends on its code.
+enum RawHeaderField {
+    Path(ObjectPath),
+    Interface(String),
+    Member(String),
+    ErrorName(String),
+    ReplySerial(u32),
+    Destination(String),
+    Sender(String),
+    Signature(Signature),
+    UnixFds(u32),
+    Unknown,
+}
+
+impl<'de> Deserialize<'de> for RawHeaderField {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct FieldVisitor;
+
+        impl<'de> Visitor<'de> for FieldVisitor {
+            type Value = RawHeaderField;
+
+            fn expecting(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                f.write_str("a DBus header field")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> std::result::Result<RawHeaderField, A::Error> {
+                fn value<'de, T: Deserialize<'de>, A: SeqAccess<'de>>(
+                    seq: &mut A,
+                ) -> std::result::Result<T, A::Error> {
+                    seq.next_element()?
+                        .ok_or_else(|| de::Error::invalid_length(1, &"a header field value"))
+                }
+
+                let code: u8 = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(0, &"a header field code"))?;
+                Ok(match code {
+                    1 => RawHeaderField::Path(value(&mut seq)?),
+                    2 => RawHeaderField::Interface(value(&mut seq)?),
+                    3 => RawHeaderField::Member(value(&mut seq)?),
+                    4 => RawHeaderField::ErrorName(value(&mut seq)?),
+                    5 => RawHeaderField::ReplySerial(value(&mut seq)?),
+                    6 => RawHeaderField::Destination(value(&mut seq)?),
+                    7 => RawHeaderField::Sender(value(&mut seq)?),
+                    8 => RawHeaderField::Signature(value(&mut seq)?),
+                    9 => RawHeaderField::UnixFds(value(&mut seq)?),
+                    _ => {
+                        value::<IgnoredAny, _>(&mut seq)?;
+                        RawHeaderField::Unknown
+                    }
+                })
+            }
+        }
+
+        deserializer.deserialize_tuple(2, FieldVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::de::from_message;
+    use crate::error::{Error, Result};
+    use crate::message::header::{decode_message, encode_message, Flags, Header, MessageType};
+    use crate::message::Endianness;
+    use crate::ser::serialize_with_signature;
+    use crate::types::ObjectPath;
+
+    fn method_call(serial: u32) -> Result<Header> {
+        let mut header = Header::new(MessageType::MethodCall, serial);
+        header.fields.path = Some(ObjectPath::new("/org/freedesktop/DBus")?);
+        header.fields.interface = Some("org.freedesktop.DBus".to_owned());
+        header.fields.member = Some("Hello".to_owned());
+        header.fields.destination = Some("org.freedesktop.DBus".to_owned());
+        Ok(header)
+    }
+
+    #[test]
+    fn encode_hello() -> Result<()> {
+        let mut expected = vec![
+            b'l', 1, 0, 1, 0, 0, 0, 0, 1, 0, 0, 0, 0x6d, 0, 0, 0, // fixed header
+            1, 1, b'o', 0, 21, 0, 0, 0,
+        ];
+        expected.extend_from_slice(b"/org/freedesktop/DBus\0");
+        expected.extend_from_slice(&[0, 0, 2, 1, b's', 0, 20, 0, 0, 0]);
+        expected.extend_from_slice(b"org.freedesktop.DBus\0");
+        expected.extend_from_slice(&[0, 0, 0, 3, 1, b's', 0, 5, 0, 0, 0]);
+        expected.extend_from_slice(b"Hello\0");
+        expected.extend_from_slice(&[0, 0, 6, 1, b's', 0, 20, 0, 0, 0]);
+        expected.extend_from_slice(b"org.freedesktop.DBus\0");
+        expected.extend_from_slice(&[0, 0, 0]);
+
+        let data = method_call(1)?.encode()?;
+        assert_eq!(data, expected);
+        let (header, body_start) = Header::decode(&data)?;
+        assert_eq!(header, method_call(1)?);
+        assert_eq!(body_start, data.len());
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip_messages() -> Result<()> {
+        let body = serialize_with_signature((3u32, "a"), "us")?;
+        for endianness in [Endianness::Little, Endianness::Big] {
+            let mut header = method_call(7)?;
+            header.endianness = endianness;
+            header.flags = Flags::from_bits(5);
+            let mut data = encode_message(&header, &body)?;
+            let len = data.len();
+            data.extend_from_slice(b"next message");
+
+            let (header2, body2, end) = decode_message(&data)?;
+            assert_eq!(end, len);
+            assert_eq!(header2.endianness, endianness);
+            assert_eq!(header2.flags, header.flags);
+            assert_eq!(header2.body_length, body.data.len() as u32);
+            assert_eq!(header2.fields.signature.unwrap().as_str(), "us");
+            assert_eq!(body2.signature, b"(us)".to_vec());
+            assert_eq!(from_message::<(u32, String)>(&body2)?, (3, "a".to_owned()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_headers() -> Result<()> {
+        let data = method_call(1)?.encode()?;
+        assert_eq!(
+            Header::decode(&data[..10]),
+            Err(Error::TruncatedMessage(16, 10))
+        );
+        assert_eq!(
+            Header::decode(&data[..30]),
+            Err(Error::TruncatedMessage(125, 30))
+        );
+
+        let mut bad = data.clone();
+        bad[0] = b'x';
+        assert_eq!(Header::decode(&bad), Err(Error::UnknownEndianness(b'x')));
+        let mut bad = data.clone();
+        bad[1] = 9;
+        assert_eq!(Header::decode(&bad), Err(Error::UnknownMessageType(9)));
+        let mut bad = data.clone();
+        bad[3] = 2;
+        assert_eq!(
+            Header::decode(&bad),
+            Err(Error::UnsupportedProtocolVersion(2))
+        );
+
+        let mut header = method_call(1)?;
+        header.fields.member = None;
+        assert_eq!(header.encode(), Err(Error::MissingHeaderField("member")));
+        assert_eq!(
+            Header::new(MessageType::Signal, 0).encode(),
+            Err(Error::ZeroSerial)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,285 @@
+//! Reading complete messages from a stream of bytes.
+//!
+//! [`MessageBuffer`] takes bytes in chunks of any size, such as those
+//! read from a socket or a capture, and gives back each message once all
+//! of it has arrived. [`MessageReader`] does the same for any [`Read`].
+//!
+//! The length of each message is worked out from its fixed header, so a
+//! message that fails to decode is skipped, and the messages after it
+//! can still be read. If the fixed header itself is invalid, the start
+//! of the next message cannot be found, and the same error is returned
+//! until the buffer is cleared. Iterating over a [`MessageReader`] ends
+//! after such an error, or after the stream ends in the middle of a
+//! message, rather than returning the error forever.
+
+use crate::error::{Error, Result};
+use crate::message::header::{decode_message, message_length, Header, FIXED_HEADER_LEN};
+use crate::message::Message;
+
+use std::io::{ErrorKind, Read};
+
+/// A buffer for bytes that arrive in chunks, from which complete messages
+/// are taken.
+#[derive(Clone, Debug, Default)]
+pub struct MessageBuffer {
+    data: Vec<u8>,
+}
+
+impl MessageBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the next bytes of the stream.
+    pub fn push(&mut self, chunk: &[u8]) {
+        self.data.extend_from_slice(chunk);
+    }
+
+    /// Takes the next message, if all of it has arrived. A message that
+    /// has arrived but fails to decode is removed from the buffer.
+    pub fn next_message(&mut self) -> Result<Option<(Header, Message)>> {
+        let len = match message_length(&self.data)? {
+            Some(len) if len <= self.data.len() => len,
+            _ => return Ok(None),
+        };
+        let res = decode_message(&self.data[..len]);
+        self.data.drain(..len);
+        let (header, body, _) = res?;
+        Ok(Some((header, body)))
+    }
+
+    /// The number of bytes that are not yet part of a complete message.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Discards the buffered bytes, such as after an error in a fixed
+    /// header.
+    pub fn clear(&mut self) {
+        self.data.clear();
+    }
+
+    // Whether the fixed header of the next message has arrived, and
+    // is invalid.
+    fn has_invalid_header(&self) -> bool {
+        message_length(&self.data).is_err()
+    }
+
+    // The length of the message being waited for, if its fixed header
+    // has arrived.
+    fn pending_len(&self) -> usize {
+        match message_length(&self.data) {
+            Ok(Some(len)) => len,
+            _ => FIXED_HEADER_LEN,
+        }
+    }
+}
+
+/// Reads complete messages from `R`. This buffers whatever it reads, so
+/// any bytes after the last message read are held here.
+#[derive(Debug)]
+pub struct MessageReader<R: Read> {
+    reader: R,
+    buffer: MessageBuffer,
+
+    // Set once iterating can give no more messages
+    fused: bool,
+}
+
+impl<R: Read> MessageReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buffer: MessageBuffer::new(),
+            fused: false,
+        }
+    }
+
+    /// Reads the next message, or returns `None` if the stream ends
+    /// between messages. If the stream ends in the middle of a message,
+    /// this fails with [`Error::TruncatedMessage`].
+    pub fn read_message(&mut self) -> Result<Option<(Header, Message)>> {
+        let mut chunk = [0u8; 4096];
+        loop {
+            if let Some(message) = self.buffer.next_message()? {
+                return Ok(Some(message));
+            }
+            let read = match self.reader.read(&mut chunk) {
+                Ok(read) => read,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(Error::Io(err.to_string())),
+            };
+            if read == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                return Err(Error::TruncatedMessage(
+                    self.buffer.pending_len(),
+                    self.buffer.len(),
+                ));
+            }
+            self.buffer.push(&chunk[..read]);
+        }
+    }
+
+    pub fn buffer(&mut self) -> &mut MessageBuffer {
+        &mut self.buffer
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read> Iterator for MessageReader<R> {
+    type Item = Result<(Header, Message)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.fused {
+            return None;
+        }
+        let res = self.read_message();
+        match res {
+            Err(Error::TruncatedMessage(..)) => self.fused = true,
+            Err(_) if self.buffer.has_invalid_header() => self.fused = true,
+            _ => {}
+        }
+        res.transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::de::from_message;
+    use crate::error::{Error, Result};
+    use crate::message::header::{encode_message, Header, MessageType};
+    use crate::message::reader::{MessageBuffer, MessageReader};
+    use crate::ser::serialize;
+    use crate::types::ObjectPath;
+
+    fn signal(serial: u32, body: &str) -> Result<Vec<u8>> {
+        let mut header = Header::new(MessageType::Signal, serial);
+        header.fields.path = Some(ObjectPath::new("/a")?);
+        header.fields.interface = Some("a.b".to_owned());
+        header.fields.member = Some("C".to_owned());
+        encode_message(&header, &serialize(body)?)
+    }
+
+    #[test]
+    fn read_in_chunks() -> Result<()> {
+        let mut stream = signal(1, "first")?;
+        stream.extend(signal(2, "second")?);
+
+        // Every split of the stream into two chunks gives both messages.
+        for split in 0..stream.len() {
+            let mut buffer = MessageBuffer::new();
+            let mut received = Vec::new();
+            for chunk in [&stream[..split], &stream[split..]] {
+                buffer.push(chunk);
+                while let Some((header, body)) = buffer.next_message()? {
+                    received.push((header.serial, from_message::<String>(&body)?));
+                }
+            }
+            assert_eq!(
+                received,
+                vec![(1, "first".to_owned()), (2, "second".to_owned())]
+            );
+            assert!(buffer.is_empty());
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_from_socket() -> Result<()> {
+        use std::io::Write;
+        use std::os::unix::net::UnixStream;
+        use std::thread;
+
+        let (mut writer, reader) = UnixStream::pair().unwrap();
+        let sender = thread::spawn(move || -> Result<()> {
+            for serial in 1..=20 {
+                let message = signal(serial, &"x".repeat(serial as usize * 500))?;
+                for chunk in message.chunks(7) {
+                    writer.write_all(chunk).unwrap();
+                }
+            }
+            Ok(())
+        });
+
+        let mut count = 0;
+        for message in MessageReader::new(reader) {
+            let (header, body) = message?;
+            count += 1;
+            assert_eq!(header.serial, count);
+            assert_eq!(from_message::<String>(&body)?.len(), count as usize * 500);
+        }
+        assert_eq!(count, 20);
+        sender.join().unwrap()
+    }
+
+    #[test]
+    fn bad_input() -> Result<()> {
+        let message = signal(1, "a")?;
+
+        let mut reader = MessageReader::new(&message[..message.len() - 1]);
+        assert_eq!(
+            reader.read_message(),
+            Err(Error::TruncatedMessage(message.len(), message.len() - 1))
+        );
+
+        // A message that does not decode is skipped.
+        let mut stream = message.clone();
+        stream[1] = 9;
+        stream.extend_from_slice(&message);
+        let mut reader = MessageReader::new(stream.as_slice());
+        assert_eq!(reader.read_message(), Err(Error::UnknownMessageType(9)));
+        assert_eq!(reader.read_message()?.map(|(h, _)| h.serial), Some(1));
+        assert_eq!(reader.read_message(), Ok(None));
+
+        let too_long = [
+            b'l', 1, 0, 1, 0xff, 0xff, 0xff, 0xff, 1, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let mut buffer = MessageBuffer::new();
+        buffer.push(&too_long);
+        assert_eq!(
+            buffer.next_message(),
+            Err(Error::MessageTooLong(0xffff_ffff + 16, 1 << 27))
+        );
+
+        // Iterating stops after errors that would otherwise repeat.
+        let mut stream = too_long.to_vec();
+        stream.extend_from_slice(&message);
+        let received: Vec<_> = MessageReader::new(stream.as_slice()).collect();
+        assert_eq!(
+            received,
+            vec![Err(Error::MessageTooLong(0xffff_ffff + 16, 1 << 27))]
+        );
+        let received: Vec<_> = MessageReader::new(&message[..20]).collect();
+        assert_eq!(
+            received,
+            vec![Err(Error::TruncatedMessage(message.len(), 20))]
+        );
+
+        // Mangled messages give errors rather than panics.
+        let mut state = 0x2545_f491u32;
+        for _ in 0..2000 {
+            let mut mangled = message.clone();
+            for _ in 0..3 {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                let ix = state as usize % mangled.len();
+                mangled[ix] = (state >> 24) as u8;
+            }
+            let mut buffer = MessageBuffer::new();
+            buffer.push(&mangled);
+            let _ = buffer.next_message();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,338 @@
+use crate::align::align;
+use crate::error::{Error, Result};
+use crate::message::{Endianness, Message};
+use crate::signature::{self, alignment, single_type_end};
+use crate::types::ObjectPath;
+
+use byteorder::{ByteOrder, BE, LE};
+use std::str::from_utf8;
+
+/// The longest array allowed by the DBus specification, 64 MiB.
+pub const MAX_ARRAY_LEN: usize = 1 << 26;
+
+/// The deepest nesting of variants within variants that is accepted.
+pub const MAX_VARIANT_DEPTH: usize = 64;
+
+/// Checks that a message's data is valid for its signature, as the DBus
+/// specification requires, without deserializing it. This checks that:
+///
+/// * the signature and the signatures of variants are valid;
+/// * arrays are at most [`MAX_ARRAY_LEN`] bytes long, their items end
+///   where the array does, and nothing runs past the end of the data;
+/// * booleans are 0 or 1;
+/// * strings and object paths are UTF-8 and nul-terminated, without
+///   interior nuls, and object paths are valid;
+/// * padding is zero;
+/// * variants are nested at most [`MAX_VARIANT_DEPTH`] deep;
+/// * there is no data after the values of the signature.
+///
+/// Unix fd indices are not checked against `fds`, which may not have
+/// arrived yet.
+///
+/// An invalid signature fails with its own error. The first problem in
+/// the data fails with [`Error::InvalidMessage`], which gives the byte
+/// offset at which it was found and the index in the signature of the
+/// value it was found in. Within variants, this is the index of the `v`.
+pub fn validate(message: &Message) -> Result<()> {
+    let sig = message.signature.as_slice();
+    signature::validate(sig)?;
+    let mut validator = Validator {
+        data: &message.data,
+        ix: 0,
+        endianness: message.endianness,
+        variant_depth: 0,
+    };
+    let mut sig_ix = 0;
+    while sig_ix < sig.len() {
+        let end = single_type_end(sig, sig_ix)?;
+        validator.value(&sig[sig_ix..end], sig_ix, true)?;
+        sig_ix = end;
+    }
+    if validator.ix != validator.data.len() {
+        let leftover = validator.data.len() - validator.ix;
+        return Err(validator.error(Error::LeftoverData(leftover), validator.ix, sig.len()));
+    }
+    Ok(())
+}
+
+struct Validator<'a> {
+    data: &'a [u8],
+    ix: usize,
+    endianness: Endianness,
+    variant_depth: usize,
+}
+
+impl<'a> Validator<'a> {
+    fn error(&self, err: Error, offset: usize, sig_ix: usize) -> Error {
+        Error::InvalidMessage(Box::new(err), offset, sig_ix)
+    }
+
+    fn align(&mut self, alignment: usize, sig_ix: usize) -> Result<()> {
+        let end = align(self.ix, alignment);
+        let padding = self.take(end - self.ix, sig_ix)?;
+        if let Some(pos) = padding.iter().position(|&b| b != 0) {
+            return Err(self.error(Error::NonZeroPadding, end - padding.len() + pos, sig_ix));
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, len: usize, sig_ix: usize) -> Result<&'a [u8]> {
+        let start = self.ix;
+        let end = start + len;
+        match self.data.get(start..end) {
+            Some(bytes) => {
+                self.ix = end;
+                Ok(bytes)
+            }
+            None => Err(self.error(Error::IndexOutOfBounds(end), start, sig_ix)),
+        }
+    }
+
+    fn u32(&mut self, sig_ix: usize) -> Result<u32> {
+        self.align(4, sig_ix)?;
+        let bytes = self.take(4, sig_ix)?;
+        Ok(match self.endianness {
+            Endianness::Little => LE::read_u32(bytes),
+            Endianness::Big => BE::read_u32(bytes),
+        })
+    }
+
+    // A string of `len` bytes, followed by a nul, starting at `start`.
+    fn string(&mut self, len: usize, start: usize, sig_ix: usize) -> Result<&'a str> {
+        let bytes = self.take(len + 1, sig_ix)?;
+        if bytes[len] != 0 {
+            return Err(self.error(Error::MissingNul, start, sig_ix));
+        }
+        if bytes[..len].contains(&0) {
+            return Err(self.error(Error::InteriorNul, start, sig_ix));
+        }
+        from_utf8(&bytes[..len]).map_err(|err| self.error(err.into(), start, sig_ix))
+    }
+
+    // `sig` is a single complete type, at `sig_ix` in the message's
+    // signature if `tracked`, and within a variant at `sig_ix` if not.
+    fn value(&mut self, sig: &[u8], sig_ix: usize, tracked: bool) -> Result<()> {
+        let child_ix = |offset: usize| if tracked { sig_ix + offset } else { sig_ix };
+        match sig[0] {
+            b'y' => {
+                self.take(1, sig_ix)?;
+            }
+            b'n' | b'q' => {
+                self.align(2, sig_ix)?;
+                self.take(2, sig_ix)?;
+            }
+            b'i' | b'u' | b'h' => {
+                self.u32(sig_ix)?;
+            }
+            b'x' | b't' | b'd' => {
+                self.align(8, sig_ix)?;
+                self.take(8, sig_ix)?;
+            }
+            b'b' => {
+                let value = self.u32(sig_ix)?;
+                if value > 1 {
+                    let err = Error::InvalidBoolValue(value);
+                    return Err(self.error(err, self.ix - 4, sig_ix));
+                }
+            }
+            b's' | b'o' => {
+                let len = self.u32(sig_ix)? as usize;
+                let start = self.ix;
+                let s = self.string(len, start, sig_ix)?;
+                if sig[0] == b'o' {
+                    if let Err(err) = ObjectPath::new(s) {
+                        return Err(self.error(err, start, sig_ix));
+                    }
+                }
+            }
+            b'g' => {
+                self.signature(sig_ix)?;
+            }
+            b'v' => {
+                let start = self.ix;
+                let inner = self.signature(sig_ix)?;
+                if inner.is_empty() || single_type_end(inner, 0) != Ok(inner.len()) {
+                    let err = Error::NotSingleType(inner.to_vec());
+                    return Err(self.error(err, start, sig_ix));
+                }
+                if self.variant_depth == MAX_VARIANT_DEPTH {
+                    let err = Error::VariantNestingTooDeep(MAX_VARIANT_DEPTH + 1);
+                    return Err(self.error(err, start, sig_ix));
+                }
+                self.variant_depth += 1;
+                self.value(inner, sig_ix, false)?;
+                self.variant_depth -= 1;
+            }
+            b'a' => {
+                let len_start = align(self.ix, 4);
+                let len = self.u32(sig_ix)?;
+                if len as usize > MAX_ARRAY_LEN {
+                    let err = Error::ArrayTooLong(len as usize, MAX_ARRAY_LEN, len_start);
+                    return Err(self.error(err, len_start, sig_ix));
+                }
+                self.align(alignment(sig[1])?, sig_ix)?;
+                let end = self.ix + len as usize;
+                if end > self.data.len() {
+                    return Err(self.error(Error::IndexOutOfBounds(end), len_start, sig_ix));
+                }
+                while self.ix < end {
+                    self.value(&sig[1..], child_ix(1), tracked)?;
+                }
+                if self.ix != end {
+                    let err = Error::ArrayElementOverrun(self.ix, end);
+                    return Err(self.error(err, end, sig_ix));
+                }
+            }
+            b'(' | b'{' => {
+                self.align(8, sig_ix)?;
+                let mut ix = 1;
+                while ix < sig.len() - 1 {
+                    let end = single_type_end(sig, ix)?;
+                    self.value(&sig[ix..end], child_ix(ix), tracked)?;
+                    ix = end;
+                }
+            }
+            other => return Err(Error::UnrecognizedSignatureCharacter(other)),
+        }
+        Ok(())
+    }
+
+    // Reads a signature, and checks that it is valid.
+    fn signature(&mut self, sig_ix: usize) -> Result<&'a [u8]> {
+        let start = self.ix;
+        let len = self.take(1, sig_ix)?[0] as usize;
+        let sig = self.string(len, start + 1, sig_ix)?.as_bytes();
+        if let Err(err) = signature::validate(sig) {
+            return Err(self.error(err, start, sig_ix));
+        }
+        Ok(sig)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::{Error, Result};
+    use crate::message::validate::{validate, MAX_VARIANT_DEPTH};
+    use crate::message::{Endianness, Message};
+    use crate::ser::{serialize, serialize_with_signature};
+    use crate::types::{ObjectPath, Signature};
+    use crate::value::Value;
+    use std::collections::BTreeMap;
+
+    fn message(data: Vec<u8>, signature: &str) -> Message {
+        Message {
+            data,
+            signature: signature.as_bytes().to_vec(),
+            fds: Vec::new(),
+            endianness: Endianness::Little,
+        }
+    }
+
+    fn invalid(err: Error, offset: usize, sig_ix: usize) -> Result<()> {
+        Err(Error::InvalidMessage(Box::new(err), offset, sig_ix))
+    }
+
+    #[test]
+    fn serializer_output_is_valid() -> Result<()> {
+        let mut dict = BTreeMap::new();
+        dict.insert("a".to_owned(), Value::from(1u8).into_variant());
+        dict.insert("b".to_owned(), Value::from("x").into_variant());
+        let value = (
+            true,
+            vec![(1i16, 2u64)],
+            dict,
+            ObjectPath::new("/org/a")?,
+            Signature::new("a{sv}")?,
+            Vec::<f64>::new(),
+        );
+        validate(&serialize(&value)?)?;
+        validate(&serialize(&value)?.into_endianness(Endianness::Big)?)?;
+        validate(&serialize_with_signature((1u8, 2u32), "yv")?)?;
+        validate(&message(Vec::new(), ""))
+    }
+
+    #[test]
+    fn invalid_data() -> Result<()> {
+        assert_eq!(
+            validate(&message(vec![2, 0, 0, 0], "b")),
+            invalid(Error::InvalidBoolValue(2), 0, 0)
+        );
+        assert_eq!(
+            validate(&message(vec![1, 1, 0, 0, 0, 0, 0, 0], "yu")),
+            invalid(Error::NonZeroPadding, 1, 1)
+        );
+        assert_eq!(
+            validate(&message(vec![1, 0, 0, 0, 2, 0, 0], "(ii)")),
+            invalid(Error::IndexOutOfBounds(8), 4, 2)
+        );
+        assert_eq!(
+            validate(&message(vec![1, 0, 0, 0, 2], "y")),
+            invalid(Error::LeftoverData(4), 1, 1)
+        );
+        assert_eq!(
+            validate(&message(vec![2, 0, 0, 0, b'a', 0, b'b'], "s")),
+            invalid(Error::MissingNul, 4, 0)
+        );
+        assert_eq!(
+            validate(&message(vec![5, 0, 0, 0, 1, 2], "ay")),
+            invalid(Error::IndexOutOfBounds(9), 0, 0)
+        );
+        assert_eq!(
+            validate(&message(vec![3, 0, 0, 0, b'/', b'a', b'/', 0], "o")),
+            invalid(Error::InvalidObjectPath("/a/".to_owned()), 4, 0)
+        );
+        let bytes = vec![0xff];
+        let invalid_utf8 = std::str::from_utf8(&bytes).unwrap_err();
+        assert_eq!(
+            validate(&message(vec![1, 0, 0, 0, 0xff, 0], "s")),
+            invalid(invalid_utf8.into(), 4, 0)
+        );
+
+        // An array of 2 bytes whose item runs past it
+        assert_eq!(
+            validate(&message(vec![2, 0, 0, 0, 1, 0, 0, 0], "au")),
+            invalid(Error::ArrayElementOverrun(8, 6), 6, 0)
+        );
+        assert_eq!(
+            validate(&message(vec![0, 0, 0, 8], "ay")),
+            invalid(Error::ArrayTooLong(1 << 27, 1 << 26, 0), 0, 0)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_variants() -> Result<()> {
+        // A string inside `(yv)`, with an interior nul
+        let data = vec![7, 1, b's', 0, 2, 0, 0, 0, b'a', 0, 0];
+        assert_eq!(
+            validate(&message(data, "(yv)")),
+            invalid(Error::InteriorNul, 8, 2)
+        );
+        assert_eq!(
+            validate(&message(vec![2, b'y', b'y', 0, 1, 1], "v")),
+            invalid(Error::NotSingleType(b"yy".to_vec()), 0, 0)
+        );
+        assert_eq!(
+            validate(&message(vec![3, b'a', b'[', b'y', 0], "v")),
+            invalid(Error::UnrecognizedSignatureCharacter(b'['), 0, 0)
+        );
+
+        let mut nested = Value::from(1u8);
+        for _ in 0..MAX_VARIANT_DEPTH {
+            nested = nested.into_variant();
+        }
+        validate(&serialize(&nested)?)?;
+        let res = validate(&serialize(nested.into_variant())?);
+        assert!(
+            matches!(&res, Err(Error::InvalidMessage(err, _, 0)) if **err == Error::VariantNestingTooDeep(65)),
+            "{:?}",
+            res
+        );
+
+        assert_eq!(
+            validate(&message(Vec::new(), "a{vs}")),
+            Err(Error::NonBasicDictKey(b"v".to_vec()))
+        );
+        Ok(())
+    }
+}
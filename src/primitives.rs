@@ -1,10 +1,10 @@
-use crate::error::Result;
+use crate::error::{Error, Result};
+use crate::types::{ObjectPath, Signature, UnixFdIndex};
 
 use std::mem::size_of;
 
 pub(crate) trait DbusPrimitive {
     fn signature() -> u8;
-    fn alignment() -> usize;
     fn size(&self) -> usize;
     fn serialize(&self, out: &mut [u8]) -> Result<()>;
 }
@@ -24,10 +24,6 @@ macro_rules! basic_primitive {
                 out.copy_from_slice(&self.to_le_bytes());
                 Ok(())
             }
-
-            fn alignment() -> usize {
-                size_of::<$type>()
-            }
         }
     };
 }
@@ -54,10 +50,6 @@ impl DbusPrimitive for bool {
         out.copy_from_slice(&(*self as u32).to_le_bytes());
         Ok(())
     }
-
-    fn alignment() -> usize {
-        4
-    }
 }
 
 impl DbusPrimitive for &str {
@@ -71,63 +63,63 @@ impl DbusPrimitive for &str {
 
     fn serialize(&self, out: &mut [u8]) -> Result<()> {
         let bytes = self.as_bytes();
+        if bytes.contains(&0) {
+            return Err(Error::InteriorNul);
+        }
         out[0..4].copy_from_slice(&(bytes.len() as u32).to_le_bytes());
         out[4..4 + bytes.len()].copy_from_slice(bytes);
         out[4 + bytes.len()] = 0u8;
         Ok(())
     }
-
-    fn alignment() -> usize {
-        4
-    }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct Signature(pub String);
-
 impl DbusPrimitive for Signature {
     fn signature() -> u8 {
         b'g'
     }
 
     fn size(&self) -> usize {
-        self.0.as_bytes().len() + 2 // size and terminating null
+        self.as_str().len() + 2 // size and terminating null
     }
 
     fn serialize(&self, out: &mut [u8]) -> Result<()> {
-        let bytes = self.0.as_bytes();
-        out[0..1].copy_from_slice(&(bytes.len() as u32).to_le_bytes());
+        let bytes = self.as_str().as_bytes();
+        out[0] = bytes.len() as u8;
         out[1..1 + bytes.len()].copy_from_slice(bytes);
         out[1 + bytes.len()] = 0u8;
         Ok(())
     }
-
-    fn alignment() -> usize {
-        1
-    }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct ObjectPath(pub String);
-
 impl DbusPrimitive for ObjectPath {
     fn signature() -> u8 {
         b'o'
     }
 
     fn size(&self) -> usize {
-        self.0.as_bytes().len() + 5 // size and terminating null
+        self.as_str().len() + 5 // size and terminating null
     }
 
     fn serialize(&self, out: &mut [u8]) -> Result<()> {
-        let bytes = self.0.as_bytes();
+        let bytes = self.as_str().as_bytes();
         out[0..4].copy_from_slice(&(bytes.len() as u32).to_le_bytes());
         out[4..4 + bytes.len()].copy_from_slice(bytes);
         out[4 + bytes.len()] = 0u8;
         Ok(())
     }
+}
+
+impl DbusPrimitive for UnixFdIndex {
+    fn signature() -> u8 {
+        b'h'
+    }
 
-    fn alignment() -> usize {
+    fn size(&self) -> usize {
         4
     }
+
+    fn serialize(&self, out: &mut [u8]) -> Result<()> {
+        out.copy_from_slice(&self.0.to_le_bytes());
+        Ok(())
+    }
 }
@@ -1,16 +1,30 @@
 use crate::error::{Error, Result};
 use crate::message::Message;
+use crate::primitives::DbusPrimitive;
+use crate::signature::{is_basic, single_type_end};
+use crate::types::{newtype_signature, UnixFdIndex};
+#[cfg(unix)]
+use crate::types::{take_sent_fd, UNIX_FD_NAME};
+use crate::value::{take_empty_array_signature, EMPTY_ARRAY_NAME, VARIANT_NAME};
 
 use serde::{ser, Serialize};
+use std::borrow::Cow;
+use std::iter::once;
 use std::mem::take;
-use std::str::from_utf8;
+use std::rc::Rc;
 
 mod internal;
 mod message_builder;
 pub mod serializer_policy;
+pub(crate) mod target;
 
 use internal::*;
-use serializer_policy::{DefaultSerializerPolicy, SerializerPolicy, StructSerializationStyle};
+use serializer_policy::{
+    DefaultSerializerPolicy, EnumSerializationStyle, FieldContext, FieldNameCase,
+    FieldSerializationStyle, InteriorNulStyle, MapSerializationStyle, OptionSerializationStyle,
+    SeqSerializationStyle, SerializerPolicy, StructSerializationStyle,
+};
+use target::Target;
 
 /// This is the entry point to the serializer. The default
 /// serialization policy, [`DefaultSerializerPolicy`]
@@ -27,8 +41,21 @@ use serializer_policy::{DefaultSerializerPolicy, SerializerPolicy, StructSeriali
 /// To make these decisions on a struct-by-struct basis, you can
 /// create a custom implementation of the [`SerializerPolicy`] trait.
 ///
-/// Currently, all arrays are serialized as `av`. This is a known
-/// shortcoming and will be addressed in future versions.
+/// By default, the item signature of an array is inferred from its
+/// items, falling back to `av` if they disagree. The policy can instead
+/// supply an item signature for arrays, by the struct field they are
+/// the value of, with [`SerializerPolicy::query_seq`].
+/// Maps are handled similarly, with [`SerializerPolicy::query_map`].
+///
+/// Individual struct fields can be renamed, skipped, given a signature
+/// or serialized in a different struct style with
+/// [`SerializerPolicy::query_field`], without adding serde attributes.
+///
+/// If the signature is known in advance, for example from an interface's
+/// introspection data, [`serialize_with_signature`] can be used instead.
+///
+/// Messages are little-endian, unless [`SerializerPolicy::endianness`]
+/// says otherwise.
 ///
 /// [`DefaultSerializerPolicy`]: serializer_policy::DefaultSerializerPolicy
 /// [`StronglyTypedSerializerPolicy`]: serializer_policy::StronglyTypedSerializerPolicy
@@ -37,13 +64,10 @@ pub fn serialize_with_policy(
     value: impl Serialize,
     config: impl SerializerPolicy,
 ) -> Result<Message> {
-    let internal_ser = ReadySerializer::new();
-    let ser = Serializer {
-        internal_ser,
-        config,
-    };
+    let endianness = config.endianness();
+    let ser = Serializer::new(ReadySerializer::new(), config);
     let done_serializer = value.serialize(ser)?;
-    done_serializer.complete()
+    done_serializer.complete()?.into_endianness(endianness)
 }
 
 /// This is a convenience function that simply calls [`serialize_with_policy`]
@@ -54,9 +78,266 @@ pub fn serialize(value: impl Serialize) -> Result<Message> {
     serialize_with_policy(value, DefaultSerializerPolicy)
 }
 
+/// Serializes `value` to fit `signature`, such as one taken from an
+/// interface's introspection data. A signature with several complete
+/// types, such as `sa{sv}`, is treated as a struct of them, and so fits
+/// a tuple or a struct.
+///
+/// The signature decides what the serialization policy otherwise would:
+/// the item signatures of arrays and maps, whether each struct is
+/// serialized as a struct or as a dict (`a{sT}`), and where values are
+/// wrapped in variants. Integers are converted to the width in the
+/// signature if they are in range, and strings can be serialized as
+/// object paths (`o`) or signatures (`g`). Unit enum variants are
+/// serialized as their name for `s`, or as their index otherwise.
+///
+/// If the value does not fit, this fails with an error giving the
+/// index in the signature of the type that the value did not fit.
+/// Values inside variants are serialized with the default policy.
+pub fn serialize_with_signature(value: impl Serialize, signature: &str) -> Result<Message> {
+    serialize_with_signature_and_policy(value, signature, DefaultSerializerPolicy)
+}
+
+/// Like [`serialize_with_signature`], but with the given policy for the
+/// values inside variants, whose signature is not known in advance.
+pub fn serialize_with_signature_and_policy(
+    value: impl Serialize,
+    signature: &str,
+    config: impl SerializerPolicy,
+) -> Result<Message> {
+    let target = Target::new(signature.as_bytes())?;
+    let endianness = config.endianness();
+    let ser = Serializer::new(ReadySerializer::new(), config).with_target(Some(target));
+    let done_serializer = value.serialize(ser)?;
+    done_serializer.complete()?.into_endianness(endianness)
+}
+
 struct Serializer<T: SerializerPolicy> {
     internal_ser: ReadySerializer,
     config: T,
+    field: Option<FieldContext>,
+
+    // The names of the struct fields leading to this value
+    path: Rc<[&'static str]>,
+
+    // The struct style chosen for this value by `query_field`, if any
+    struct_style: Option<StructSerializationStyle>,
+
+    // Set for the index inside a `UnixFdIndex`, the only integer that
+    // fits `h`
+    unix_fd_index: bool,
+
+    // Set for the value of a field of a dict, which can be left out
+    omittable: bool,
+
+    // The signature the value must fit, if serializing with a signature
+    target: Option<Target>,
+}
+
+// What is left to do with a value once it is serialized, when it was
+// serialized to fit a target signature.
+enum Finish {
+    Plain,
+    Check(Target, usize),
+    Variant(VariantSerializer),
+}
+
+impl Finish {
+    fn finish(self, done: DoneSerializer) -> Result<DoneSerializer> {
+        match self {
+            Finish::Plain => Ok(done),
+            Finish::Check(target, sig_start) => {
+                target.check(&done, sig_start)?;
+                Ok(done)
+            }
+            Finish::Variant(var_ser) => var_ser.finish_variant(done),
+        }
+    }
+}
+
+// The target signatures of the fields of a struct or tuple
+enum FieldTargets {
+    Untargeted,
+    Struct(Target, std::vec::IntoIter<Target>),
+    Dict(Target),
+}
+
+impl FieldTargets {
+    fn next(&mut self) -> Result<Option<Target>> {
+        match self {
+            FieldTargets::Untargeted => Ok(None),
+            FieldTargets::Struct(target, fields) => match fields.next() {
+                Some(field) => Ok(Some(field)),
+                None => Err(target.fields_exhausted()),
+            },
+            FieldTargets::Dict(value) => Ok(Some(value.clone())),
+        }
+    }
+}
+
+impl<C: SerializerPolicy> Serializer<C> {
+    fn new(internal_ser: ReadySerializer, config: C) -> Self {
+        Self {
+            internal_ser,
+            config,
+            field: None,
+            path: Rc::new([]),
+            struct_style: None,
+            unix_fd_index: false,
+            omittable: false,
+            target: None,
+        }
+    }
+
+    // A serializer for the value of a struct field, as decided by the
+    // policy. A target signature from the enclosing value takes
+    // precedence over the one the policy chose.
+    fn for_field(
+        internal_ser: ReadySerializer,
+        config: C,
+        field: FieldContext,
+        parent_path: &[&'static str],
+        style: &FieldSerializationStyle,
+        target: Option<Target>,
+    ) -> Result<Self> {
+        let target = match (target, &style.signature) {
+            (None, Some(sig)) => Some(Target::new(sig.as_bytes())?),
+            (target, _) => target,
+        };
+        Ok(Self {
+            internal_ser,
+            config,
+            field: Some(field),
+            path: parent_path
+                .iter()
+                .copied()
+                .chain(once(field.field_name))
+                .collect(),
+            struct_style: style.struct_style,
+            unix_fd_index: false,
+            omittable: false,
+            target,
+        })
+    }
+
+    // A serializer for what stands in for this value, such as the
+    // contents of an `Option`, which is decided for as this value is,
+    // but without its target signature.
+    fn for_contents(&self, internal_ser: ReadySerializer) -> Self {
+        Self {
+            internal_ser,
+            config: self.config.clone(),
+            field: self.field,
+            path: self.path.clone(),
+            struct_style: self.struct_style,
+            unix_fd_index: false,
+            omittable: false,
+            target: None,
+        }
+    }
+
+    // Lets `OptionSerializationStyle::Omit` leave the value out.
+    fn omittable(mut self) -> Self {
+        self.omittable = true;
+        self
+    }
+
+    fn with_path(mut self, path: &Rc<[&'static str]>) -> Self {
+        self.path = path.clone();
+        self
+    }
+
+    fn with_target(mut self, target: Option<Target>) -> Self {
+        self.target = target;
+        self
+    }
+
+    // Starts a variant if the target signature calls for one, in which
+    // case the value inside it has no target signature.
+    fn prepare(self) -> (Finish, Self) {
+        let Serializer {
+            internal_ser,
+            config,
+            field,
+            path,
+            struct_style,
+            unix_fd_index,
+            omittable,
+            target,
+        } = self;
+        let (finish, internal_ser, target) = match target {
+            Some(target) if target.is_variant() => {
+                let (var_ser, internal_ser) = internal_ser.start_variant();
+                (Finish::Variant(var_ser), internal_ser, None)
+            }
+            Some(target) => {
+                let sig_start = internal_ser.signature_len();
+                (
+                    Finish::Check(target.clone(), sig_start),
+                    internal_ser,
+                    Some(target),
+                )
+            }
+            None => (Finish::Plain, internal_ser, None),
+        };
+        let ser = Serializer {
+            internal_ser,
+            config,
+            field,
+            path,
+            struct_style,
+            unix_fd_index,
+            omittable,
+            target,
+        };
+        (finish, ser)
+    }
+
+    // For values that are always serialized as variants, checks that
+    // the target signature, if any, allows for one.
+    fn into_variant_target(self) -> Result<ReadySerializer> {
+        match self.target {
+            Some(target) if !target.is_variant() => Err(target.mismatch(b"v")),
+            _ => Ok(self.internal_ser),
+        }
+    }
+
+    fn serialize_leaf(
+        self,
+        serialize: impl FnOnce(ReadySerializer, Option<&Target>) -> Result<DoneSerializer>,
+    ) -> Result<DoneSerializer> {
+        let (finish, ser) = self.prepare();
+        let done = serialize(ser.internal_ser, ser.target.as_ref())?;
+        finish.finish(done)
+    }
+
+    fn serialize_integer<T: DbusPrimitive>(
+        self,
+        val: i128,
+        primitive: T,
+    ) -> Result<DoneSerializer> {
+        self.serialize_leaf(|ser, target| match target {
+            Some(target) => target.serialize_integer(ser, val, T::signature()),
+            None => ser.serialize_primitive(&primitive),
+        })
+    }
+
+    // Applies the policy for strings containing nuls. Any nuls left in
+    // are rejected when the string is serialized.
+    fn sanitize<'a>(&self, val: &'a str) -> Cow<'a, str> {
+        if !val.contains('\0') {
+            return Cow::Borrowed(val);
+        }
+        match self.config.query_interior_nul(self.field.as_ref()) {
+            InteriorNulStyle::Error => Cow::Borrowed(val),
+            InteriorNulStyle::Strip => Cow::Owned(val.replace('\0', "")),
+        }
+    }
+
+    fn field_targets(target: &Target) -> Result<FieldTargets> {
+        let fields = target.struct_fields(b"(")?;
+        Ok(FieldTargets::Struct(target.clone(), fields.into_iter()))
+    }
 }
 
 impl<C: SerializerPolicy> ser::Serializer for Serializer<C> {
@@ -72,78 +353,157 @@ impl<C: SerializerPolicy> ser::Serializer for Serializer<C> {
     type SerializeStructVariant = SerializeStructVariant<C>;
 
     fn serialize_bool(self, val: bool) -> Result<DoneSerializer> {
-        self.internal_ser.serialize_primitive(&val)
+        self.serialize_leaf(|ser, _| ser.serialize_primitive(&val))
     }
 
     fn serialize_i8(self, val: i8) -> Result<DoneSerializer> {
-        self.internal_ser.serialize_primitive(&(val as i16))
+        self.serialize_integer(val.into(), val as i16)
     }
 
     fn serialize_i16(self, val: i16) -> Result<DoneSerializer> {
-        self.internal_ser.serialize_primitive(&val)
+        self.serialize_integer(val.into(), val)
     }
 
     fn serialize_i32(self, val: i32) -> Result<DoneSerializer> {
-        self.internal_ser.serialize_primitive(&val)
+        self.serialize_integer(val.into(), val)
     }
 
     fn serialize_i64(self, val: i64) -> Result<DoneSerializer> {
-        self.internal_ser.serialize_primitive(&val)
+        self.serialize_integer(val.into(), val)
     }
 
     fn serialize_u8(self, val: u8) -> Result<DoneSerializer> {
-        self.internal_ser.serialize_primitive(&val)
+        self.serialize_integer(val.into(), val)
     }
 
     fn serialize_u16(self, val: u16) -> Result<DoneSerializer> {
-        self.internal_ser.serialize_primitive(&val)
+        self.serialize_integer(val.into(), val)
     }
 
     fn serialize_u32(self, val: u32) -> Result<DoneSerializer> {
-        self.internal_ser.serialize_primitive(&val)
+        if self.unix_fd_index {
+            return self.serialize_leaf(|ser, target| match target {
+                Some(target) if target.signature() != b"h" => {
+                    target.serialize_integer(ser, val.into(), b'h')
+                }
+                _ => ser.serialize_primitive(&UnixFdIndex(val)),
+            });
+        }
+        self.serialize_integer(val.into(), val)
     }
 
     fn serialize_u64(self, val: u64) -> Result<DoneSerializer> {
-        self.internal_ser.serialize_primitive(&val)
+        self.serialize_integer(val.into(), val)
     }
 
     fn serialize_f32(self, val: f32) -> Result<DoneSerializer> {
-        self.internal_ser.serialize_primitive(&(val as f64))
+        self.serialize_leaf(|ser, _| ser.serialize_primitive(&(val as f64)))
     }
 
     fn serialize_f64(self, val: f64) -> Result<DoneSerializer> {
-        self.internal_ser.serialize_primitive(&val)
+        self.serialize_leaf(|ser, _| ser.serialize_primitive(&val))
     }
 
     fn serialize_char(self, val: char) -> Result<DoneSerializer> {
-        self.internal_ser.serialize_primitive(&(val as u32))
+        let val_str = val.to_string();
+        let val_str = self.sanitize(&val_str);
+        self.serialize_leaf(|ser, target| match target {
+            Some(target) if target.signature() == b"s" => ser.serialize_primitive(&&*val_str),
+            Some(target) => target.serialize_integer(ser, (val as u32).into(), b'u'),
+            None => ser.serialize_primitive(&(val as u32)),
+        })
     }
 
     fn serialize_str(self, val: &str) -> Result<DoneSerializer> {
-        self.internal_ser.serialize_primitive(&val)
+        let val = self.sanitize(val);
+        self.serialize_leaf(|ser, target| match target {
+            Some(target) => target.serialize_str(ser, &val),
+            None => ser.serialize_primitive(&&*val),
+        })
     }
 
-    // TODO: This seems like the wrong thing to do. Come back later?
     fn serialize_bytes(self, val: &[u8]) -> Result<DoneSerializer> {
-        self.internal_ser.serialize_primitive(&from_utf8(val)?)
+        self.serialize_leaf(|ser, _| Ok(ser.serialize_byte_array(val)))
     }
 
     fn serialize_none(self) -> Result<DoneSerializer> {
-        self.serialize_unit()
+        match self.config.query_option(self.field.as_ref()) {
+            // This serializes to nothing, which dicts leave out.
+            OptionSerializationStyle::Omit if self.omittable => self.serialize_unit(),
+            OptionSerializationStyle::Omit => Err(Error::NoneOutsideDict),
+            OptionSerializationStyle::Array => {
+                let (finish, ser) = self.prepare();
+                let done = match &ser.target {
+                    Some(target) => {
+                        let item_sig = target.array_item(b"a")?.signature().to_vec();
+                        ser.internal_ser.start_array(item_sig).finish_array()?
+                    }
+                    None => ser.internal_ser.start_inferred_array().finish_array()?,
+                };
+                finish.finish(done)
+            }
+            OptionSerializationStyle::Variant => {
+                if !self.config.allow_empty_variants() {
+                    return Err(Error::EmptyVariant);
+                }
+                let internal_ser = self.into_variant_target()?;
+                let (var_ser, _) = internal_ser.start_variant();
+                Ok(var_ser.finish_empty_variant())
+            }
+            OptionSerializationStyle::Default(default) => {
+                self.serialize_leaf(|ser, _| Ok(ser.serialize_copy(default.value())))
+            }
+        }
     }
 
     fn serialize_some<T>(self, val: &T) -> Result<DoneSerializer>
     where
         T: Serialize + ?Sized,
     {
-        val.serialize(self)
+        match self.config.query_option(self.field.as_ref()) {
+            OptionSerializationStyle::Omit | OptionSerializationStyle::Default(_) => {
+                val.serialize(self)
+            }
+            OptionSerializationStyle::Array => {
+                let (finish, mut ser) = self.prepare();
+                let internal_ser = take(&mut ser.internal_ser);
+                let done = match &ser.target {
+                    Some(target) => {
+                        let item_target = target.array_item(b"a")?;
+                        let item_sig = item_target.signature().to_vec();
+                        let (array_ser, item) = internal_ser.start_array(item_sig).start_item();
+                        let item =
+                            val.serialize(ser.for_contents(item).with_target(Some(item_target)))?;
+                        array_ser.finish_item(item)?.finish_array()?
+                    }
+                    None => {
+                        let (array_ser, item) = internal_ser.start_inferred_array().start_item();
+                        let item = val.serialize(ser.for_contents(item))?;
+                        array_ser.finish_item(item)?.finish_array()?
+                    }
+                };
+                finish.finish(done)
+            }
+            OptionSerializationStyle::Variant => {
+                let contents = self.for_contents(ReadySerializer::new());
+                let internal_ser = self.into_variant_target()?;
+                let (var_ser, item) = internal_ser.start_variant();
+                let item = val.serialize(Serializer {
+                    internal_ser: item,
+                    ..contents
+                })?;
+                var_ser.finish_variant(item)
+            }
+        }
     }
 
+    // This serializes to nothing, as an empty message body does, so
+    // that it is dropped from dicts whatever their target signature.
     fn serialize_unit(self) -> Result<DoneSerializer> {
-        let ser = self.internal_ser;
-        let ser = ser.start_struct();
-        let ser = ser.finish_struct();
-        Ok(ser)
+        match &self.target {
+            Some(target) if !self.omittable => Err(target.mismatch(b"")),
+            _ => Ok(self.internal_ser.finish_empty()),
+        }
     }
 
     fn serialize_unit_struct(self, _: &'static str) -> Result<DoneSerializer> {
@@ -152,55 +512,152 @@ impl<C: SerializerPolicy> ser::Serializer for Serializer<C> {
 
     fn serialize_unit_variant(
         self,
-        _: &'static str,
+        name: &'static str,
         variant_index: u32,
-        _: &'static str,
+        variant: &'static str,
     ) -> Result<DoneSerializer> {
-        variant_index.serialize(self)
+        let style = self.config.query_enum(name);
+        let tagged = matches!(
+            style,
+            EnumSerializationStyle::NameTaggedStruct | EnumSerializationStyle::IndexTaggedStruct
+        );
+        // A basic target signature asks for the bare index or name.
+        let bare = matches!(&self.target, Some(target) if is_basic(target.signature()[0]));
+        if tagged && !bare {
+            // Unit variants have no value, so an empty dict stands in for
+            // one, for all the variants to have the same signature.
+            let (finish, ser) = self.prepare();
+            let (outer, item) =
+                VariantOuter::start(ser.internal_ser, style, variant_index, variant)?;
+            let item = item.start_dict().finish_dict()?;
+            return finish.finish(outer.finish(item)?);
+        }
+        let by_name = style.tags_by_name();
+        self.serialize_leaf(|ser, target| match target {
+            Some(target) if target.signature() == b"s" => ser.serialize_primitive(&variant),
+            Some(target) => target.serialize_integer(ser, variant_index.into(), b'u'),
+            None if by_name => ser.serialize_primitive(&variant),
+            None => ser.serialize_primitive(&variant_index),
+        })
     }
 
-    fn serialize_newtype_struct<T>(self, _: &'static str, value: &T) -> Result<DoneSerializer>
+    // The types in `types` serialize their contents to fit their own
+    // type code, unless the target signature says otherwise, and Unix
+    // fds are serialized by index, with the message taking a duplicate.
+    // `Value`s mark their variants and empty arrays, whose signatures
+    // could not otherwise be known.
+    fn serialize_newtype_struct<T>(self, name: &'static str, value: &T) -> Result<DoneSerializer>
     where
         T: Serialize + ?Sized,
     {
-        value.serialize(self)
+        #[cfg(unix)]
+        if name == UNIX_FD_NAME {
+            if let Some((key, fd)) = take_sent_fd() {
+                return self.serialize_leaf(|ser, target| match target {
+                    Some(target) if target.signature() != b"h" => Err(target.mismatch(b"h")),
+                    _ => Ok(ser.serialize_unix_fd(key, fd)),
+                });
+            }
+        }
+        if name == EMPTY_ARRAY_NAME {
+            if let Some(item_sig) = take_empty_array_signature() {
+                let item_sig = item_sig.into_bytes();
+                if item_sig.is_empty() || single_type_end(&item_sig, 0)? != item_sig.len() {
+                    return Err(Error::NotSingleType(item_sig));
+                }
+                return self.serialize_leaf(|ser, _| ser.start_array(item_sig).finish_array());
+            }
+        }
+        if name == VARIANT_NAME {
+            let config = self.config.clone();
+            let path = self.path.clone();
+            let internal_ser = self.into_variant_target()?;
+            let (var_ser, item) = internal_ser.start_variant();
+            let item = value.serialize(Serializer::new(item, config).with_path(&path))?;
+            return var_ser.finish_variant(item);
+        }
+        match newtype_signature(name) {
+            Some(code) => {
+                let (finish, mut ser) = self.prepare();
+                if ser.target.is_none() {
+                    ser.target = Some(Target::new(&[code])?);
+                }
+                ser.unix_fd_index = code == b'h';
+                finish.finish(value.serialize(ser)?)
+            }
+            None => value.serialize(self),
+        }
     }
 
     fn serialize_newtype_variant<T>(
         self,
-        _: &'static str,
-        _: u32,
+        name: &'static str,
+        variant_index: u32,
         variant: &'static str,
         value: &T,
     ) -> Result<DoneSerializer>
     where
         T: Serialize + ?Sized,
     {
-        let ser = self.internal_ser;
-        let ser = ser.start_dict();
-
-        let (ser, item) = ser.start_item();
-        let item = value.serialize(Serializer {
-            internal_ser: item,
-            config: self.config,
-        })?;
-        let ser = ser.finish_item(variant, item)?;
-
-        let ser = ser.finish_dict();
-        Ok(ser)
+        let (finish, ser) = self.prepare();
+        let style = ser.config.query_enum(name);
+        let (outer, item) = VariantOuter::start(ser.internal_ser, style, variant_index, variant)?;
+        let item = value.serialize(Serializer::new(item, ser.config).with_path(&ser.path))?;
+        finish.finish(outer.finish(item)?)
     }
 
     fn serialize_seq(self, _: std::option::Option<usize>) -> Result<Self::SerializeSeq> {
+        let (finish, ser) = self.prepare();
+        let (internal_ser, item_target) = match &ser.target {
+            Some(target) => {
+                let item_target = target.array_item(b"a")?;
+                let item_sig = item_target.signature().to_vec();
+                (
+                    SerializeSeqInternal::Typed(ser.internal_ser.start_array(item_sig)),
+                    Some(item_target),
+                )
+            }
+            None => match ser.config.query_seq(ser.field.as_ref()) {
+                SeqSerializationStyle::Variant => (
+                    SerializeSeqInternal::Variant(ser.internal_ser.start_array(vec![b'v'])),
+                    None,
+                ),
+                // The items are serialized to fit the given signature,
+                // like those of a sequence with a target signature.
+                SeqSerializationStyle::Typed(item_sig) => {
+                    let item_target = Target::new(&item_sig)?;
+                    (
+                        SerializeSeqInternal::Typed(ser.internal_ser.start_array(item_sig)),
+                        Some(item_target),
+                    )
+                }
+                SeqSerializationStyle::Inferred => (
+                    SerializeSeqInternal::Inferred(ser.internal_ser.start_inferred_array()),
+                    None,
+                ),
+            },
+        };
         Ok(SerializeSeq {
-            internal_ser: Some(self.internal_ser.start_array(vec![b'v'])),
-            config: self.config,
+            internal_ser,
+            item_target,
+            finish,
+            config: ser.config,
+            path: ser.path,
         })
     }
 
     fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple> {
+        let (finish, ser) = self.prepare();
+        let fields = match &ser.target {
+            Some(target) => Self::field_targets(target)?,
+            None => FieldTargets::Untargeted,
+        };
         Ok(SerializeTuple {
-            internal_ser: Some(self.internal_ser.start_struct()),
-            config: self.config,
+            internal_ser: Some(ser.internal_ser.start_struct()),
+            fields,
+            finish,
+            config: ser.config,
+            path: ser.path,
         })
     }
 
@@ -209,77 +666,170 @@ impl<C: SerializerPolicy> ser::Serializer for Serializer<C> {
         _: &'static str,
         _: usize,
     ) -> Result<Self::SerializeTupleStruct> {
+        let (finish, ser) = self.prepare();
+        let fields = match &ser.target {
+            Some(target) => Self::field_targets(target)?,
+            None => FieldTargets::Untargeted,
+        };
         Ok(SerializeTupleStruct {
-            internal_ser: Some(self.internal_ser.start_struct()),
-            config: self.config,
+            internal_ser: Some(ser.internal_ser.start_struct()),
+            fields,
+            finish,
+            config: ser.config,
+            path: ser.path,
         })
     }
 
     fn serialize_tuple_variant(
         self,
-        _: &'static str,
-        _: u32,
+        name: &'static str,
+        variant_index: u32,
         variant: &'static str,
         _: usize,
     ) -> Result<Self::SerializeTupleVariant> {
-        let parent_ser = self.internal_ser.start_dict();
-        let (parent_ser, internal_ser) = parent_ser.start_item();
+        let (finish, ser) = self.prepare();
+        let style = ser.config.query_enum(name);
+        let (outer, internal_ser) =
+            VariantOuter::start(ser.internal_ser, style, variant_index, variant)?;
         let internal_ser = Some(internal_ser.start_struct());
         Ok(SerializeTupleVariant {
             internal_ser,
-            parent_ser,
-            name: variant,
-            config: self.config,
+            outer,
+            finish,
+            config: ser.config,
+            path: ser.path,
         })
     }
 
     fn serialize_map(self, _: std::option::Option<usize>) -> Result<Self::SerializeMap> {
+        let (finish, ser) = self.prepare();
+        let (style, key_target, value_target) = match &ser.target {
+            Some(target) => {
+                let (key, value) = target.dict_entry(b"a{")?;
+                let style = MapSerializationStyle::Typed(
+                    key.signature().to_vec(),
+                    value.signature().to_vec(),
+                );
+                (style, Some(key), Some(value))
+            }
+            None => match ser.config.query_map(ser.field.as_ref()) {
+                // The keys and values are serialized to fit the given
+                // signatures, like those of a map with a target signature.
+                MapSerializationStyle::Typed(key_sig, value_sig) => {
+                    let key = Target::new(&key_sig)?;
+                    let value = Target::new(&value_sig)?;
+                    let style = MapSerializationStyle::Typed(key_sig, value_sig);
+                    (style, Some(key), Some(value))
+                }
+                style => (style, None, None),
+            },
+        };
         Ok(SerializeMap {
-            internal_ser: Some(self.internal_ser.start_array(vec![b'{', b's', b'v', b'}'])),
-            inner_ser: None,
-            pending_ser: None,
-            config: self.config,
+            internal_ser: ser.internal_ser,
+            style,
+            entries: Vec::new(),
+            pending_key: None,
+            key_target,
+            value_target,
+            finish,
+            config: ser.config,
+            path: ser.path,
         })
     }
 
     fn serialize_struct(self, name: &'static str, _: usize) -> Result<Self::SerializeStruct> {
-        let internal_ser = match self.config.query_struct_name(name) {
-            StructSerializationStyle::Dict => {
-                SerializeStructInternal::Dict(self.internal_ser.start_dict())
+        let (finish, ser) = self.prepare();
+        let (internal_ser, fields) = match ser.target {
+            None => {
+                let style = match ser.struct_style {
+                    Some(style) => style,
+                    None => ser.config.query_struct_name(name),
+                };
+                let internal_ser = match style {
+                    StructSerializationStyle::Dict => {
+                        SerializeStructInternal::Dict(ser.internal_ser.start_dict())
+                    }
+                    StructSerializationStyle::StronglyTyped => {
+                        SerializeStructInternal::Struct(ser.internal_ser.start_struct())
+                    }
+                };
+                (internal_ser, FieldTargets::Untargeted)
             }
-            StructSerializationStyle::StronglyTyped => {
-                SerializeStructInternal::Struct(self.internal_ser.start_struct())
+            Some(target) if target.signature()[0] == b'(' => (
+                SerializeStructInternal::Struct(ser.internal_ser.start_struct()),
+                Self::field_targets(&target)?,
+            ),
+            Some(target) => {
+                let (key, value) = target.dict_entry(b"a{sv}")?;
+                if key.signature() != b"s" {
+                    return Err(key.mismatch(b"s"));
+                }
+                if value.is_variant() {
+                    // The dict wraps the values in variants itself.
+                    (
+                        SerializeStructInternal::Dict(ser.internal_ser.start_dict()),
+                        FieldTargets::Untargeted,
+                    )
+                } else {
+                    let internal_ser = ser.internal_ser.start_typed_dict(value.signature());
+                    (
+                        SerializeStructInternal::Dict(internal_ser),
+                        FieldTargets::Dict(value),
+                    )
+                }
             }
         };
 
         Ok(SerializeStruct {
             internal_ser,
-            config: self.config,
+            name,
+            case: ser.config.query_field_case(name),
+            fields,
+            finish,
+            config: ser.config,
+            path: ser.path,
         })
     }
 
     fn serialize_struct_variant(
         self,
-        _: &'static str,
-        _: u32,
+        name: &'static str,
+        variant_index: u32,
         variant: &'static str,
         _: usize,
     ) -> Result<Self::SerializeStructVariant> {
-        let parent_ser = self.internal_ser.start_dict();
-        let (parent_ser, internal_ser) = parent_ser.start_item();
+        let (finish, ser) = self.prepare();
+        let style = ser.config.query_enum(name);
+        let (outer, internal_ser) =
+            VariantOuter::start(ser.internal_ser, style, variant_index, variant)?;
         let internal_ser = Some(internal_ser.start_dict());
         Ok(SerializeStructVariant {
             internal_ser,
-            parent_ser,
+            outer,
             name: variant,
-            config: self.config,
+            case: ser.config.query_field_case(name),
+            finish,
+            config: ser.config,
+            path: ser.path,
         })
     }
 }
 
+#[derive(Default)]
+enum SerializeSeqInternal {
+    Variant(ReadyArraySerializer),
+    Typed(ReadyArraySerializer),
+    Inferred(InferredArraySerializer),
+    #[default]
+    Empty,
+}
+
 struct SerializeSeq<T: SerializerPolicy> {
-    internal_ser: Option<ReadyArraySerializer>,
+    internal_ser: SerializeSeqInternal,
+    item_target: Option<Target>,
+    finish: Finish,
     config: T,
+    path: Rc<[&'static str]>,
 }
 
 impl<C: SerializerPolicy> ser::SerializeSeq for SerializeSeq<C> {
@@ -290,27 +840,99 @@ impl<C: SerializerPolicy> ser::SerializeSeq for SerializeSeq<C> {
     where
         T: Serialize + ?Sized,
     {
-        let internal_ser = self.internal_ser.take().expect("programming error");
-        let (internal_ser, item) = internal_ser.start_item();
-        let (item, sub_item) = item.start_variant();
-        let sub_item = value.serialize(Serializer {
-            internal_ser: sub_item,
-            config: self.config.clone(),
-        })?;
-        let item = item.finish_variant(sub_item);
-        let internal_ser = internal_ser.finish_item(item)?;
-        self.internal_ser = Some(internal_ser);
+        let internal_ser = take(&mut self.internal_ser);
+        match internal_ser {
+            SerializeSeqInternal::Variant(internal_ser) => {
+                let (internal_ser, item) = internal_ser.start_item();
+                let (item, sub_item) = item.start_variant();
+                let sub_item = value.serialize(
+                    Serializer::new(sub_item, self.config.clone()).with_path(&self.path),
+                )?;
+                let item = item.finish_variant(sub_item)?;
+                let internal_ser = internal_ser.finish_item(item)?;
+                self.internal_ser = SerializeSeqInternal::Variant(internal_ser);
+            }
+            SerializeSeqInternal::Typed(internal_ser) => {
+                let (internal_ser, item) = internal_ser.start_item();
+                let item = value.serialize(
+                    Serializer::new(item, self.config.clone())
+                        .with_path(&self.path)
+                        .with_target(self.item_target.clone()),
+                )?;
+                let internal_ser = internal_ser.finish_item(item)?;
+                self.internal_ser = SerializeSeqInternal::Typed(internal_ser);
+            }
+            SerializeSeqInternal::Inferred(internal_ser) => {
+                let (internal_ser, item) = internal_ser.start_item();
+                let item = value
+                    .serialize(Serializer::new(item, self.config.clone()).with_path(&self.path))?;
+                let internal_ser = internal_ser.finish_item(item)?;
+                self.internal_ser = SerializeSeqInternal::Inferred(internal_ser);
+            }
+            SerializeSeqInternal::Empty => {
+                unreachable!() // Because it's always put back at the end
+            }
+        }
         Ok(())
     }
 
     fn end(self) -> Result<DoneSerializer> {
-        Ok(self.internal_ser.expect("programming error").finish_array())
+        let done = match self.internal_ser {
+            SerializeSeqInternal::Variant(internal_ser)
+            | SerializeSeqInternal::Typed(internal_ser) => internal_ser.finish_array()?,
+            SerializeSeqInternal::Inferred(internal_ser) => internal_ser.finish_array()?,
+            SerializeSeqInternal::Empty => {
+                unreachable!() // Never left in this state except in panic
+            }
+        };
+        self.finish.finish(done)
+    }
+}
+
+// The item signature that all the items agree on, if any. Untyped empty
+// arrays agree with any array signature.
+fn infer_item_signature<'a>(
+    items: impl Iterator<Item = &'a DoneSerializer> + Clone,
+) -> Option<Vec<u8>> {
+    let has_untyped = items.clone().any(|item| item.is_untyped_empty());
+    let mut typed_sigs = items
+        .clone()
+        .filter(|item| !item.is_untyped_empty())
+        .map(|item| item.signature());
+    match typed_sigs.next() {
+        None => items.clone().next().map(|item| item.signature().to_vec()),
+        Some(first) => {
+            if typed_sigs.all(|sig| sig == first) && (first[0] == b'a' || !has_untyped) {
+                Some(first.to_vec())
+            } else {
+                None
+            }
+        }
+    }
+}
+
+// Converts a separately serialized item to the given signature, where
+// that is possible without re-serializing it. If it is not possible,
+// the item is returned as is, to be rejected when it is added.
+fn coerce_item(item: DoneSerializer, item_sig: &[u8]) -> Result<DoneSerializer> {
+    if item.signature() == item_sig {
+        Ok(item)
+    } else if item_sig == b"v" {
+        item.into_variant()
+    } else if item.is_untyped_empty() && item_sig[0] == b'a' {
+        let item = ReadySerializer::new().start_array(item_sig[1..].to_vec());
+        item.finish_array()
+    } else {
+        Ok(item)
     }
 }
 
 struct SerializeTuple<T: SerializerPolicy> {
     internal_ser: Option<ReadyStructSerializer>,
+    fields: FieldTargets,
+    finish: Finish,
     config: T,
+    path: Rc<[&'static str]>,
 }
 
 impl<C: SerializerPolicy> ser::SerializeTuple for SerializeTuple<C> {
@@ -321,28 +943,31 @@ impl<C: SerializerPolicy> ser::SerializeTuple for SerializeTuple<C> {
     where
         T: Serialize + ?Sized,
     {
+        let target = self.fields.next()?;
         let internal_ser = self.internal_ser.take().expect("programming error");
         let (internal_ser, item) = internal_ser.start_item();
-        let item = value.serialize(Serializer {
-            internal_ser: item,
-            config: self.config.clone(),
-        })?;
+        let item = value.serialize(
+            Serializer::new(item, self.config.clone())
+                .with_path(&self.path)
+                .with_target(target),
+        )?;
         let internal_ser = internal_ser.finish_item(item);
         self.internal_ser = Some(internal_ser);
         Ok(())
     }
 
     fn end(self) -> Result<DoneSerializer> {
-        Ok(self
-            .internal_ser
-            .expect("programming error")
-            .finish_struct())
+        let internal_ser = self.internal_ser.expect("programming error");
+        self.finish.finish(internal_ser.finish_struct())
     }
 }
 
 struct SerializeTupleStruct<T: SerializerPolicy> {
     internal_ser: Option<ReadyStructSerializer>,
+    fields: FieldTargets,
+    finish: Finish,
     config: T,
+    path: Rc<[&'static str]>,
 }
 
 impl<C: SerializerPolicy> ser::SerializeTupleStruct for SerializeTupleStruct<C> {
@@ -353,30 +978,78 @@ impl<C: SerializerPolicy> ser::SerializeTupleStruct for SerializeTupleStruct<C>
     where
         T: Serialize + ?Sized,
     {
+        let target = self.fields.next()?;
         let internal_ser = self.internal_ser.take().expect("programming error");
         let (internal_ser, item) = internal_ser.start_item();
-        let item = value.serialize(Serializer {
-            internal_ser: item,
-            config: self.config.clone(),
-        })?;
+        let item = value.serialize(
+            Serializer::new(item, self.config.clone())
+                .with_path(&self.path)
+                .with_target(target),
+        )?;
         let internal_ser = internal_ser.finish_item(item);
         self.internal_ser = Some(internal_ser);
         Ok(())
     }
 
     fn end(self) -> Result<DoneSerializer> {
-        Ok(self
-            .internal_ser
-            .expect("programming error")
-            .finish_struct())
+        let internal_ser = self.internal_ser.expect("programming error");
+        self.finish.finish(internal_ser.finish_struct())
+    }
+}
+
+// What surrounds the value of a newtype, tuple or struct variant, and
+// identifies the variant, according to the enum's serialization style.
+enum VariantOuter {
+    Dict(PendingDictSerializer, &'static str),
+    TaggedStruct(PendingStructSerializer, VariantSerializer),
+}
+
+impl VariantOuter {
+    fn start(
+        internal_ser: ReadySerializer,
+        style: EnumSerializationStyle,
+        variant_index: u32,
+        variant: &'static str,
+    ) -> Result<(Self, ReadySerializer)> {
+        match style {
+            EnumSerializationStyle::Index | EnumSerializationStyle::Name => {
+                let (dict_ser, value) = internal_ser.start_dict().start_item();
+                Ok((VariantOuter::Dict(dict_ser, variant), value))
+            }
+            EnumSerializationStyle::NameTaggedStruct
+            | EnumSerializationStyle::IndexTaggedStruct => {
+                let (struct_ser, tag) = internal_ser.start_struct().start_item();
+                let tag = if style.tags_by_name() {
+                    tag.serialize_primitive(&variant)?
+                } else {
+                    tag.serialize_primitive(&variant_index)?
+                };
+                let (struct_ser, value) = struct_ser.finish_item(tag).start_item();
+                let (var_ser, value) = value.start_variant();
+                Ok((VariantOuter::TaggedStruct(struct_ser, var_ser), value))
+            }
+        }
+    }
+
+    fn finish(self, value: DoneSerializer) -> Result<DoneSerializer> {
+        match self {
+            VariantOuter::Dict(dict_ser, variant) => {
+                dict_ser.finish_item(variant, value)?.finish_dict()
+            }
+            VariantOuter::TaggedStruct(struct_ser, var_ser) => {
+                let value = var_ser.finish_variant(value)?;
+                Ok(struct_ser.finish_item(value).finish_struct())
+            }
+        }
     }
 }
 
 struct SerializeTupleVariant<T: SerializerPolicy> {
-    parent_ser: PendingDictSerializer,
+    outer: VariantOuter,
     internal_ser: Option<ReadyStructSerializer>,
-    name: &'static str,
+    finish: Finish,
     config: T,
+    path: Rc<[&'static str]>,
 }
 
 impl<C: SerializerPolicy> ser::SerializeTupleVariant for SerializeTupleVariant<C> {
@@ -389,10 +1062,8 @@ impl<C: SerializerPolicy> ser::SerializeTupleVariant for SerializeTupleVariant<C
     {
         let internal_ser = self.internal_ser.take().expect("programming error");
         let (internal_ser, item) = internal_ser.start_item();
-        let item = value.serialize(Serializer {
-            internal_ser: item,
-            config: self.config.clone(),
-        })?;
+        let item =
+            value.serialize(Serializer::new(item, self.config.clone()).with_path(&self.path))?;
         let internal_ser = internal_ser.finish_item(item);
         self.internal_ser = Some(internal_ser);
         Ok(())
@@ -400,24 +1071,28 @@ impl<C: SerializerPolicy> ser::SerializeTupleVariant for SerializeTupleVariant<C
 
     fn end(self) -> Result<DoneSerializer> {
         let SerializeTupleVariant {
-            parent_ser,
+            outer,
             internal_ser,
-            name,
+            finish,
             config: _,
+            path: _,
         } = self;
         let internal_ser = internal_ser.expect("programming error");
         let internal_ser = internal_ser.finish_struct();
-        let parent_ser = parent_ser.finish_optional_item(name, internal_ser)?;
-        let parent_ser = parent_ser.finish_dict();
-        Ok(parent_ser)
+        finish.finish(outer.finish(internal_ser)?)
     }
 }
 
 struct SerializeMap<T: SerializerPolicy> {
-    internal_ser: Option<ReadyArraySerializer>,
-    pending_ser: Option<PendingArraySerializer>,
-    inner_ser: Option<ReadyStructSerializer>,
+    internal_ser: ReadySerializer,
+    style: MapSerializationStyle,
+    entries: Vec<(DoneSerializer, DoneSerializer)>,
+    pending_key: Option<DoneSerializer>,
+    key_target: Option<Target>,
+    value_target: Option<Target>,
+    finish: Finish,
     config: T,
+    path: Rc<[&'static str]>,
 }
 
 impl<C: SerializerPolicy> ser::SerializeMap for SerializeMap<C> {
@@ -428,17 +1103,10 @@ impl<C: SerializerPolicy> ser::SerializeMap for SerializeMap<C> {
     where
         T: Serialize + ?Sized,
     {
-        let internal_ser = self.internal_ser.take().expect("programming error");
-        let (pending_ser, item) = internal_ser.start_item();
-        let item = item.start_kv_pair();
-        let (item, key_item) = item.start_item();
-        let key_item = value.serialize(Serializer {
-            internal_ser: key_item,
-            config: self.config.clone(),
-        })?;
-        let item = item.finish_item(key_item);
-        self.pending_ser = Some(pending_ser);
-        self.inner_ser = Some(item);
+        let ser = Serializer::new(ReadySerializer::new(), self.config.clone())
+            .with_path(&self.path)
+            .with_target(self.key_target.clone());
+        self.pending_key = Some(value.serialize(ser)?);
         Ok(())
     }
 
@@ -446,43 +1114,91 @@ impl<C: SerializerPolicy> ser::SerializeMap for SerializeMap<C> {
     where
         T: Serialize + ?Sized,
     {
-        let pending_ser = self.pending_ser.take().expect("programming error");
-        let inner_ser = self.inner_ser.take().expect("programming error");
-        let (inner_ser, inner_inner_ser) = inner_ser.start_item();
-        let (inner_inner_ser, inner_inner_inner_ser) = inner_inner_ser.start_variant();
-        let inner_inner_inner_ser = value.serialize(Serializer {
-            internal_ser: inner_inner_inner_ser,
-            config: self.config.clone(),
-        })?;
-        let inner_inner_ser = inner_inner_ser.finish_variant(inner_inner_inner_ser);
-        let inner_ser = inner_ser.finish_item(inner_inner_ser);
-        let inner_ser = inner_ser.finish_kv_pair();
-        let internal_ser = pending_ser.finish_item(inner_ser)?;
-        self.internal_ser = Some(internal_ser);
+        let key = self.pending_key.take().expect("programming error");
+        let ser = Serializer::new(ReadySerializer::new(), self.config.clone())
+            .with_path(&self.path)
+            .with_target(self.value_target.clone());
+        let value = value.serialize(ser)?;
+        self.entries.push((key, value));
         Ok(())
     }
 
     fn end(self) -> Result<DoneSerializer> {
-        let internal_ser = self.internal_ser.expect("programming error");
-        Ok(internal_ser.finish_array())
+        let SerializeMap {
+            internal_ser,
+            style,
+            entries,
+            finish,
+            ..
+        } = self;
+
+        // With no entries, the signature is only a guess, which sibling
+        // maps can override, as with empty sequences.
+        let untyped = entries.is_empty() && !matches!(style, MapSerializationStyle::Typed(..));
+        let (key_sig, value_sig) = match style {
+            MapSerializationStyle::Typed(key_sig, value_sig) => (key_sig, value_sig),
+            MapSerializationStyle::Variant => (infer_key_signature(&entries)?, vec![b'v']),
+            MapSerializationStyle::Inferred => {
+                let key_sig = infer_key_signature(&entries)?;
+                let values = entries.iter().map(|(_, value)| value);
+                let value_sig = infer_item_signature(values).unwrap_or_else(|| vec![b'v']);
+                (key_sig, value_sig)
+            }
+        };
+
+        let mut item_sig = vec![b'{'];
+        item_sig.extend_from_slice(&key_sig);
+        item_sig.extend_from_slice(&value_sig);
+        item_sig.push(b'}');
+
+        let mut internal_ser = internal_ser.start_array(item_sig);
+        for (key, value) in entries {
+            let item = ReadySerializer::new().start_kv_pair();
+            let item = item.append_item(key);
+            let item = item.append_item(coerce_item(value, &value_sig)?);
+            internal_ser = internal_ser.append_item(item.finish_kv_pair()?)?;
+        }
+        let done = internal_ser.finish_array()?;
+        finish.finish(if untyped {
+            done.mark_untyped_empty()
+        } else {
+            done
+        })
+    }
+}
+
+// Unlike values, keys cannot fall back to variants, so they must agree.
+// The keys of empty maps are assumed to be strings.
+fn infer_key_signature(entries: &[(DoneSerializer, DoneSerializer)]) -> Result<Vec<u8>> {
+    let mut keys = entries.iter().map(|(key, _)| key.signature());
+    let first = match keys.next() {
+        Some(first) => first,
+        None => return Ok(vec![b's']),
+    };
+    for key in keys {
+        if key != first {
+            return Err(Error::MismatchSignature(first.to_vec(), key.to_vec()));
+        }
     }
+    Ok(first.to_vec())
 }
 
+#[derive(Default)]
 enum SerializeStructInternal {
     Dict(ReadyDictSerializer),
     Struct(ReadyStructSerializer),
+    #[default]
     Empty,
 }
 
-impl Default for SerializeStructInternal {
-    fn default() -> Self {
-        Self::Empty
-    }
-}
-
 struct SerializeStruct<T: SerializerPolicy> {
     internal_ser: SerializeStructInternal,
+    name: &'static str,
+    case: FieldNameCase,
+    fields: FieldTargets,
+    finish: Finish,
     config: T,
+    path: Rc<[&'static str]>,
 }
 
 impl<C: SerializerPolicy> ser::SerializeStruct for SerializeStruct<C> {
@@ -493,23 +1209,47 @@ impl<C: SerializerPolicy> ser::SerializeStruct for SerializeStruct<C> {
     where
         T: Serialize + ?Sized,
     {
+        let field = FieldContext {
+            struct_name: self.name,
+            field_name: name,
+        };
+        let style = self.config.query_field(&field, &self.path);
+        if style.skip {
+            return Ok(());
+        }
+        let target = self.fields.next()?;
         let internal_ser = take(&mut self.internal_ser);
         match internal_ser {
             SerializeStructInternal::Dict(internal_ser) => {
                 let (internal_ser, item) = internal_ser.start_item();
-                let item = value.serialize(Serializer {
-                    internal_ser: item,
-                    config: self.config.clone(),
-                })?;
-                let internal_ser = internal_ser.finish_optional_item(name, item)?;
+                let item = value.serialize(
+                    Serializer::for_field(
+                        item,
+                        self.config.clone(),
+                        field,
+                        &self.path,
+                        &style,
+                        target,
+                    )?
+                    .omittable(),
+                )?;
+                let key = match style.rename {
+                    Some(key) => key.into(),
+                    None => self.case.convert(name),
+                };
+                let internal_ser = internal_ser.finish_optional_item(&key, item)?;
                 self.internal_ser = SerializeStructInternal::Dict(internal_ser);
             }
             SerializeStructInternal::Struct(internal_ser) => {
                 let (internal_ser, item) = internal_ser.start_item();
-                let item = value.serialize(Serializer {
-                    internal_ser: item,
-                    config: self.config.clone(),
-                })?;
+                let item = value.serialize(Serializer::for_field(
+                    item,
+                    self.config.clone(),
+                    field,
+                    &self.path,
+                    &style,
+                    target,
+                )?)?;
                 let internal_ser = internal_ser.finish_item(item);
                 self.internal_ser = SerializeStructInternal::Struct(internal_ser);
             }
@@ -521,21 +1261,25 @@ impl<C: SerializerPolicy> ser::SerializeStruct for SerializeStruct<C> {
     }
 
     fn end(self) -> Result<DoneSerializer> {
-        match self.internal_ser {
-            SerializeStructInternal::Dict(internal_ser) => Ok(internal_ser.finish_dict()),
-            SerializeStructInternal::Struct(internal_ser) => Ok(internal_ser.finish_struct()),
+        let done = match self.internal_ser {
+            SerializeStructInternal::Dict(internal_ser) => internal_ser.finish_dict()?,
+            SerializeStructInternal::Struct(internal_ser) => internal_ser.finish_struct(),
             SerializeStructInternal::Empty => {
                 unreachable!() // Never left in this state except in panic
             }
-        }
+        };
+        self.finish.finish(done)
     }
 }
 
 struct SerializeStructVariant<T: SerializerPolicy> {
     internal_ser: Option<ReadyDictSerializer>,
-    parent_ser: PendingDictSerializer,
+    outer: VariantOuter,
     name: &'static str,
+    case: FieldNameCase,
+    finish: Finish,
     config: T,
+    path: Rc<[&'static str]>,
 }
 
 impl<C: SerializerPolicy> ser::SerializeStructVariant for SerializeStructVariant<C> {
@@ -546,40 +1290,62 @@ impl<C: SerializerPolicy> ser::SerializeStructVariant for SerializeStructVariant
     where
         T: Serialize + ?Sized,
     {
+        let field = FieldContext {
+            struct_name: self.name,
+            field_name: name,
+        };
+        let style = self.config.query_field(&field, &self.path);
+        if style.skip {
+            return Ok(());
+        }
         let internal_ser = self.internal_ser.take().expect("programming error");
         let (internal_ser, item) = internal_ser.start_item();
-        let item = value.serialize(Serializer {
-            internal_ser: item,
-            config: self.config.clone(),
-        })?;
-        let internal_ser = internal_ser.finish_optional_item(name, item)?;
+        let item = value.serialize(
+            Serializer::for_field(item, self.config.clone(), field, &self.path, &style, None)?
+                .omittable(),
+        )?;
+        let key = match style.rename {
+            Some(key) => key.into(),
+            None => self.case.convert(name),
+        };
+        let internal_ser = internal_ser.finish_optional_item(&key, item)?;
         self.internal_ser = Some(internal_ser);
         Ok(())
     }
 
     fn end(self) -> Result<DoneSerializer> {
         let SerializeStructVariant {
-            parent_ser,
+            outer,
             internal_ser,
-            name,
+            name: _,
+            case: _,
+            finish,
             config: _,
+            path: _,
         } = self;
         let internal_ser = internal_ser.expect("programming error");
-        let internal_ser = internal_ser.finish_dict();
-        let parent_ser = parent_ser.finish_optional_item(name, internal_ser)?;
-        let parent_ser = parent_ser.finish_dict();
-        Ok(parent_ser)
+        let internal_ser = internal_ser.finish_dict()?;
+        finish.finish(outer.finish(internal_ser)?)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::error::Result;
-    use crate::message::Message;
+    use crate::error::{Error, Result};
+    use crate::message::{validate, Endianness, Message};
     use crate::ser::serialize;
     use crate::ser::serialize_with_policy;
-    use crate::ser::serializer_policy::StronglyTypedSerializerPolicy;
+    use crate::ser::serialize_with_signature;
+    use crate::ser::serialize_with_signature_and_policy;
+    use crate::ser::serializer_policy::{
+        EnumSerializationStyle, FieldContext, FieldSerializationStyle, InteriorNulStyle,
+        MapSerializationStyle, OptionSerializationStyle, SeqSerializationStyle, SerializerPolicy,
+        StronglyTypedSerializerPolicy, StructSerializationStyle,
+    };
+    use crate::types::{ObjectPath, UnixFdIndex};
+    use crate::value::Value;
     use serde::Serialize;
+    use std::collections::BTreeMap;
     use test_log::test;
 
     #[test]
@@ -589,6 +1355,8 @@ mod tests {
         let correct_message = Message {
             data: vec![37, 0, 0, 0],
             signature: "i".as_bytes().to_vec(),
+            fds: vec![],
+            endianness: Endianness::Little,
         };
         assert_eq!(
             correct_message, message,
@@ -608,6 +1376,8 @@ mod tests {
                 0u8, 0u8, 0u8, 0u8, 0u8, 154u8, 153u8, 153u8, 153u8, 153u8, 153u8, 32u8, 64u8,
             ],
             signature: "(sd(sd))".as_bytes().to_vec(),
+            fds: vec![],
+            endianness: Endianness::Little,
         };
         assert_eq!(
             correct_message, message,
@@ -634,6 +1404,8 @@ mod tests {
                 0u8, 0u8, 0u8, 0u8, 0u8, 154u8, 153u8, 153u8, 153u8, 153u8, 153u8, 32u8, 64u8,
             ],
             signature: "(sd(sd))".as_bytes().to_vec(),
+            fds: vec![],
+            endianness: Endianness::Little,
         };
         assert_eq!(
             correct_message, message,
@@ -687,6 +1459,8 @@ mod tests {
                 154u8, 153u8, 153u8, 153u8, 153u8, 153u8, 32u8, 64u8, // double 8.3
             ],
             signature: "a{sv}".as_bytes().to_vec(),
+            fds: vec![],
+            endianness: Endianness::Little,
         };
         assert_eq!(
             correct_message, message,
@@ -730,6 +1504,8 @@ mod tests {
                 154, 153, 153, 153, 153, 153, 201, 63, // 0.2
             ],
             signature: "a{sv}".as_bytes().to_vec(),
+            fds: vec![],
+            endianness: Endianness::Little,
         };
         assert_eq!(
             correct_message, message,
@@ -821,4 +1597,1109 @@ mod tests {
         assert_ne!(mesg_with, mesg_without);
         Ok(())
     }
+
+    #[derive(Clone, Debug)]
+    struct TypedArrayPolicy;
+
+    impl SerializerPolicy for TypedArrayPolicy {
+        fn query_struct_name(&self, _: &str) -> StructSerializationStyle {
+            StructSerializationStyle::StronglyTyped
+        }
+
+        fn query_seq(&self, field: Option<&FieldContext>) -> SeqSerializationStyle {
+            match field {
+                Some(FieldContext {
+                    struct_name: "WithArrays",
+                    field_name: "ints",
+                }) => SeqSerializationStyle::Typed(b"i".to_vec()),
+                Some(FieldContext {
+                    struct_name: "WithArrays",
+                    field_name: "pairs",
+                }) => SeqSerializationStyle::Typed(b"(ss)".to_vec()),
+                _ => SeqSerializationStyle::Variant,
+            }
+        }
+    }
+
+    #[derive(Clone, Debug, Serialize)]
+    struct WithArrays {
+        ints: Vec<i32>,
+        pairs: Vec<(String, String)>,
+        others: Vec<i32>,
+    }
+
+    #[test]
+    fn typed_arrays() -> Result<()> {
+        let data = WithArrays {
+            ints: vec![1, 2],
+            pairs: vec![("a".to_owned(), "b".to_owned())],
+            others: vec![],
+        };
+        let message = serialize_with_policy(data, TypedArrayPolicy)?;
+        let correct_message = Message {
+            data: vec![
+                8, 0, 0, 0, // 8 bytes of array
+                1, 0, 0, 0, // 1
+                2, 0, 0, 0, // 2
+                14, 0, 0, 0, // 14 bytes of array
+                1, 0, 0, 0, // 1 byte string
+                97, 0, // "a"
+                0, 0, // padding(4)
+                1, 0, 0, 0, // 1 byte string
+                98, 0, // "b"
+                0, 0, // padding(4)
+                0, 0, 0, 0, // empty array
+            ],
+            signature: "(aia(ss)av)".as_bytes().to_vec(),
+            fds: vec![],
+            endianness: Endianness::Little,
+        };
+        assert_eq!(
+            correct_message, message,
+            "typed arrays serialized incorrectly"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn typed_array_items_fit() -> Result<()> {
+        #[derive(Clone, Debug)]
+        struct FitPolicy;
+
+        impl SerializerPolicy for FitPolicy {
+            fn query_struct_name(&self, _: &str) -> StructSerializationStyle {
+                StructSerializationStyle::StronglyTyped
+            }
+
+            fn query_seq(&self, field: Option<&FieldContext>) -> SeqSerializationStyle {
+                match field.map(|field| field.field_name) {
+                    Some("lists") => SeqSerializationStyle::Typed(b"ay".to_vec()),
+                    Some("values") => SeqSerializationStyle::Typed(b"v".to_vec()),
+                    _ => SeqSerializationStyle::Inferred,
+                }
+            }
+        }
+
+        #[derive(Clone, Debug, Serialize)]
+        struct Lists {
+            lists: Vec<Vec<u8>>,
+            values: Vec<u32>,
+        }
+
+        // Empty items and items that need wrapping in variants fit the
+        // item signature, as they would with a target signature.
+        let data = Lists {
+            lists: vec![vec![], vec![1, 2]],
+            values: vec![3],
+        };
+        let message = serialize_with_policy(&data, FitPolicy)?;
+        let correct_message = serialize_with_signature(&data, "(aayav)")?;
+        assert_eq!(
+            correct_message, message,
+            "typed array items serialized incorrectly"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn typed_array_empty() -> Result<()> {
+        let data = WithArrays {
+            ints: vec![],
+            pairs: vec![],
+            others: vec![],
+        };
+        let message = serialize_with_policy(data, TypedArrayPolicy)?;
+        let correct_message = Message {
+            data: vec![
+                0, 0, 0, 0, // empty array of i
+                0, 0, 0, 0, // empty array of (ss)
+                0, 0, 0, 0, // empty array of v
+            ],
+            signature: "(aia(ss)av)".as_bytes().to_vec(),
+            fds: vec![],
+            endianness: Endianness::Little,
+        };
+        assert_eq!(
+            correct_message, message,
+            "empty typed arrays serialized incorrectly"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn typed_array_mismatch() {
+        #[derive(Clone, Debug, Serialize)]
+        struct WithArrays {
+            ints: Vec<String>,
+        }
+
+        let data = WithArrays {
+            ints: vec!["1".to_owned()],
+        };
+        let res = serialize_with_policy(data, TypedArrayPolicy);
+        assert_eq!(
+            res,
+            Err(Error::TargetSignatureMismatch(
+                b"i".to_vec(),
+                0,
+                b"s".to_vec()
+            ))
+        );
+    }
+
+    #[test]
+    fn inferred_array() -> Result<()> {
+        let message = serialize(vec![1, 3, 5])?;
+        let correct_message = Message {
+            data: vec![
+                12, 0, 0, 0, // 12 bytes of array
+                1, 0, 0, 0, // 1
+                3, 0, 0, 0, // 3
+                5, 0, 0, 0, // 5
+            ],
+            signature: "ai".as_bytes().to_vec(),
+            fds: vec![],
+            endianness: Endianness::Little,
+        };
+        assert_eq!(
+            correct_message, message,
+            "inferred array serialized incorrectly"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn inferred_array_fallback() -> Result<()> {
+        #[derive(Clone, Debug, Serialize)]
+        #[serde(untagged)]
+        enum IntOrString {
+            Int(i32),
+            String(String),
+        }
+
+        let data = vec![IntOrString::Int(1), IntOrString::String("a".to_owned())];
+        let message = serialize(data)?;
+        let correct_message = Message {
+            data: vec![
+                18, 0, 0, 0, // 18 bytes of array
+                1, 105, 0, // signature "i"
+                0, // padding(4)
+                1, 0, 0, 0, // 1
+                1, 115, 0, // signature "s"
+                0, // padding(4)
+                1, 0, 0, 0, // 1 byte string
+                97, 0, // "a"
+            ],
+            signature: "av".as_bytes().to_vec(),
+            fds: vec![],
+            endianness: Endianness::Little,
+        };
+        assert_eq!(
+            correct_message, message,
+            "heterogeneous array serialized incorrectly"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn inferred_array_nested() -> Result<()> {
+        let message = serialize(vec![vec![], vec![(1u8, "a")]])?;
+        let correct_message = Message {
+            data: vec![
+                22, 0, 0, 0, // 22 bytes of array
+                0, 0, 0, 0, // empty array
+                10, 0, 0, 0, // 10 bytes of array
+                0, 0, 0, 0, // padding(8) for (ys)
+                1, // 1
+                0, 0, 0, // padding(4)
+                1, 0, 0, 0, // 1 byte string
+                97, 0, // "a"
+            ],
+            signature: "aa(ys)".as_bytes().to_vec(),
+            fds: vec![],
+            endianness: Endianness::Little,
+        };
+        assert_eq!(
+            correct_message, message,
+            "nested arrays serialized incorrectly"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn inferred_array_empty() -> Result<()> {
+        let data: Vec<i32> = vec![];
+        let message = serialize(data)?;
+        let correct_message = Message {
+            data: vec![0, 0, 0, 0],
+            signature: "av".as_bytes().to_vec(),
+            fds: vec![],
+            endianness: Endianness::Little,
+        };
+        assert_eq!(
+            correct_message, message,
+            "empty array serialized incorrectly"
+        );
+
+        let data: Vec<Vec<i32>> = vec![vec![], vec![]];
+        let message = serialize(data)?;
+        assert_eq!(message.signature, "aav".as_bytes().to_vec());
+        Ok(())
+    }
+
+    #[derive(Clone, Debug)]
+    struct VariantArrayPolicy;
+
+    impl SerializerPolicy for VariantArrayPolicy {
+        fn query_struct_name(&self, _: &str) -> StructSerializationStyle {
+            StructSerializationStyle::Dict
+        }
+
+        fn query_seq(&self, _: Option<&FieldContext>) -> SeqSerializationStyle {
+            SeqSerializationStyle::Variant
+        }
+    }
+
+    #[test]
+    fn inferred_array_late_fallback() -> Result<()> {
+        #[derive(Clone, Debug, Serialize)]
+        #[serde(untagged)]
+        enum Item {
+            Byte(u8),
+            Int(i32),
+            String(String),
+            Pair((u8, u64)),
+            Ints(Vec<i32>),
+        }
+
+        // Items already written are taken apart again when one does not
+        // agree with them, whether they are fixed size or not.
+        let cases = vec![
+            vec![Item::Byte(1), Item::Byte(2), Item::Byte(3), Item::Int(4)],
+            vec![Item::Pair((1, 2)), Item::Pair((3, 4)), Item::Byte(5)],
+            vec![
+                Item::String("a".to_owned()),
+                Item::String("bcd".to_owned()),
+                Item::Int(1),
+                Item::String("e".to_owned()),
+            ],
+            vec![Item::Ints(vec![]), Item::Ints(vec![]), Item::Int(1)],
+        ];
+        for data in cases {
+            let message = serialize(&data)?;
+            let correct_message = serialize_with_policy(&data, VariantArrayPolicy)?;
+            assert_eq!(
+                correct_message, message,
+                "heterogeneous array {:?} serialized incorrectly",
+                data
+            );
+        }
+
+        let message = serialize(vec![vec![1], vec![], vec![2]])?;
+        let correct_message = serialize_with_signature(vec![vec![1], vec![], vec![2]], "aai")?;
+        assert_eq!(
+            correct_message, message,
+            "empty array among typed arrays serialized incorrectly"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn inferred_array_large() -> Result<()> {
+        let data = vec![7u32; 1 << 20];
+        let message = serialize(&data)?;
+        assert_eq!(message.signature, "au".as_bytes().to_vec());
+        assert_eq!(message.data.len(), 4 + (4 << 20));
+        assert_eq!(message.data[4..8], [7, 0, 0, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn serialize_bytes() -> Result<()> {
+        let data = serde_bytes::ByteBuf::from(vec![0xde, 0xad, 0xbe, 0xef, 0xff]);
+        let message = serialize(data)?;
+        let correct_message = Message {
+            data: vec![5, 0, 0, 0, 0xde, 0xad, 0xbe, 0xef, 0xff],
+            signature: "ay".as_bytes().to_vec(),
+            fds: vec![],
+            endianness: Endianness::Little,
+        };
+        assert_eq!(
+            correct_message, message,
+            "byte slice serialized incorrectly"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_message_limits() {
+        let longest = serde_bytes::ByteBuf::from(vec![0; 1 << 26]);
+        assert!(serialize(longest).is_ok());
+        let too_long = serde_bytes::ByteBuf::from(vec![0; (1 << 26) + 1]);
+        assert_eq!(
+            serialize((1u8, too_long)),
+            Err(Error::ArrayTooLong((1 << 26) + 1, 1 << 26, 4))
+        );
+
+        let nested = |depth| (0..depth).fold(Value::Byte(0), |v, _| Value::Struct(vec![v]));
+        assert!(serialize(nested(32)).is_ok());
+        assert_eq!(serialize(nested(33)), Err(Error::StructNestingTooDeep(33)));
+        // Nesting starts again inside a variant
+        assert!(serialize(Value::Struct(vec![nested(32).into_variant()])).is_ok());
+        assert_eq!(
+            serialize(nested(33).into_variant()),
+            Err(Error::StructNestingTooDeep(33))
+        );
+
+        let wide = Value::Struct(vec![Value::Byte(0); 254]);
+        assert_eq!(
+            serialize(wide.clone().into_variant()),
+            Err(Error::SignatureTooLong(256))
+        );
+        assert_eq!(serialize(wide), Err(Error::SignatureTooLong(256)));
+    }
+
+    #[test]
+    fn serialized_messages_are_valid() -> Result<()> {
+        #[derive(Serialize)]
+        enum Kind {
+            Unit,
+            Pair(u8, String),
+        }
+
+        #[derive(Serialize)]
+        struct Data {
+            name: String,
+            items: Vec<(u8, u32)>,
+            empty: Vec<u32>,
+            dict: BTreeMap<u32, BTreeMap<String, i16>>,
+            maybe: Option<f64>,
+            kinds: Vec<Kind>,
+            value: Value,
+        }
+
+        let data = Data {
+            name: "name".to_owned(),
+            items: vec![(1, 2)],
+            empty: vec![],
+            dict: vec![(3, vec![("a".to_owned(), -1)].into_iter().collect())]
+                .into_iter()
+                .collect(),
+            maybe: Some(1.5),
+            kinds: vec![Kind::Unit, Kind::Pair(4, "b".to_owned())],
+            value: Value::Struct(vec![Value::Byte(5)]).into_variant(),
+        };
+        crate::message::validate(&serialize(&data)?)?;
+        crate::message::validate(&serialize_with_policy(
+            &data,
+            StronglyTypedSerializerPolicy,
+        )?)?;
+
+        // These are serialized as nothing, as DBus has no empty struct.
+        #[derive(Serialize)]
+        struct Unit;
+        #[derive(Serialize)]
+        struct Missing {
+            maybe: Option<u8>,
+        }
+        crate::message::validate(&serialize(())?)?;
+        crate::message::validate(&serialize(Unit)?)?;
+        assert!(
+            serialize_with_policy(Missing { maybe: None }, StronglyTypedSerializerPolicy).is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn interior_nuls() -> Result<()> {
+        #[derive(Clone)]
+        struct NulPolicy(InteriorNulStyle);
+
+        impl SerializerPolicy for NulPolicy {
+            fn query_struct_name(&self, _: &str) -> StructSerializationStyle {
+                StructSerializationStyle::StronglyTyped
+            }
+
+            fn query_interior_nul(&self, _: Option<&FieldContext>) -> InteriorNulStyle {
+                self.0
+            }
+        }
+
+        assert_eq!(serialize("a\0b"), Err(Error::InteriorNul));
+        assert_eq!(serialize_with_signature('\0', "s"), Err(Error::InteriorNul));
+        assert_eq!(
+            serialize_with_policy(("a\0b", 1u8), NulPolicy(InteriorNulStyle::Error)),
+            Err(Error::InteriorNul)
+        );
+        assert_eq!(
+            serialize_with_policy(("a\0b\0", 1u8), NulPolicy(InteriorNulStyle::Strip))?,
+            serialize(("ab", 1u8))?
+        );
+        assert_eq!(
+            serialize_with_signature_and_policy('\0', "s", NulPolicy(InteriorNulStyle::Strip))?,
+            serialize("")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn non_basic_map_keys() {
+        let mut map = BTreeMap::new();
+        map.insert((1u8, 2u8), 3u8);
+        assert_eq!(
+            serialize(&map),
+            Err(Error::NonBasicDictKey(b"(yy)".to_vec()))
+        );
+        let mut map = BTreeMap::new();
+        map.insert(vec![1u8], 3u8);
+        assert_eq!(serialize(&map), Err(Error::NonBasicDictKey(b"ay".to_vec())));
+    }
+
+    #[test]
+    fn typed_map_keys() -> Result<()> {
+        let mut data = BTreeMap::new();
+        data.insert(1u32, "a");
+        data.insert(2u32, "bc");
+        let message = serialize(data)?;
+        let correct_message = Message {
+            data: vec![
+                27, 0, 0, 0, // 27 bytes of array
+                0, 0, 0, 0, // padding(8)
+                1, 0, 0, 0, // key 1
+                1, 0, 0, 0, // 1 byte string
+                97, 0, // "a"
+                0, 0, 0, 0, 0, 0, // padding(8)
+                2, 0, 0, 0, // key 2
+                2, 0, 0, 0, // 2 byte string
+                98, 99, 0, // "bc"
+            ],
+            signature: "a{us}".as_bytes().to_vec(),
+            fds: vec![],
+            endianness: Endianness::Little,
+        };
+        assert_eq!(correct_message, message, "typed map serialized incorrectly");
+        Ok(())
+    }
+
+    #[test]
+    fn inferred_map_values() -> Result<()> {
+        #[derive(Clone, Debug, Serialize)]
+        #[serde(untagged)]
+        enum IntOrString {
+            Int(i32),
+            String(String),
+        }
+
+        let mut strings = BTreeMap::new();
+        strings.insert("a", "b");
+        assert_eq!(serialize(&strings)?.signature, b"a{ss}".to_vec());
+
+        let mut mixed = BTreeMap::new();
+        mixed.insert("a".to_owned(), IntOrString::Int(3));
+        mixed.insert("b".to_owned(), IntOrString::String("c".to_owned()));
+        assert_eq!(serialize(&mixed)?.signature, b"a{sv}".to_vec());
+
+        let mut nested = BTreeMap::new();
+        nested.insert(7u32, mixed);
+        nested.insert(8u32, BTreeMap::new());
+        assert_eq!(serialize(&nested)?.signature, b"a{ua{sv}}".to_vec());
+
+        let empty: BTreeMap<u32, u32> = BTreeMap::new();
+        assert_eq!(serialize(&empty)?.signature, b"a{sv}".to_vec());
+
+        // Empty maps take their signature from their siblings.
+        let mut ints = BTreeMap::new();
+        ints.insert(3u32, vec![("a", 1i32)].into_iter().collect());
+        ints.insert(4u32, BTreeMap::new());
+        assert_eq!(serialize(&ints)?.signature, b"a{ua{si}}".to_vec());
+        ints.insert(5u32, BTreeMap::new());
+        ints.remove(&3);
+        assert_eq!(serialize(&ints)?.signature, b"a{ua{sv}}".to_vec());
+
+        let mut strings = BTreeMap::new();
+        strings.insert(
+            1u8,
+            vec![BTreeMap::new(), vec![(2u8, "b")].into_iter().collect()],
+        );
+        strings.insert(3u8, vec![]);
+        assert_eq!(serialize(&strings)?.signature, b"a{yaa{ys}}".to_vec());
+        Ok(())
+    }
+
+    #[test]
+    fn map_policy() -> Result<()> {
+        #[derive(Clone, Debug)]
+        struct MapPolicy;
+
+        impl SerializerPolicy for MapPolicy {
+            fn query_struct_name(&self, _: &str) -> StructSerializationStyle {
+                StructSerializationStyle::StronglyTyped
+            }
+
+            fn query_map(&self, field: Option<&FieldContext>) -> MapSerializationStyle {
+                match field.map(|field| field.field_name) {
+                    Some("variants") => MapSerializationStyle::Variant,
+                    Some("typed") => MapSerializationStyle::Typed(b"s".to_vec(), b"x".to_vec()),
+                    _ => MapSerializationStyle::Inferred,
+                }
+            }
+        }
+
+        #[derive(Clone, Debug, Serialize)]
+        struct WithMaps {
+            variants: BTreeMap<String, String>,
+            typed: BTreeMap<String, i64>,
+        }
+
+        let data = WithMaps {
+            variants: vec![("a".to_owned(), "b".to_owned())].into_iter().collect(),
+            typed: BTreeMap::new(),
+        };
+        let message = serialize_with_policy(&data, MapPolicy)?;
+        assert_eq!(message.signature, b"(a{sv}a{sx})".to_vec());
+
+        let data = WithMaps {
+            variants: BTreeMap::new(),
+            typed: vec![("a".to_owned(), 3)].into_iter().collect(),
+        };
+        let message = serialize_with_policy(&data, MapPolicy)?;
+        assert_eq!(message.signature, b"(a{sv}a{sx})".to_vec());
+
+        // Values nested in a typed map are serialized to fit its value
+        // signature.
+        #[derive(Clone, Debug)]
+        struct ObjectsPolicy;
+
+        impl SerializerPolicy for ObjectsPolicy {
+            fn query_struct_name(&self, _: &str) -> StructSerializationStyle {
+                StructSerializationStyle::StronglyTyped
+            }
+
+            fn query_map(&self, field: Option<&FieldContext>) -> MapSerializationStyle {
+                match field {
+                    Some(_) => MapSerializationStyle::Typed(b"o".to_vec(), b"a{sa{sv}}".to_vec()),
+                    None => MapSerializationStyle::Inferred,
+                }
+            }
+        }
+
+        #[derive(Clone, Debug, Serialize)]
+        struct Objects {
+            objects: BTreeMap<ObjectPath, BTreeMap<String, BTreeMap<String, u32>>>,
+        }
+
+        let mut interfaces = BTreeMap::new();
+        interfaces.insert(
+            "org.a".to_owned(),
+            vec![("x".to_owned(), 1)].into_iter().collect(),
+        );
+        interfaces.insert("org.b".to_owned(), BTreeMap::new());
+        let mut objects = BTreeMap::new();
+        objects.insert(ObjectPath::new("/a")?, interfaces);
+        objects.insert(ObjectPath::new("/b")?, BTreeMap::new());
+        let message = serialize_with_policy(Objects { objects }, ObjectsPolicy)?;
+        assert_eq!(message.signature, b"(a{oa{sa{sv}}})".to_vec());
+        crate::message::validate(&message)?;
+        Ok(())
+    }
+
+    #[test]
+    fn map_key_mismatch() {
+        #[derive(Clone, Debug, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+        #[serde(untagged)]
+        enum IntOrString {
+            Int(i32),
+            String(String),
+        }
+
+        let mut data = BTreeMap::new();
+        data.insert(IntOrString::Int(1), 1);
+        data.insert(IntOrString::String("a".to_owned()), 2);
+        assert_eq!(
+            serialize(data),
+            Err(Error::MismatchSignature(b"i".to_vec(), b"s".to_vec()))
+        );
+    }
+
+    #[derive(Clone, Debug, Serialize)]
+    struct Point {
+        x: i64,
+        y: i64,
+    }
+
+    #[test]
+    fn signature_struct_styles() -> Result<()> {
+        let data = Point { x: 1, y: 2 };
+
+        let message = serialize_with_signature(&data, "(ii)")?;
+        assert_eq!(message, serialize((1i32, 2i32))?);
+
+        let message = serialize_with_signature(&data, "a{sv}")?;
+        assert_eq!(message, serialize(&data)?);
+
+        let mut map = BTreeMap::new();
+        map.insert("x", 1u8);
+        map.insert("y", 2u8);
+        let message = serialize_with_signature(&data, "a{sy}")?;
+        assert_eq!(message, serialize(&map)?);
+        Ok(())
+    }
+
+    #[test]
+    fn signature_nested() -> Result<()> {
+        let mut data = BTreeMap::new();
+        data.insert("a", vec![Point { x: 1, y: 2 }, Point { x: 3, y: 4 }]);
+        data.insert("b", vec![]);
+        let message = serialize_with_signature(&data, "a{sa(ii)}")?;
+        let correct_message = Message {
+            data: vec![
+                48, 0, 0, 0, // 48 bytes of array
+                0, 0, 0, 0, // padding(8)
+                1, 0, 0, 0, // 1 byte string
+                97, 0, // "a"
+                0, 0, // padding(4)
+                16, 0, 0, 0, // 16 bytes of array
+                0, 0, 0, 0, // padding(8)
+                1, 0, 0, 0, 2, 0, 0, 0, // (1, 2)
+                3, 0, 0, 0, 4, 0, 0, 0, // (3, 4)
+                1, 0, 0, 0, // 1 byte string
+                98, 0, // "b"
+                0, 0, // padding(4)
+                0, 0, 0, 0, // empty array
+                0, 0, 0, 0, // padding(8)
+            ],
+            signature: "a{sa(ii)}".as_bytes().to_vec(),
+            fds: vec![],
+            endianness: Endianness::Little,
+        };
+        assert_eq!(
+            correct_message, message,
+            "nested message serialized incorrectly"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn signature_basic_conversions() -> Result<()> {
+        #[derive(Clone, Debug, Serialize)]
+        enum Color {
+            Red,
+            Green,
+        }
+
+        let data = (3i64, "/org/example", 'x', Color::Green, Color::Red, 7u16);
+        let message = serialize_with_signature(&data, "qoqsyv")?;
+        assert_eq!(message.signature, b"(qoqsyv)".to_vec());
+        assert_eq!(
+            message.data,
+            vec![
+                3, 0, // 3
+                0, 0, // padding(4)
+                12, 0, 0, 0, // 12 byte object path
+                47, 111, 114, 103, 47, 101, 120, 97, 109, 112, 108, 101, 0, // "/org/example"
+                0, // padding(2)
+                120, 0, // 'x'
+                5, 0, 0, 0, // 5 byte string
+                71, 114, 101, 101, 110, 0, // "Green"
+                0, // 0
+                1, 113, 0, // signature "q"
+                7, 0, // 7
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn signature_mismatch() {
+        assert_eq!(
+            serialize_with_signature(300, "y"),
+            Err(Error::TargetValueOutOfRange("300".to_owned(), b'y', 0))
+        );
+        assert_eq!(
+            serialize_with_signature(("a", "b"), "(si)"),
+            Err(Error::TargetSignatureMismatch(
+                b"i".to_vec(),
+                2,
+                b"s".to_vec()
+            ))
+        );
+        assert_eq!(
+            serialize_with_signature((1, 2, 3), "ii"),
+            Err(Error::TargetFieldsExhausted(b"(ii)".to_vec(), 0))
+        );
+
+        // Positions are in the signature as given, with several
+        // top-level types.
+        assert_eq!(
+            serialize_with_signature(("a", "b"), "si"),
+            Err(Error::TargetSignatureMismatch(
+                b"i".to_vec(),
+                1,
+                b"s".to_vec()
+            ))
+        );
+        assert_eq!(
+            serialize_with_signature(("a", 5u8), "sa{sv}"),
+            Err(Error::TargetSignatureMismatch(
+                b"a{sv}".to_vec(),
+                1,
+                b"y".to_vec()
+            ))
+        );
+        assert_eq!(
+            serialize_with_signature(("a", 1u32, 2u8), "sus"),
+            Err(Error::TargetSignatureMismatch(
+                b"s".to_vec(),
+                2,
+                b"y".to_vec()
+            ))
+        );
+        assert_eq!(
+            serialize_with_signature(("a", 300), "sy"),
+            Err(Error::TargetValueOutOfRange("300".to_owned(), b'y', 1))
+        );
+
+        assert_eq!(
+            serialize_with_signature((), "u"),
+            Err(Error::TargetSignatureMismatch(
+                b"u".to_vec(),
+                0,
+                b"".to_vec()
+            ))
+        );
+
+        // Only Unix fds fit `h`.
+        assert_eq!(
+            serialize_with_signature(3u32, "h"),
+            Err(Error::TargetSignatureMismatch(
+                b"h".to_vec(),
+                0,
+                b"u".to_vec()
+            ))
+        );
+        assert_eq!(
+            serialize_with_signature(UnixFdIndex(3), "h"),
+            Ok(Message {
+                data: vec![3, 0, 0, 0],
+                signature: b"h".to_vec(),
+                fds: vec![],
+                endianness: Endianness::Little,
+            })
+        );
+        assert_eq!(
+            serialize_with_signature(vec![Point { x: 1, y: 2 }], "a(is)"),
+            Err(Error::TargetSignatureMismatch(
+                b"s".to_vec(),
+                3,
+                b"x".to_vec()
+            ))
+        );
+        assert_eq!(
+            serialize_with_signature(vec![1], "a{si}"),
+            Err(Error::TargetSignatureMismatch(
+                b"{si}".to_vec(),
+                1,
+                b"i".to_vec()
+            ))
+        );
+
+        // Signatures are shown as text.
+        let message = |res: Result<Message>| res.unwrap_err().to_string();
+        assert_eq!(
+            message(serialize_with_signature(300, "y")),
+            "value 300 out of range for signature 'y' at 0"
+        );
+        assert_eq!(
+            message(serialize_with_signature(("a", "b"), "(si)")),
+            "value with signature \"s\" does not fit signature \"i\" at 2"
+        );
+        assert_eq!(
+            message(serialize_with_signature((1, 2, 3), "ii")),
+            "value has more fields than signature \"(ii)\" at 0"
+        );
+    }
+
+    #[derive(Clone, Debug)]
+    struct OptionPolicy(OptionSerializationStyle);
+
+    impl SerializerPolicy for OptionPolicy {
+        fn query_struct_name(&self, _: &str) -> StructSerializationStyle {
+            StructSerializationStyle::StronglyTyped
+        }
+
+        fn query_option(&self, _: Option<&FieldContext>) -> OptionSerializationStyle {
+            self.0.clone()
+        }
+    }
+
+    #[test]
+    fn option_array() -> Result<()> {
+        let policy = OptionPolicy(OptionSerializationStyle::Array);
+        let data = (Some(7u16), None::<u16>);
+        let message = serialize_with_policy(data, policy.clone())?;
+        let correct_message = Message {
+            data: vec![
+                2, 0, 0, 0, // 2 bytes of array
+                7, 0, // 7
+                0, 0, // padding(4)
+                0, 0, 0, 0, // empty array
+            ],
+            signature: "(aqav)".as_bytes().to_vec(),
+            fds: vec![],
+            endianness: Endianness::Little,
+        };
+        assert_eq!(
+            correct_message, message,
+            "optional arrays serialized incorrectly"
+        );
+
+        let message = serialize_with_signature_and_policy(data, "aqaq", policy)?;
+        assert_eq!(message.signature, b"(aqaq)".to_vec());
+        Ok(())
+    }
+
+    #[derive(Clone)]
+    struct EmptyVariantPolicy;
+
+    impl SerializerPolicy for EmptyVariantPolicy {
+        fn query_struct_name(&self, _: &str) -> StructSerializationStyle {
+            StructSerializationStyle::StronglyTyped
+        }
+
+        fn query_option(&self, _: Option<&FieldContext>) -> OptionSerializationStyle {
+            OptionSerializationStyle::Variant
+        }
+
+        fn allow_empty_variants(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn option_variant() -> Result<()> {
+        let policy = OptionPolicy(OptionSerializationStyle::Variant);
+        let message = serialize_with_policy((Some(7u16), 1u8), policy.clone())?;
+        validate(&message)?;
+        assert_eq!(
+            serialize_with_policy((1u8, None::<u32>), policy),
+            Err(Error::EmptyVariant)
+        );
+
+        // Empty variants are only serialized when asked for, as they do
+        // not validate.
+        let data = (Some(7u16), None::<u16>);
+        let message = serialize_with_policy(data, EmptyVariantPolicy)?;
+        assert_eq!(
+            validate(&message),
+            Err(Error::InvalidMessage(
+                Box::new(Error::NotSingleType(Vec::new())),
+                6,
+                2
+            ))
+        );
+        let correct_message = Message {
+            data: vec![
+                1, 113, 0, // signature "q"
+                0, // padding(2)
+                7, 0, // 7
+                0, 0, // empty signature
+            ],
+            signature: "(vv)".as_bytes().to_vec(),
+            fds: vec![],
+            endianness: Endianness::Little,
+        };
+        assert_eq!(
+            correct_message, message,
+            "optional variants serialized incorrectly"
+        );
+        Ok(())
+    }
+
+    #[derive(Clone, Debug)]
+    struct EnumPolicy;
+
+    impl SerializerPolicy for EnumPolicy {
+        fn query_struct_name(&self, _: &str) -> StructSerializationStyle {
+            StructSerializationStyle::StronglyTyped
+        }
+
+        fn query_enum(&self, name: &str) -> EnumSerializationStyle {
+            match name {
+                "ByName" => EnumSerializationStyle::Name,
+                "NameTagged" => EnumSerializationStyle::NameTaggedStruct,
+                "IndexTagged" => EnumSerializationStyle::IndexTaggedStruct,
+                _ => EnumSerializationStyle::Index,
+            }
+        }
+    }
+
+    #[test]
+    fn enum_styles() -> Result<()> {
+        #[derive(Clone, Debug, Serialize)]
+        enum ByIndex {
+            A,
+            B(u8),
+        }
+
+        #[derive(Clone, Debug, Serialize)]
+        enum ByName {
+            A,
+            B(u8),
+        }
+
+        #[derive(Clone, Debug, Serialize)]
+        enum NameTagged {
+            A,
+            B(u8, u8),
+        }
+
+        #[derive(Clone, Debug, Serialize)]
+        enum IndexTagged {
+            A,
+            B { x: u8 },
+        }
+
+        let data = (ByIndex::A, ByIndex::B(1), ByName::A, ByName::B(2));
+        let message = serialize_with_policy(data, EnumPolicy)?;
+        assert_eq!(message.signature, b"(ua{sv}sa{sv})".to_vec());
+
+        let data = (NameTagged::A, NameTagged::B(3, 4));
+        let message = serialize_with_policy(data, EnumPolicy)?;
+        let correct_message = Message {
+            data: vec![
+                1, 0, 0, 0, // 1 byte string
+                65, 0, // "A"
+                5, 97, 123, 115, 118, 125, 0, // signature "a{sv}"
+                0, 0, 0, // padding(4)
+                0, 0, 0, 0, // empty array
+                0, 0, 0, 0, // padding(8)
+                1, 0, 0, 0, // 1 byte string
+                66, 0, // "B"
+                4, 40, 121, 121, 41, 0, // signature "(yy)"
+                0, 0, 0, 0, // padding(8)
+                3, 4, // (3, 4)
+            ],
+            signature: "((sv)(sv))".as_bytes().to_vec(),
+            fds: vec![],
+            endianness: Endianness::Little,
+        };
+        assert_eq!(
+            correct_message, message,
+            "tagged struct enum serialized incorrectly"
+        );
+
+        let data = (IndexTagged::A, IndexTagged::B { x: 5 });
+        let message = serialize_with_policy(data, EnumPolicy)?;
+        let correct_message = Message {
+            data: vec![
+                0, 0, 0, 0, // index 0
+                5, 97, 123, 115, 118, 125, 0, // signature "a{sv}"
+                0, // padding(4)
+                0, 0, 0, 0, // empty array
+                1, 0, 0, 0, // index 1
+                5, 97, 123, 115, 118, 125, 0, // signature "a{sv}"
+                0, // padding(4)
+                10, 0, 0, 0, // 10 bytes of array
+                1, 0, 0, 0, // 1 byte string
+                120, 0, // "x"
+                1, 121, 0, // signature "y"
+                5, // 5
+            ],
+            signature: "((uv)(uv))".as_bytes().to_vec(),
+            fds: vec![],
+            endianness: Endianness::Little,
+        };
+        assert_eq!(
+            correct_message, message,
+            "tagged struct enum serialized incorrectly"
+        );
+        Ok(())
+    }
+
+    #[derive(Clone)]
+    struct FieldPolicy;
+
+    impl SerializerPolicy for FieldPolicy {
+        fn query_struct_name(&self, name: &str) -> StructSerializationStyle {
+            match name {
+                "Point" | "Settings" => StructSerializationStyle::StronglyTyped,
+                _ => StructSerializationStyle::Dict,
+            }
+        }
+
+        fn query_field(
+            &self,
+            field: &FieldContext,
+            parent_path: &[&'static str],
+        ) -> FieldSerializationStyle {
+            let mut style = FieldSerializationStyle::default();
+            match (field.struct_name, field.field_name, parent_path) {
+                (_, "token", []) => style.skip = true,
+                (_, "retries", []) => {
+                    style.rename = Some("Retries".to_owned());
+                    style.signature = Some("u".to_owned());
+                }
+                ("Settings", "window", []) => {
+                    style.struct_style = Some(StructSerializationStyle::Dict)
+                }
+                ("Point", "x", ["tags"]) => style.signature = Some("v".to_owned()),
+                _ => {}
+            }
+            style
+        }
+    }
+
+    #[test]
+    fn field_styles() -> Result<()> {
+        #[derive(Serialize)]
+        struct Settings {
+            name: String,
+            token: String,
+            retries: i64,
+            window: Point,
+            tags: Vec<Point>,
+        }
+
+        let data = Settings {
+            name: "main".to_owned(),
+            token: "secret".to_owned(),
+            retries: 3,
+            window: Point { x: 1, y: 2 },
+            tags: vec![Point { x: 3, y: 4 }],
+        };
+        let message = serialize_with_policy(&data, FieldPolicy)?;
+        assert_eq!(message.signature, b"(sua{sv}a(vx))".to_vec());
+        let fields = (&data.name, 3u32, &data.window, &data.tags);
+        assert_eq!(
+            message,
+            serialize_with_signature(fields, "sua{sv}a(vx)")?,
+            "fields serialized incorrectly"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn field_styles_dict() -> Result<()> {
+        #[derive(Serialize)]
+        struct Login {
+            token: String,
+            retries: i64,
+        }
+
+        #[derive(Serialize)]
+        struct Renamed {
+            #[serde(rename = "Retries")]
+            retries: u32,
+        }
+
+        let message = serialize_with_policy(
+            Login {
+                token: "secret".to_owned(),
+                retries: 3,
+            },
+            FieldPolicy,
+        )?;
+        assert_eq!(message, serialize(Renamed { retries: 3 })?);
+        Ok(())
+    }
 }
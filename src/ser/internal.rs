@@ -1,10 +1,17 @@
+use crate::align::align;
 use crate::error::{Error, Result};
-use crate::message::Message;
+use crate::message::header::MAX_MESSAGE_LEN;
+#[cfg(unix)]
+use crate::message::RawFd;
+use crate::message::{Endianness, Message};
 use crate::primitives::DbusPrimitive;
+use crate::signature::{alignment, is_basic, single_type_end, validate, Type};
 
-use super::message_builder::PendingMessage;
+use super::message_builder::{Mark, MessageBuilder, PendingMessage};
 
-use std::mem::swap;
+use std::mem::{replace, swap, take};
+#[cfg(unix)]
+use std::os::unix::io::OwnedFd;
 
 pub(super) struct ReadySerializer {
     mesg: PendingMessage,
@@ -12,17 +19,55 @@ pub(super) struct ReadySerializer {
 
 pub(super) struct DoneSerializer {
     mesg: PendingMessage,
+
+    // Set for empty arrays whose item signature was not known, and
+    // so could be replaced with an empty array of any item signature.
+    untyped_empty: bool,
 }
 
 impl DoneSerializer {
+    fn new(mesg: PendingMessage) -> Self {
+        Self {
+            mesg,
+            untyped_empty: false,
+        }
+    }
+
     fn extract(self) -> PendingMessage {
         self.mesg
     }
 
+    pub(super) fn signature(&self) -> &[u8] {
+        &self.mesg.signature
+    }
+
+    pub(super) fn mark_untyped_empty(mut self) -> Self {
+        self.untyped_empty = true;
+        self
+    }
+
+    pub(super) fn is_untyped_empty(&self) -> bool {
+        self.untyped_empty
+    }
+
+    pub(super) fn into_variant(self) -> Result<DoneSerializer> {
+        let (var_ser, _) = ReadySerializer::new().start_variant();
+        var_ser.finish_variant(self) // This relies on the implementation detail that variant DoneSerializers are interchangeable
+    }
+
     pub(super) fn complete(self) -> Result<Message> {
+        // The signatures within variants are checked when they are
+        // finished.
+        validate(&self.mesg.signature)?;
+        let (data, fds) = self.mesg.builder.complete()?;
+        if data.len() > MAX_MESSAGE_LEN {
+            return Err(Error::MessageTooLong(data.len() as u64, MAX_MESSAGE_LEN));
+        }
         Ok(Message {
-            data: self.mesg.builder.complete(),
+            data,
             signature: self.mesg.signature,
+            fds,
+            endianness: Endianness::Little,
         })
     }
 }
@@ -34,17 +79,59 @@ impl ReadySerializer {
         }
     }
 
+    // The length of the signature so far, which a serialized value's
+    // signature follows on from.
+    pub(super) fn signature_len(&self) -> usize {
+        self.mesg.signature.len()
+    }
+
     pub(super) fn serialize_primitive<T: DbusPrimitive>(
         self,
         primitive: &T,
     ) -> Result<DoneSerializer> {
         let mut mesg = self.mesg;
 
-        mesg.builder.align(T::alignment());
+        mesg.builder.align(alignment(T::signature())?);
         primitive.serialize(mesg.builder.prepare_write(primitive.size()))?;
         mesg.signature.push(T::signature());
 
-        Ok(DoneSerializer { mesg })
+        Ok(DoneSerializer::new(mesg))
+    }
+
+    // `fd` is a duplicate of the fd numbered `key`.
+    #[cfg(unix)]
+    pub(super) fn serialize_unix_fd(self, key: RawFd, fd: OwnedFd) -> DoneSerializer {
+        let mut mesg = self.mesg;
+        mesg.builder.write_unix_fd(key, fd);
+        mesg.signature.push(b'h');
+        DoneSerializer::new(mesg)
+    }
+
+    pub(super) fn serialize_byte_array(self, bytes: &[u8]) -> DoneSerializer {
+        let mut mesg = self.mesg;
+
+        mesg.builder.align(4);
+        let token = mesg.builder.start_length();
+        mesg.builder
+            .prepare_write(bytes.len())
+            .copy_from_slice(bytes);
+        mesg.builder.finish_length(token);
+        mesg.signature.extend_from_slice(b"ay");
+
+        DoneSerializer::new(mesg)
+    }
+
+    // Serializes nothing, for a unit.
+    pub(super) fn finish_empty(self) -> DoneSerializer {
+        DoneSerializer::new(self.mesg)
+    }
+
+    // Serializes a copy of a value that was serialized separately.
+    pub(super) fn serialize_copy(self, value: &DoneSerializer) -> DoneSerializer {
+        let mut mesg = self.mesg;
+        mesg.builder.append_copy(&value.mesg.builder);
+        mesg.signature.extend_from_slice(&value.mesg.signature);
+        DoneSerializer::new(mesg)
     }
 
     pub(super) fn start_struct(self) -> ReadyStructSerializer {
@@ -59,9 +146,32 @@ impl ReadySerializer {
         ReadyArraySerializer::new(self.mesg, item_sig)
     }
 
+    pub(super) fn start_inferred_array(self) -> InferredArraySerializer {
+        InferredArraySerializer {
+            prev: self.mesg,
+            contents: PendingMessage::new(),
+            item_sig: None,
+            untyped: 0,
+            bounds: ItemBounds::Variants,
+        }
+    }
+
     pub(super) fn start_dict(self) -> ReadyDictSerializer {
         ReadyDictSerializer {
             ser: self.start_array(vec![b'{', b's', b'v', b'}']),
+            variant_values: true,
+        }
+    }
+
+    // Like `start_dict`, but with values of the given signature, which
+    // are not wrapped in variants unless the signature is `v`.
+    pub(super) fn start_typed_dict(self, value_sig: &[u8]) -> ReadyDictSerializer {
+        let mut item_sig = vec![b'{', b's'];
+        item_sig.extend_from_slice(value_sig);
+        item_sig.push(b'}');
+        ReadyDictSerializer {
+            ser: self.start_array(item_sig),
+            variant_values: value_sig == b"v",
         }
     }
 
@@ -84,11 +194,14 @@ pub(super) struct VariantSerializer {
 }
 
 impl VariantSerializer {
-    pub(super) fn finish_variant(self, value: DoneSerializer) -> DoneSerializer {
+    pub(super) fn finish_variant(self, value: DoneSerializer) -> Result<DoneSerializer> {
         let mut mesg = self.mesg;
         let value_mesg = value.extract();
 
+        // The variant's signature is a single complete type, so this
+        // checks its nesting.
         let signature_len = value_mesg.signature.len();
+        validate(&value_mesg.signature)?;
 
         // signature size
         mesg.builder.prepare_write(1)[0] = signature_len as u8;
@@ -106,50 +219,91 @@ impl VariantSerializer {
         // add variant signature to mesg
         mesg.signature.push(b'v');
 
-        DoneSerializer { mesg }
+        Ok(DoneSerializer::new(mesg))
+    }
+
+    // A variant with an empty signature and no value
+    pub(super) fn finish_empty_variant(self) -> DoneSerializer {
+        let mut mesg = self.mesg;
+        mesg.builder.prepare_write(2).copy_from_slice(&[0, 0]);
+        mesg.signature.push(b'v');
+        DoneSerializer::new(mesg)
     }
 }
 
 pub(super) struct ReadyStructSerializer {
     mesg: PendingMessage,
+
+    // Where the `{` of a kv pair is in the signature
+    kv_start: Option<usize>,
 }
 
 impl ReadyStructSerializer {
     fn new(mut mesg: PendingMessage) -> Self {
         mesg.builder.align(8);
         mesg.signature.push(b'(');
-        ReadyStructSerializer { mesg }
+        ReadyStructSerializer {
+            mesg,
+            kv_start: None,
+        }
     }
 
     fn new_kv_pair(mut mesg: PendingMessage) -> Self {
         mesg.builder.align(8);
+        let kv_start = Some(mesg.signature.len());
         mesg.signature.push(b'{');
-        ReadyStructSerializer { mesg }
+        ReadyStructSerializer { mesg, kv_start }
     }
 
     pub(super) fn start_item(self) -> (PendingStructSerializer, ReadySerializer) {
-        (PendingStructSerializer, ReadySerializer { mesg: self.mesg })
+        let pending = PendingStructSerializer {
+            kv_start: self.kv_start,
+        };
+        (pending, ReadySerializer { mesg: self.mesg })
+    }
+
+    // Like `start_item` and `finish_item`, but for an item that was
+    // serialized separately, with its own `ReadySerializer`.
+    pub(super) fn append_item(mut self, item: DoneSerializer) -> ReadyStructSerializer {
+        let item = item.extract();
+        self.mesg.builder.append_data(&item.builder);
+        self.mesg.signature.extend_from_slice(&item.signature);
+        self
     }
 
     pub(super) fn finish_struct(self) -> DoneSerializer {
         let mut mesg = self.mesg;
         mesg.signature.push(b')');
-        DoneSerializer { mesg }
+        DoneSerializer::new(mesg)
     }
 
-    pub(super) fn finish_kv_pair(self) -> DoneSerializer {
+    // Checks that the key is a basic type, which the array signature the
+    // pair is checked against can't guarantee when that was itself
+    // inferred from the keys.
+    pub(super) fn finish_kv_pair(self) -> Result<DoneSerializer> {
         let mut mesg = self.mesg;
+        let start = self
+            .kv_start
+            .expect("kv pair finished without being started");
+        let key_end = single_type_end(&mesg.signature, start + 1)?;
+        let key = &mesg.signature[start + 1..key_end];
+        if key.len() != 1 || !is_basic(key[0]) {
+            return Err(Error::NonBasicDictKey(key.to_vec()));
+        }
         mesg.signature.push(b'}');
-        DoneSerializer { mesg }
+        Ok(DoneSerializer::new(mesg))
     }
 }
 
-pub(super) struct PendingStructSerializer;
+pub(super) struct PendingStructSerializer {
+    kv_start: Option<usize>,
+}
 
 impl PendingStructSerializer {
     pub(super) fn finish_item(self, item: DoneSerializer) -> ReadyStructSerializer {
         ReadyStructSerializer {
             mesg: item.extract(),
+            kv_start: self.kv_start,
         }
     }
 }
@@ -183,12 +337,22 @@ impl ReadyArraySerializer {
         (pending, ready)
     }
 
-    pub(super) fn finish_array(self) -> DoneSerializer {
+    // Like `start_item` and `finish_item`, but for an item that was
+    // serialized separately, with its own `ReadySerializer`.
+    pub(super) fn append_item(mut self, item: DoneSerializer) -> Result<ReadyArraySerializer> {
+        let item = item.extract();
+        check_item_sig(&self.item_sig, item.signature)?;
+        self.contents.builder.append_data(&item.builder);
+        Ok(self)
+    }
+
+    pub(super) fn finish_array(self) -> Result<DoneSerializer> {
         let Self {
             prev: mut mesg,
             contents,
             mut item_sig,
         } = self;
+        let item_alignment = alignment(*item_sig.first().ok_or(Error::SignatureExhausted)?)?;
 
         // Get signature correct
         mesg.signature.push(b'a');
@@ -196,10 +360,13 @@ impl ReadyArraySerializer {
 
         mesg.builder.align(4);
         let token = mesg.builder.start_length();
+        // The padding to the first item is required even if there are
+        // no items, and is not counted in the length.
+        mesg.builder.align(item_alignment);
         mesg.builder.append_data(&contents.builder);
         mesg.builder.finish_length(token);
 
-        DoneSerializer { mesg }
+        Ok(DoneSerializer::new(mesg))
     }
 }
 
@@ -208,15 +375,20 @@ pub(super) struct PendingArraySerializer {
     item_sig: Vec<u8>,
 }
 
+fn check_item_sig(item_sig: &[u8], sig: Vec<u8>) -> Result<()> {
+    if item_sig != sig.as_slice() {
+        return Err(Error::MismatchSignature(item_sig.to_vec(), sig));
+    }
+    Ok(())
+}
+
 impl PendingArraySerializer {
     pub(super) fn finish_item(self, item: DoneSerializer) -> Result<ReadyArraySerializer> {
         let mut children_mesg = item.extract();
         let mut sig = Vec::new();
         swap(&mut sig, &mut children_mesg.signature);
         let item_sig = self.item_sig;
-        if item_sig != sig {
-            return Err(Error::MismatchSignature(item_sig, sig));
-        }
+        check_item_sig(&item_sig, sig)?;
         Ok(ReadyArraySerializer {
             prev: self.prev,
             contents: children_mesg,
@@ -225,30 +397,279 @@ impl PendingArraySerializer {
     }
 }
 
+// An array whose item signature is taken from its items. Items are
+// written into the array as they come, for as long as they agree on a
+// signature. If one does not, the items so far are taken apart again
+// and each wrapped in a variant, as are all the items after it.
+pub(super) struct InferredArraySerializer {
+    prev: PendingMessage,
+
+    // As for `ReadyArraySerializer`, with the signature kept empty
+    contents: PendingMessage,
+    item_sig: Option<Vec<u8>>,
+
+    // Untyped empty arrays are only counted until an item with a
+    // signature comes, as they are written differently depending on it.
+    untyped: usize,
+    bounds: ItemBounds,
+}
+
+// Where the items in an inferred array start, to take them apart again.
+enum ItemBounds {
+    // Any item fits in a variant, so these are never taken apart.
+    Variants,
+
+    // Fixed-size items all go in one alignment slice, one after another.
+    Fixed {
+        first: Mark,
+        stride: usize,
+        count: usize,
+    },
+
+    Marked(Vec<Mark>),
+}
+
+impl ItemBounds {
+    fn new(item_sig: &[u8], first: Mark) -> Result<ItemBounds> {
+        if item_sig == b"v" {
+            return Ok(ItemBounds::Variants);
+        }
+        // Unix fds are written as separate message components.
+        let size = match Type::parse(item_sig) {
+            Ok(item_type) if !item_sig.contains(&b'h') => item_type.fixed_size(),
+            _ => None,
+        };
+        Ok(match size {
+            Some(size) => ItemBounds::Fixed {
+                first,
+                stride: align(size, alignment(item_sig[0])?),
+                count: 0,
+            },
+            None => ItemBounds::Marked(Vec::new()),
+        })
+    }
+
+    fn push(&mut self, mark: Mark) {
+        match self {
+            ItemBounds::Variants => {}
+            ItemBounds::Fixed { count, .. } => *count += 1,
+            ItemBounds::Marked(marks) => marks.push(mark),
+        }
+    }
+
+    fn into_marks(self) -> Vec<Mark> {
+        match self {
+            ItemBounds::Variants => Vec::new(),
+            ItemBounds::Fixed {
+                first,
+                stride,
+                count,
+            } => (0..count).map(|ix| first.advance(ix * stride)).collect(),
+            ItemBounds::Marked(marks) => marks,
+        }
+    }
+}
+
+impl InferredArraySerializer {
+    pub(super) fn start_item(self) -> (PendingInferredArraySerializer, ReadySerializer) {
+        let Self {
+            prev,
+            mut contents,
+            item_sig,
+            untyped,
+            bounds,
+        } = self;
+        let pending = PendingInferredArraySerializer {
+            prev,
+            item_sig,
+            untyped,
+            bounds,
+            mark: contents.builder.mark(),
+        };
+        (pending, ReadySerializer { mesg: contents })
+    }
+
+    pub(super) fn finish_array(mut self) -> Result<DoneSerializer> {
+        let item_sig = match self.item_sig {
+            Some(item_sig) => item_sig,
+            None if self.untyped == 0 => {
+                // With no items, there is nothing to infer from.
+                let array = ReadySerializer { mesg: self.prev }.start_array(vec![b'v']);
+                return Ok(array.finish_array()?.mark_untyped_empty());
+            }
+            None => {
+                for _ in 0..self.untyped {
+                    self.contents = append_empty_array(self.contents, b"v")?;
+                }
+                b"av".to_vec()
+            }
+        };
+        ReadyArraySerializer {
+            prev: self.prev,
+            contents: self.contents,
+            item_sig,
+        }
+        .finish_array()
+    }
+
+    // The first item with a signature, which the untyped empty arrays
+    // before it must agree with.
+    fn first_typed_item(&mut self, mark: Mark, sig: Vec<u8>) -> Result<()> {
+        if self.untyped == 0 {
+            self.bounds = ItemBounds::new(&sig, mark)?;
+            self.bounds.push(mark);
+        } else {
+            let item = self.contents.builder.split_off(mark, alignment(sig[0])?);
+            self.bounds = ItemBounds::new(&sig, mark)?;
+            for _ in 0..take(&mut self.untyped) {
+                self.bounds.push(self.contents.builder.mark());
+                self.contents = append_empty_array(take(&mut self.contents), &sig[1..])?;
+            }
+            self.bounds.push(self.contents.builder.mark());
+            self.contents.builder.append_data(&item);
+        }
+        self.item_sig = Some(sig);
+        Ok(())
+    }
+
+    // Takes the items so far apart again, to wrap each in a variant, along
+    // with the item just written after the mark.
+    fn wrap_in_variants(&mut self, mark: Mark, sig: Vec<u8>) -> Result<()> {
+        let item_alignment = alignment(*sig.first().ok_or(Error::SignatureExhausted)?)?;
+        let item = self.contents.builder.split_off(mark, item_alignment);
+        let mut contents = match self.item_sig.replace(b"v".to_vec()) {
+            Some(item_sig) if item_sig == b"v" => take(&mut self.contents),
+            Some(item_sig) => {
+                let item_alignment = alignment(item_sig[0])?;
+                let marks = replace(&mut self.bounds, ItemBounds::Variants).into_marks();
+                let mut items = Vec::with_capacity(marks.len());
+                for mark in marks.into_iter().rev() {
+                    items.push(self.contents.builder.split_off(mark, item_alignment));
+                }
+                let mut contents = PendingMessage::new();
+                for item in items.into_iter().rev() {
+                    contents = append_variant(contents, item, item_sig.clone())?;
+                }
+                contents
+            }
+            None => {
+                let mut contents = PendingMessage::new();
+                for _ in 0..take(&mut self.untyped) {
+                    let empty = append_empty_array(PendingMessage::new(), b"v")?;
+                    contents = append_variant(contents, empty.builder, b"av".to_vec())?;
+                }
+                contents
+            }
+        };
+        contents = append_variant(contents, item, sig)?;
+        self.contents = contents;
+        Ok(())
+    }
+}
+
+pub(super) struct PendingInferredArraySerializer {
+    prev: PendingMessage,
+    item_sig: Option<Vec<u8>>,
+    untyped: usize,
+    bounds: ItemBounds,
+
+    // Where the item is started in the contents
+    mark: Mark,
+}
+
+impl PendingInferredArraySerializer {
+    pub(super) fn finish_item(self, item: DoneSerializer) -> Result<InferredArraySerializer> {
+        let untyped_empty = item.is_untyped_empty();
+        let mut contents = item.extract();
+        let sig = take(&mut contents.signature);
+        let mark = self.mark;
+        let mut ser = InferredArraySerializer {
+            prev: self.prev,
+            contents,
+            item_sig: self.item_sig,
+            untyped: self.untyped,
+            bounds: self.bounds,
+        };
+
+        match ser.item_sig.as_deref() {
+            Some(item_sig) if item_sig == sig => ser.bounds.push(mark),
+            Some(item_sig) if untyped_empty && item_sig[0] == b'a' => {
+                // Written again, as an empty array of the item signature
+                ser.contents.builder.split_off(mark, 4);
+                ser.contents = append_empty_array(take(&mut ser.contents), &item_sig[1..])?;
+                ser.bounds.push(mark);
+            }
+            None if untyped_empty => {
+                ser.contents.builder.split_off(mark, 4);
+                ser.untyped += 1;
+            }
+            None if ser.untyped == 0 || sig.first() == Some(&b'a') => {
+                ser.first_typed_item(mark, sig)?;
+            }
+            _ => ser.wrap_in_variants(mark, sig)?,
+        }
+        Ok(ser)
+    }
+}
+
+// Writes an empty array into the contents of another array.
+fn append_empty_array(contents: PendingMessage, item_sig: &[u8]) -> Result<PendingMessage> {
+    let array = ReadySerializer { mesg: contents }.start_array(item_sig.to_vec());
+    let mut contents = array.finish_array()?.extract();
+    contents.signature.clear();
+    Ok(contents)
+}
+
+// Writes a value that was split off into the contents of an array, in a
+// variant unless it already is one.
+fn append_variant(
+    mut contents: PendingMessage,
+    builder: MessageBuilder,
+    signature: Vec<u8>,
+) -> Result<PendingMessage> {
+    if signature == b"v" {
+        contents.builder.append_data(&builder);
+        return Ok(contents);
+    }
+    let (var_ser, _) = ReadySerializer { mesg: contents }.start_variant();
+    let value = DoneSerializer::new(PendingMessage { builder, signature });
+    let mut contents = var_ser.finish_variant(value)?.extract();
+    contents.signature.clear();
+    Ok(contents)
+}
+
 // For standard dicts-of-variants i.e. a{sv}, with cancellation and optional dropping of {}
 pub(super) struct ReadyDictSerializer {
     ser: ReadyArraySerializer,
+    variant_values: bool,
 }
 
 impl ReadyDictSerializer {
     pub(super) fn start_item(self) -> (PendingDictSerializer, ReadySerializer) {
-        let dict = PendingDictSerializer { ser: self.ser };
+        let dict = PendingDictSerializer {
+            ser: self.ser,
+            variant_values: self.variant_values,
+        };
         let ready = ReadySerializer::new();
         (dict, ready)
     }
 
-    pub(super) fn finish_dict(self) -> DoneSerializer {
+    pub(super) fn finish_dict(self) -> Result<DoneSerializer> {
         self.ser.finish_array()
     }
 }
 
 pub(super) struct PendingDictSerializer {
     ser: ReadyArraySerializer,
+    variant_values: bool,
 }
 
 impl PendingDictSerializer {
     pub(super) fn cancel_item(self) -> ReadyDictSerializer {
-        ReadyDictSerializer { ser: self.ser }
+        ReadyDictSerializer {
+            ser: self.ser,
+            variant_values: self.variant_values,
+        }
     }
 
     pub(super) fn finish_optional_item(
@@ -256,7 +677,8 @@ impl PendingDictSerializer {
         name: &str,
         value: DoneSerializer,
     ) -> Result<ReadyDictSerializer> {
-        if value.mesg.signature == [b'(', b')'] {
+        // Units serialize as nothing, and empty structs as `()`.
+        if matches!(value.mesg.signature[..], [] | [b'(', b')']) {
             Ok(self.cancel_item())
         } else {
             self.finish_item(name, value)
@@ -274,13 +696,21 @@ impl PendingDictSerializer {
         let (kv_ser, str_ser) = kv_ser.start_item();
         let str_ser = str_ser.serialize_primitive(&name)?;
         let kv_ser = kv_ser.finish_item(str_ser);
-        let (kv_ser, var_ser) = kv_ser.start_item();
-        let (var_ser, _) = var_ser.start_variant();
-        let var_ser = var_ser.finish_variant(value); // This relies on the implementation detail that variant DoneSerializers are interchangeable
-        let kv_ser = kv_ser.finish_item(var_ser);
-        let kv_ser = kv_ser.finish_kv_pair();
+        // Values that are already variants are not wrapped again.
+        let kv_ser = if self.variant_values && value.signature() != b"v" {
+            let (kv_ser, var_ser) = kv_ser.start_item();
+            let (var_ser, _) = var_ser.start_variant();
+            let var_ser = var_ser.finish_variant(value)?; // This relies on the implementation detail that variant DoneSerializers are interchangeable
+            kv_ser.finish_item(var_ser)
+        } else {
+            kv_ser.append_item(value)
+        };
+        let kv_ser = kv_ser.finish_kv_pair()?;
         let ser = ser.finish_item(kv_ser)?;
-        Ok(ReadyDictSerializer { ser })
+        Ok(ReadyDictSerializer {
+            ser,
+            variant_values: self.variant_values,
+        })
     }
 }
 
@@ -288,7 +718,7 @@ impl PendingDictSerializer {
 mod tests {
     use super::ReadySerializer;
     use crate::error::Result;
-    use crate::message::Message;
+    use crate::message::{Endianness, Message};
 
     #[test]
     fn serialize_int() -> Result<()> {
@@ -300,6 +730,8 @@ mod tests {
         let correct_message = Message {
             data: vec![37, 0, 0, 0],
             signature: "i".as_bytes().to_vec(),
+            fds: vec![],
+            endianness: Endianness::Little,
         };
         assert_eq!(
             correct_message, message,
@@ -314,12 +746,14 @@ mod tests {
         let serializer = ReadySerializer::new();
         let (serializer, sub_serializer) = serializer.start_variant();
         let sub_serializer = sub_serializer.serialize_primitive(&i)?;
-        let serializer = serializer.finish_variant(sub_serializer);
+        let serializer = serializer.finish_variant(sub_serializer)?;
         let message = serializer.complete()?;
 
         let correct_message = Message {
             data: vec![1, 105, 0, 0, 37, 0, 0, 0],
             signature: "v".as_bytes().to_vec(),
+            fds: vec![],
+            endianness: Endianness::Little,
         };
         assert_eq!(
             correct_message, message,
@@ -350,8 +784,8 @@ mod tests {
         let sub_serializer = sub_serializer.serialize_primitive(&4.0)?;
         let serializer = serializer.finish_item(sub_serializer)?;
 
-        let serializer = serializer.finish_array();
-        let top_level_serializer = top_level_serializer.finish_variant(serializer);
+        let serializer = serializer.finish_array()?;
+        let top_level_serializer = top_level_serializer.finish_variant(serializer)?;
         let message = top_level_serializer.complete()?;
 
         let correct_message = Message {
@@ -360,6 +794,8 @@ mod tests {
                 0, 0, 0, 0, 0, 8, 64, 0, 0, 0, 0, 0, 0, 16, 64,
             ],
             signature: "v".as_bytes().to_vec(),
+            fds: vec![],
+            endianness: Endianness::Little,
         };
         assert_eq!(
             correct_message, message,
@@ -389,7 +825,7 @@ mod tests {
         let sub_serializer = sub_serializer.serialize_primitive(&4)?;
         let serializer = serializer.finish_item(sub_serializer)?;
 
-        let serializer = serializer.finish_array();
+        let serializer = serializer.finish_array()?;
         let message = serializer.complete()?;
 
         let correct_message = Message {
@@ -398,6 +834,8 @@ mod tests {
                 4u8, 0u8, 0u8, 0u8,
             ],
             signature: "ai".as_bytes().to_vec(),
+            fds: vec![],
+            endianness: Endianness::Little,
         };
         assert_eq!(
             correct_message, message,
@@ -406,6 +844,48 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn serialize_empty_struct_array() -> Result<()> {
+        let serializer = ReadySerializer::new();
+        let serializer = serializer.start_array("(ss)".as_bytes().to_vec());
+        let serializer = serializer.finish_array()?;
+        let message = serializer.complete()?;
+
+        let correct_message = Message {
+            data: vec![
+                0, 0, 0, 0, // empty array
+                0, 0, 0, 0, // padding(8) to where the first item would be
+            ],
+            signature: "a(ss)".as_bytes().to_vec(),
+            fds: vec![],
+            endianness: Endianness::Little,
+        };
+        assert_eq!(
+            correct_message, message,
+            "empty array message serialized incorrectly"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn serialize_byte_array() -> Result<()> {
+        let serializer = ReadySerializer::new();
+        let serializer = serializer.serialize_byte_array(&[1, 2, 0, 255]);
+        let message = serializer.complete()?;
+
+        let correct_message = Message {
+            data: vec![4, 0, 0, 0, 1, 2, 0, 255],
+            signature: "ay".as_bytes().to_vec(),
+            fds: vec![],
+            endianness: Endianness::Little,
+        };
+        assert_eq!(
+            correct_message, message,
+            "byte array message serialized incorrectly"
+        );
+        Ok(())
+    }
+
     #[test]
     fn serialize_struct() -> Result<()> {
         let serializer = ReadySerializer::new();
@@ -444,6 +924,8 @@ mod tests {
                 0u8, 0u8, 0u8, 0u8, 0u8, 154u8, 153u8, 153u8, 153u8, 153u8, 153u8, 32u8, 64u8,
             ],
             signature: "(sd(sd))".as_bytes().to_vec(),
+            fds: vec![],
+            endianness: Endianness::Little,
         };
         assert_eq!(
             correct_message, message,
@@ -485,7 +967,7 @@ mod tests {
         let item = item.finish_struct();
         let serializer = serializer.finish_optional_item("d", item)?;
 
-        let serializer = serializer.finish_dict();
+        let serializer = serializer.finish_dict()?;
         let message = serializer.complete()?;
 
         let correct_message = Message {
@@ -499,6 +981,8 @@ mod tests {
                 153u8, 153u8, 153u8, 153u8, 32u8, 64u8,
             ],
             signature: "a{sv}".as_bytes().to_vec(),
+            fds: vec![],
+            endianness: Endianness::Little,
         };
         assert_eq!(
             correct_message, message,
@@ -1,8 +1,16 @@
-use std::cmp::max;
+use std::cmp::{max, min};
 use std::collections::BTreeMap;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, OwnedFd};
 use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(unix)]
+use std::sync::Arc;
 
 use crate::align::align;
+use crate::error::{Error, Result};
+#[cfg(unix)]
+use crate::message::RawFd;
+use crate::message::{MessageFd, MAX_ARRAY_LEN};
 
 pub(super) struct PendingMessage {
     pub builder: MessageBuilder,
@@ -53,11 +61,44 @@ impl LengthToken {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+// A point in a builder, that what is built after it can be split off at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) struct Mark {
+    component: usize,
+    offset: usize,
+
+    // The alignment of the slice when it was marked, which a value
+    // started at the mark may have raised if the slice was empty.
+    alignment: usize,
+}
+
+impl Mark {
+    // The same slice, a number of bytes further on
+    pub(super) fn advance(self, bytes: usize) -> Mark {
+        Mark {
+            offset: self.offset + bytes,
+            ..self
+        }
+    }
+}
+
+// A duplicate of a Unix fd that was serialized, along with the number of
+// the fd it duplicates, by which fds serialized more than once are
+// recognized. That fd is open until the message is completed.
+#[cfg(unix)]
+#[derive(Debug, Clone)]
+struct SentFd {
+    key: RawFd,
+    fd: Arc<OwnedFd>,
+}
+
+#[derive(Debug, Clone)]
 enum MessageComponent {
     AlignmentSlice(AlignmentSlice),
     LengthBegin(LengthToken),
     LengthEnd(LengthToken),
+    #[cfg(unix)]
+    UnixFd(SentFd),
 }
 
 impl MessageBuilder {
@@ -95,6 +136,23 @@ impl MessageBuilder {
             }));
     }
 
+    // The index of a Unix fd is only known once the whole message is
+    // put together, as its items may be serialized separately. `fd` is
+    // a duplicate of the fd numbered `key`.
+    #[cfg(unix)]
+    pub(super) fn write_unix_fd(&mut self, key: RawFd, fd: OwnedFd) {
+        self.align(4);
+        self.data.push(MessageComponent::UnixFd(SentFd {
+            key,
+            fd: Arc::new(fd),
+        }));
+        self.data
+            .push(MessageComponent::AlignmentSlice(AlignmentSlice {
+                alignment: 1,
+                data: Vec::new(),
+            }));
+    }
+
     // Note: alignment must be power of 2
     pub(super) fn align(&mut self, alignment: usize) {
         {
@@ -146,6 +204,55 @@ impl MessageBuilder {
         }
     }
 
+    // Like `append_data`, for a value serialized before this message
+    // was started. The fds it duplicated may have been closed since, and
+    // their numbers reused, so its fds are recognized by the numbers of
+    // the duplicates instead.
+    pub(super) fn append_copy(&mut self, other: &MessageBuilder) {
+        #[cfg(unix)]
+        let start = self.data.len();
+        self.append_data(other);
+        #[cfg(unix)]
+        for component in &mut self.data[start..] {
+            if let MessageComponent::UnixFd(sent) = component {
+                sent.key = sent.fd.as_raw_fd();
+            }
+        }
+    }
+
+    pub(super) fn mark(&mut self) -> Mark {
+        let component = self.data.len() - 1;
+        let top = self.top();
+        Mark {
+            component,
+            offset: top.data.len(),
+            alignment: top.alignment,
+        }
+    }
+
+    // Splits off everything built since the mark, for a value of the
+    // given alignment that was started there. The padding before the
+    // value is dropped, so that the value can be put somewhere else.
+    pub(super) fn split_off(&mut self, mark: Mark, alignment: usize) -> MessageBuilder {
+        let rest = self.data.split_off(mark.component + 1);
+        let MessageComponent::AlignmentSlice(slice) = &mut self.data[mark.component] else {
+            panic!("marks are always at an alignment slice");
+        };
+        let start = min(align(mark.offset, alignment), slice.data.len());
+        let data = slice.data.split_off(start);
+        slice.data.truncate(mark.offset);
+        if slice.data.is_empty() {
+            slice.alignment = mark.alignment;
+        }
+
+        let mut split = vec![MessageComponent::AlignmentSlice(AlignmentSlice {
+            alignment,
+            data,
+        })];
+        split.extend(rest);
+        MessageBuilder { data: split }
+    }
+
     // TODO: The interface of this function seems certainly wrong.
     // I'd like to replace it with something that adds a `&[u8]`, but
     // that is a task relatively low down on the ol' priority list.
@@ -166,8 +273,15 @@ impl MessageBuilder {
         }
     }
 
-    pub(super) fn complete(self) -> Vec<u8> {
+    // Returns the data, along with the Unix fds that it holds indices
+    // into. An fd that is serialized more than once is only sent once.
+    // Arrays longer than the specification allows are rejected here, as
+    // their padding is only known once all the data is in place.
+    pub(super) fn complete(self) -> Result<(Vec<u8>, Vec<MessageFd>)> {
         let mut output_data = Vec::new();
+        #[cfg(unix)]
+        let mut keys = Vec::new();
+        let mut fds = Vec::new();
 
         // This is for arrays we are currently in, where the length
         // must be backfilled after we've outputted the other data.
@@ -217,15 +331,30 @@ impl MessageBuilder {
                         .expect("length end found without matching length begin");
                     let end_ix = output_data.len();
                     let length = end_ix - begin_ix;
+                    if length > MAX_ARRAY_LEN {
+                        return Err(Error::ArrayTooLong(length, MAX_ARRAY_LEN, fill_ix));
+                    }
                     let length = length as u32;
                     let fill_in_range = &mut output_data[fill_ix..fill_ix + 4];
                     fill_in_range.copy_from_slice(&length.to_le_bytes());
                     lengths.remove(&token);
                     recent_length = None;
                 }
+                #[cfg(unix)]
+                MessageComponent::UnixFd(SentFd { key, fd }) => {
+                    let index = match keys.iter().position(|other| *other == key) {
+                        Some(index) => index,
+                        None => {
+                            keys.push(key);
+                            fds.push(MessageFd::Owned(fd));
+                            fds.len() - 1
+                        }
+                    };
+                    output_data.extend_from_slice(&(index as u32).to_le_bytes());
+                }
             }
         }
 
-        output_data
+        Ok((output_data, fds))
     }
 }
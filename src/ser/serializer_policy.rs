@@ -1,10 +1,351 @@
+use super::internal::{DoneSerializer, ReadySerializer};
+use super::Serializer;
+use crate::error::Result;
+use crate::message::{Endianness, Message};
+
+use serde::Serialize;
+use std::borrow::Cow;
+use std::fmt::{self, Debug, Formatter};
+use std::rc::Rc;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum StructSerializationStyle {
     StronglyTyped,
     Dict,
 }
 
+/// How the items of a sequence are serialized.
+pub enum SeqSerializationStyle {
+    /// Wrap every item in a variant, giving an array signature of `av`.
+    Variant,
+
+    /// Serialize every item directly, giving an array signature of `a`
+    /// followed by the provided item signature. Every item is serialized
+    /// to fit this signature, as with [`serialize_with_signature`], and
+    /// empty arrays use it as well.
+    ///
+    /// [`serialize_with_signature`]: crate::ser::serialize_with_signature
+    Typed(Vec<u8>),
+
+    /// Serialize every item, and if they all have the same signature,
+    /// serialize them directly with that item signature. Otherwise, fall
+    /// back to wrapping every item in a variant, as with `Variant`.
+    /// Empty sequences are serialized as `av`, unless they are items in a
+    /// sequence of arrays with a known item signature.
+    Inferred,
+}
+
+/// How the entries of a map are serialized. Maps are always serialized
+/// as arrays of dict entries, with a key signature and a value signature.
+pub enum MapSerializationStyle {
+    /// Wrap every value in a variant, giving a signature of `a{Kv}`.
+    /// The key signature is taken from the keys, which must agree, and
+    /// is `s` for empty maps.
+    Variant,
+
+    /// Serialize every key and value directly with the provided key
+    /// and value signatures, in that order. If the value signature is
+    /// `v`, values are wrapped in variants.
+    Typed(Vec<u8>, Vec<u8>),
+
+    /// Take the key signature from the keys, as with `Variant`, and
+    /// infer the value signature from the values as with
+    /// [`SeqSerializationStyle::Inferred`], falling back to variants.
+    /// Empty maps are serialized as `a{sv}`.
+    Inferred,
+}
+
+/// How `Option`s are serialized. The deserializer must be told the same
+/// style, with [`DeserializerOptions`].
+///
+/// [`DeserializerOptions`]: crate::de::DeserializerOptions
+#[derive(Clone, Debug, Default)]
+pub enum OptionSerializationStyle {
+    /// Serialize `Some` as its value, and leave `None` out of dicts.
+    /// DBus has nothing to serialize `None` as elsewhere, so it fails
+    /// with [`Error::NoneOutsideDict`].
+    ///
+    /// [`Error::NoneOutsideDict`]: crate::error::Error::NoneOutsideDict
+    #[default]
+    Omit,
+
+    /// Serialize as an array of zero or one items. The item signature
+    /// of an empty array is taken from the target signature, or from
+    /// sibling arrays as with [`SeqSerializationStyle::Inferred`], and
+    /// is `v` otherwise.
+    Array,
+
+    /// Serialize `Some` as a variant holding the value, and `None` as a
+    /// variant with an empty signature. Empty variants are not valid
+    /// DBus, so `None` fails with [`Error::EmptyVariant`] unless
+    /// [`SerializerPolicy::allow_empty_variants`] is set, for peers that
+    /// expect them.
+    ///
+    /// [`Error::EmptyVariant`]: crate::error::Error::EmptyVariant
+    Variant,
+
+    /// Serialize `Some` as its value, and `None` as the given value. A
+    /// value that is byte-for-byte the same as the default deserializes
+    /// as `None`.
+    Default(OptionDefault),
+}
+
+/// A value to serialize in place of `None`, for
+/// [`OptionSerializationStyle::Default`].
+#[derive(Clone)]
+pub struct OptionDefault {
+    value: Rc<DoneSerializer>,
+    message: Message,
+}
+
+impl OptionDefault {
+    /// Serializes `value` with the default policy, to be used in place
+    /// of `None`.
+    pub fn new<T: Serialize + ?Sized>(value: &T) -> Result<Self> {
+        let serialize = || {
+            value.serialize(Serializer::new(
+                ReadySerializer::new(),
+                DefaultSerializerPolicy,
+            ))
+        };
+        Ok(Self {
+            value: Rc::new(serialize()?),
+            message: serialize()?.complete()?,
+        })
+    }
+
+    pub(super) fn value(&self) -> &DoneSerializer {
+        &self.value
+    }
+
+    pub(crate) fn message(&self) -> &Message {
+        &self.message
+    }
+}
+
+impl Debug for OptionDefault {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("OptionDefault").field(&self.message).finish()
+    }
+}
+
+/// How the variants of an enum are serialized. With the dict styles,
+/// unit variants are serialized as just their index or name, so an enum
+/// with both unit and other variants does not have a single signature.
+/// With the tagged struct styles, all variants have the same signature.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum EnumSerializationStyle {
+    /// Serialize unit variants as their index, as `u`, and other
+    /// variants as a dict with a single entry, from the variant's name
+    /// to its value, as `a{sv}`.
+    #[default]
+    Index,
+
+    /// Serialize unit variants as their name, as `s`, and other
+    /// variants as with `Index`.
+    Name,
+
+    /// Serialize variants as a struct of the variant's name and its
+    /// value, as `(sv)`. Unit variants have an empty `a{sv}` as their
+    /// value.
+    NameTaggedStruct,
+
+    /// Serialize variants as a struct of the variant's index and its
+    /// value, as `(uv)`. Unit variants have an empty `a{sv}` as their
+    /// value.
+    IndexTaggedStruct,
+}
+
+impl EnumSerializationStyle {
+    /// Whether variants are identified by name, rather than by index.
+    pub fn tags_by_name(self) -> bool {
+        matches!(
+            self,
+            EnumSerializationStyle::Name | EnumSerializationStyle::NameTaggedStruct
+        )
+    }
+}
+
+/// What to do with a string that contains a nul, which DBus strings
+/// cannot.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum InteriorNulStyle {
+    /// Fail with [`Error::InteriorNul`].
+    ///
+    /// [`Error::InteriorNul`]: crate::error::Error::InteriorNul
+    #[default]
+    Error,
+
+    /// Remove the nuls, and serialize the rest of the string.
+    Strip,
+}
+
+/// The struct field that a value is being serialized for, if any.
+///
+/// This is passed to policy queries so that they can make their
+/// decisions field-by-field.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FieldContext {
+    pub struct_name: &'static str,
+    pub field_name: &'static str,
+}
+
+/// How a single struct field is serialized, as decided by
+/// [`SerializerPolicy::query_field`]. The default leaves the field to
+/// the rest of the policy.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FieldSerializationStyle {
+    /// The key for the field when its struct is serialized as a dict,
+    /// in place of the field's name.
+    pub rename: Option<String>,
+
+    /// Leave the field out, whether its struct is serialized as a dict
+    /// or as a struct.
+    pub skip: bool,
+
+    /// Serialize the field's value to fit this signature, as with
+    /// [`serialize_with_signature`]. With `v`, the value is wrapped in
+    /// a variant, and inside dicts it is then not wrapped a second time.
+    /// This is ignored where a signature passed to
+    /// [`serialize_with_signature`] already decides the field's type.
+    ///
+    /// [`serialize_with_signature`]: super::serialize_with_signature
+    pub signature: Option<String>,
+
+    /// If the field's value is a struct, serialize it in this style,
+    /// rather than in the style [`SerializerPolicy::query_struct_name`]
+    /// gives for its name.
+    pub struct_style: Option<StructSerializationStyle>,
+}
+
+/// The naming convention for the dict keys of a struct serialized as a
+/// dict, converted from its snake case field names. The deserializer
+/// must be told the same convention, with [`DeserializerOptions`].
+///
+/// [`DeserializerOptions`]: crate::de::DeserializerOptions
+#[derive(Clone, Copy, Debug, Default)]
+pub enum FieldNameCase {
+    /// Use the field names as they are, as in `max_retries`.
+    #[default]
+    Snake,
+
+    /// As in `maxRetries`.
+    Camel,
+
+    /// As in `MaxRetries`, the convention for DBus properties.
+    Pascal,
+
+    /// As in `max-retries`.
+    Kebab,
+
+    /// Convert field names with the given function.
+    Custom(fn(&str) -> String),
+}
+
+impl FieldNameCase {
+    /// The dict key for the field named `name`.
+    pub fn convert<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        let words = name.split('_').filter(|word| !word.is_empty());
+        match self {
+            FieldNameCase::Snake => Cow::Borrowed(name),
+            FieldNameCase::Camel => words
+                .enumerate()
+                .map(|(ix, word)| {
+                    if ix == 0 {
+                        word.into()
+                    } else {
+                        capitalize(word)
+                    }
+                })
+                .collect::<String>()
+                .into(),
+            FieldNameCase::Pascal => words.map(capitalize).collect::<String>().into(),
+            FieldNameCase::Kebab => Cow::Owned(words.collect::<Vec<_>>().join("-")),
+            FieldNameCase::Custom(convert) => Cow::Owned(convert(name)),
+        }
+    }
+}
+
+fn capitalize(word: &str) -> Cow<'_, str> {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => Cow::Borrowed(word),
+    }
+}
+
 pub trait SerializerPolicy: Clone {
     fn query_struct_name(&self, name: &str) -> StructSerializationStyle;
+
+    /// Decides how to serialize a sequence. `field` is the struct field
+    /// the sequence is the value of, or `None` if the sequence is not
+    /// directly the value of a struct field.
+    fn query_seq(&self, _field: Option<&FieldContext>) -> SeqSerializationStyle {
+        SeqSerializationStyle::Inferred
+    }
+
+    /// Decides how to serialize a map, with `field` as in `query_seq`.
+    fn query_map(&self, _field: Option<&FieldContext>) -> MapSerializationStyle {
+        MapSerializationStyle::Inferred
+    }
+
+    /// Decides the naming convention for the dict keys of the struct
+    /// named `name`, when it is serialized as a dict, or of the struct
+    /// variants of the enum named `name`. Keys renamed by
+    /// [`query_field`](SerializerPolicy::query_field) are used as given.
+    fn query_field_case(&self, _name: &str) -> FieldNameCase {
+        FieldNameCase::Snake
+    }
+
+    /// Decides how to serialize the variants of the enum named `name`.
+    fn query_enum(&self, _name: &str) -> EnumSerializationStyle {
+        EnumSerializationStyle::Index
+    }
+
+    /// Decides how to serialize an `Option`, with `field` as in `query_seq`.
+    fn query_option(&self, _field: Option<&FieldContext>) -> OptionSerializationStyle {
+        OptionSerializationStyle::Omit
+    }
+
+    /// Whether `None` may be serialized as a variant with an empty
+    /// signature, for [`OptionSerializationStyle::Variant`]. The
+    /// resulting messages are not valid DBus, and the deserializer only
+    /// accepts them with [`DeserializerOptions::allow_empty_variants`].
+    ///
+    /// [`DeserializerOptions::allow_empty_variants`]: crate::de::DeserializerOptions::allow_empty_variants
+    fn allow_empty_variants(&self) -> bool {
+        false
+    }
+
+    /// Decides what to do with a string containing a nul, with `field` as
+    /// in `query_seq`. This is only consulted for such strings. Names
+    /// of fields and enum variants are never sanitized.
+    fn query_interior_nul(&self, _field: Option<&FieldContext>) -> InteriorNulStyle {
+        InteriorNulStyle::Error
+    }
+
+    /// Decides how to serialize a single struct field. `parent_path` is
+    /// the names of the fields leading from the value being serialized
+    /// to the struct that `field` belongs to, passing through any
+    /// sequences, maps, options and enum variants along the way, so that
+    /// it is empty for the fields of the outermost struct.
+    ///
+    /// This is only consulted by the serializer, so signatures from
+    /// [`DbusType`] do not reflect its decisions.
+    ///
+    /// [`DbusType`]: crate::dbus_type::DbusType
+    fn query_field(
+        &self,
+        _field: &FieldContext,
+        _parent_path: &[&'static str],
+    ) -> FieldSerializationStyle {
+        FieldSerializationStyle::default()
+    }
+
+    /// Decides the byte order of the serialized message.
+    fn endianness(&self) -> Endianness {
+        Endianness::Little
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -0,0 +1,190 @@
+use crate::error::{Error, Result};
+use crate::signature::{self, single_type_end};
+use crate::types::{ObjectPath, Signature};
+
+use super::internal::{DoneSerializer, ReadySerializer};
+
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+// A single complete type within the signature passed to
+// `serialize_with_signature`, which the value at this position is
+// being serialized to fit.
+#[derive(Clone, Debug)]
+pub(super) struct Target {
+    sig: Rc<[u8]>,
+    start: usize,
+    end: usize,
+
+    // The length of the `(` put in front of a signature with several
+    // complete types, which positions in errors leave out.
+    prefix: usize,
+}
+
+impl Target {
+    // A signature with several complete types, such as a method's
+    // arguments, is treated as a struct of them, as in `Message`.
+    pub(super) fn new(sig: &[u8]) -> Result<Self> {
+        signature::validate(sig)?;
+        let mut end = 0;
+        let mut count = 0;
+        while end < sig.len() {
+            end = single_type_end(sig, end)?;
+            count += 1;
+        }
+        let (sig, prefix): (Rc<[u8]>, _) = match count {
+            0 => return Err(Error::SignatureExhausted),
+            1 => (sig.into(), 0),
+            _ => ([&b"("[..], sig, &b")"[..]].concat().into(), 1),
+        };
+        let end = sig.len();
+        Ok(Self {
+            sig,
+            start: 0,
+            end,
+            prefix,
+        })
+    }
+
+    fn at(&self, start: usize) -> Result<Self> {
+        let end = single_type_end(&self.sig, start)?;
+        Ok(Self {
+            sig: self.sig.clone(),
+            start,
+            end,
+            prefix: self.prefix,
+        })
+    }
+
+    // The position of this type in the signature as it was given
+    fn position(&self) -> usize {
+        self.start.saturating_sub(self.prefix)
+    }
+
+    pub(super) fn signature(&self) -> &[u8] {
+        &self.sig[self.start..self.end]
+    }
+
+    pub(super) fn is_variant(&self) -> bool {
+        self.signature() == b"v"
+    }
+
+    pub(super) fn mismatch(&self, got: &[u8]) -> Error {
+        Error::TargetSignatureMismatch(self.signature().to_vec(), self.position(), got.to_vec())
+    }
+
+    // Checks the signature of the value serialized from `sig_start` on.
+    pub(super) fn check(&self, done: &DoneSerializer, sig_start: usize) -> Result<()> {
+        let sig = &done.signature()[sig_start..];
+        if sig != self.signature() {
+            return Err(self.mismatch(sig));
+        }
+        Ok(())
+    }
+
+    // For an array signature, the item signature
+    pub(super) fn array_item(&self, got: &[u8]) -> Result<Self> {
+        if self.signature()[0] != b'a' {
+            return Err(self.mismatch(got));
+        }
+        self.at(self.start + 1)
+    }
+
+    // For a signature of an array of dict entries, the key and value signatures
+    pub(super) fn dict_entry(&self, got: &[u8]) -> Result<(Self, Self)> {
+        let item = self.array_item(got)?;
+        if item.signature()[0] != b'{' {
+            return Err(self.mismatch(got));
+        }
+        let key = self.at(item.start + 1)?;
+        let value = self.at(key.end)?;
+        Ok((key, value))
+    }
+
+    // For a struct signature, the signatures of the fields in order
+    pub(super) fn struct_fields(&self, got: &[u8]) -> Result<Vec<Self>> {
+        if self.signature()[0] != b'(' {
+            return Err(self.mismatch(got));
+        }
+        let mut fields = Vec::new();
+        let mut ix = self.start + 1;
+        while ix < self.end - 1 {
+            let field = self.at(ix)?;
+            ix = field.end;
+            fields.push(field);
+        }
+        Ok(fields)
+    }
+
+    pub(super) fn fields_exhausted(&self) -> Error {
+        Error::TargetFieldsExhausted(self.signature().to_vec(), self.position())
+    }
+
+    pub(super) fn serialize_integer(
+        &self,
+        ser: ReadySerializer,
+        val: i128,
+        got: u8,
+    ) -> Result<DoneSerializer> {
+        let code = self.signature()[0];
+        let out_of_range = |_| Error::TargetValueOutOfRange(val.to_string(), code, self.position());
+        match code {
+            b'y' => ser.serialize_primitive(&u8::try_from(val).map_err(out_of_range)?),
+            b'n' => ser.serialize_primitive(&i16::try_from(val).map_err(out_of_range)?),
+            b'q' => ser.serialize_primitive(&u16::try_from(val).map_err(out_of_range)?),
+            b'i' => ser.serialize_primitive(&i32::try_from(val).map_err(out_of_range)?),
+            b'u' => ser.serialize_primitive(&u32::try_from(val).map_err(out_of_range)?),
+            b'x' => ser.serialize_primitive(&i64::try_from(val).map_err(out_of_range)?),
+            b't' => ser.serialize_primitive(&u64::try_from(val).map_err(out_of_range)?),
+            b'd' => ser.serialize_primitive(&(val as f64)),
+            _ => Err(self.mismatch(&[got])),
+        }
+    }
+
+    pub(super) fn serialize_str(&self, ser: ReadySerializer, val: &str) -> Result<DoneSerializer> {
+        match self.signature()[0] {
+            b'o' => ser.serialize_primitive(&ObjectPath::new(val)?),
+            b'g' => ser.serialize_primitive(&Signature::new(val)?),
+            _ => ser.serialize_primitive(&val),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Target;
+    use crate::error::{Error, Result};
+
+    #[test]
+    fn target_structure() -> Result<()> {
+        let target = Target::new(b"sa{sa(ii)}v")?;
+        assert_eq!(target.signature(), b"(sa{sa(ii)}v)");
+
+        let fields = target.struct_fields(b"")?;
+        let sigs: Vec<&[u8]> = fields.iter().map(|field| field.signature()).collect();
+        assert_eq!(sigs, vec![&b"s"[..], b"a{sa(ii)}", b"v"]);
+
+        let (key, value) = fields[1].dict_entry(b"")?;
+        assert_eq!(key.signature(), b"s");
+        assert_eq!(value.signature(), b"a(ii)");
+        assert_eq!(value.array_item(b"")?.signature(), b"(ii)");
+        assert_eq!(value.array_item(b"")?.start, 6);
+        Ok(())
+    }
+
+    #[test]
+    fn target_invalid() {
+        assert_eq!(
+            Target::new(b"a{si").unwrap_err(),
+            Error::MismatchedSignatureBracketing(1)
+        );
+        assert_eq!(
+            Target::new(b"(ii").unwrap_err(),
+            Error::MismatchedSignatureBracketing(0)
+        );
+        assert_eq!(
+            Target::new(b"z").unwrap_err(),
+            Error::UnrecognizedSignatureCharacter(b'z')
+        );
+    }
+}
@@ -0,0 +1,449 @@
+//! DBus type signatures.
+//!
+//! A signature is a sequence of single complete types, such as `s`,
+//! `a{sv}` or `(ia(ss))`. [`validate`] checks one against the DBus
+//! specification: besides its syntax, it may be at most
+//! [`MAX_SIGNATURE_LEN`] bytes long, and nest at most
+//! [`MAX_ARRAY_DEPTH`] arrays and [`MAX_STRUCT_DEPTH`] structs, counting
+//! dict entries as structs. Dict entries may only be the items of
+//! arrays, and their keys must be basic types.
+//!
+//! [`parse`] does the same, returning each complete type as a [`Type`],
+//! which gives its alignment and, if it has one, its fixed size.
+//! [`complete_types`] iterates over the complete types without parsing
+//! them into trees.
+
+use crate::align::align;
+use crate::error::{Error, Result};
+
+use std::fmt::{self, Display, Formatter};
+
+/// The longest signature allowed, in bytes.
+pub const MAX_SIGNATURE_LEN: usize = 255;
+
+/// The deepest nesting of arrays allowed within a signature.
+pub const MAX_ARRAY_DEPTH: usize = 32;
+
+/// The deepest nesting of structs and dict entries allowed within a
+/// signature.
+pub const MAX_STRUCT_DEPTH: usize = 32;
+
+/// A single complete type.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Type {
+    Byte,
+    Bool,
+    Int16,
+    UInt16,
+    Int32,
+    UInt32,
+    Int64,
+    UInt64,
+    Double,
+    String,
+    ObjectPath,
+    Signature,
+    UnixFd,
+    Variant,
+    Array(Box<Type>),
+    DictEntry(Box<Type>, Box<Type>),
+    Struct(Vec<Type>),
+}
+
+impl Type {
+    /// Parses a signature that is exactly one complete type.
+    pub fn parse(sig: &[u8]) -> Result<Type> {
+        validate(sig)?;
+        if sig.is_empty() || single_type_end(sig, 0)? != sig.len() {
+            return Err(Error::NotSingleType(sig.to_vec()));
+        }
+        build(sig)
+    }
+
+    /// The type code this type's signature starts with.
+    pub fn code(&self) -> u8 {
+        match self {
+            Type::Byte => b'y',
+            Type::Bool => b'b',
+            Type::Int16 => b'n',
+            Type::UInt16 => b'q',
+            Type::Int32 => b'i',
+            Type::UInt32 => b'u',
+            Type::Int64 => b'x',
+            Type::UInt64 => b't',
+            Type::Double => b'd',
+            Type::String => b's',
+            Type::ObjectPath => b'o',
+            Type::Signature => b'g',
+            Type::UnixFd => b'h',
+            Type::Variant => b'v',
+            Type::Array(_) => b'a',
+            Type::DictEntry(_, _) => b'{',
+            Type::Struct(_) => b'(',
+        }
+    }
+
+    /// Whether this is a basic type, which can be a dict key.
+    pub fn is_basic(&self) -> bool {
+        is_basic(self.code())
+    }
+
+    /// The alignment of values of this type, in bytes.
+    pub fn alignment(&self) -> usize {
+        code_alignment(self.code()).expect("type code has an alignment")
+    }
+
+    /// The size of every value of this type, in bytes, if they are all
+    /// the same size. Values of container types are never fixed size,
+    /// except for structs and dict entries whose fields all are. Their
+    /// size does not count any padding after their last field.
+    pub fn fixed_size(&self) -> Option<usize> {
+        let fields = match self {
+            Type::Byte => return Some(1),
+            Type::Int16 | Type::UInt16 => return Some(2),
+            Type::Bool | Type::Int32 | Type::UInt32 | Type::UnixFd => return Some(4),
+            Type::Int64 | Type::UInt64 | Type::Double => return Some(8),
+            Type::String | Type::ObjectPath | Type::Signature | Type::Variant | Type::Array(_) => {
+                return None
+            }
+            Type::DictEntry(key, value) => vec![&**key, &**value],
+            Type::Struct(fields) => fields.iter().collect(),
+        };
+        fields.into_iter().try_fold(0, |size, field| {
+            Some(align(size, field.alignment()) + field.fixed_size()?)
+        })
+    }
+}
+
+/// Types display as their signature.
+impl Display for Type {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Array(item) => write!(f, "a{}", item),
+            Type::DictEntry(key, value) => write!(f, "{{{}{}}}", key, value),
+            Type::Struct(fields) => {
+                f.write_str("(")?;
+                for field in fields {
+                    write!(f, "{}", field)?;
+                }
+                f.write_str(")")
+            }
+            basic => write!(f, "{}", basic.code() as char),
+        }
+    }
+}
+
+/// Whether `code` is the type code of a basic type, which can be a dict
+/// key.
+pub fn is_basic(code: u8) -> bool {
+    matches!(
+        code,
+        b'y' | b'b' | b'n' | b'q' | b'i' | b'u' | b'x' | b't' | b'd' | b's' | b'o' | b'g' | b'h'
+    )
+}
+
+/// The alignment of values of the type starting with `code`, in bytes.
+pub fn alignment(code: u8) -> Result<usize> {
+    code_alignment(code).ok_or(Error::UnrecognizedSignatureCharacter(code))
+}
+
+fn code_alignment(code: u8) -> Option<usize> {
+    match code {
+        b'y' | b'g' | b'v' => Some(1),
+        b'n' | b'q' => Some(2),
+        b'b' | b'i' | b'u' | b's' | b'o' | b'a' | b'h' => Some(4),
+        b'x' | b't' | b'd' | b'(' | b'{' => Some(8),
+        _ => None,
+    }
+}
+
+/// Checks that `sig` is a valid signature, of any number of complete
+/// types.
+pub fn validate(sig: &[u8]) -> Result<()> {
+    if sig.len() > MAX_SIGNATURE_LEN {
+        return Err(Error::SignatureTooLong(sig.len()));
+    }
+    let mut ix = 0;
+    while ix < sig.len() {
+        ix = type_end(sig, ix, Nesting::STRICT)?;
+    }
+    Ok(())
+}
+
+/// Parses a valid signature into its complete types.
+pub fn parse(sig: &[u8]) -> Result<Vec<Type>> {
+    validate(sig)?;
+    complete_types(sig).map(|sig| build(sig?)).collect()
+}
+
+/// Iterates over the single complete types of `sig`, as slices of it.
+/// An invalid type gives an error, and ends the iteration. Only the
+/// syntax of each type is checked, not the limits that [`validate`]
+/// checks.
+pub fn complete_types(sig: &[u8]) -> CompleteTypes<'_> {
+    CompleteTypes { sig, ix: 0 }
+}
+
+/// The iterator returned by [`complete_types`].
+#[derive(Clone, Debug)]
+pub struct CompleteTypes<'a> {
+    sig: &'a [u8],
+    ix: usize,
+}
+
+impl<'a> Iterator for CompleteTypes<'a> {
+    type Item = Result<&'a [u8]>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.ix >= self.sig.len() {
+            return None;
+        }
+        let start = self.ix;
+        match single_type_end(self.sig, start) {
+            Ok(end) => {
+                self.ix = end;
+                Some(Ok(&self.sig[start..end]))
+            }
+            Err(err) => {
+                self.ix = self.sig.len();
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+// How deeply the type being walked is nested, and whether it is held to
+// the specification or only walked.
+#[derive(Clone, Copy)]
+struct Nesting {
+    arrays: usize,
+    structs: usize,
+    in_array: bool,
+    strict: bool,
+}
+
+impl Nesting {
+    const STRICT: Nesting = Nesting {
+        arrays: 0,
+        structs: 0,
+        in_array: false,
+        strict: true,
+    };
+}
+
+// Returns the index just past the single complete type starting at
+// `start`. Unlike `validate`, this accepts a dict entry on its own, such
+// as the item signature of an array being walked, and the empty struct.
+// It only checks nesting from `start`.
+pub(crate) fn single_type_end(sig: &[u8], start: usize) -> Result<usize> {
+    let nesting = Nesting {
+        strict: false,
+        ..Nesting::STRICT
+    };
+    type_end(sig, start, nesting)
+}
+
+fn type_end(sig: &[u8], start: usize, nesting: Nesting) -> Result<usize> {
+    let inner = Nesting {
+        in_array: false,
+        ..nesting
+    };
+    match sig.get(start) {
+        None => Err(Error::SignatureExhausted),
+        Some(b'a') => {
+            if nesting.arrays == MAX_ARRAY_DEPTH {
+                return Err(Error::ArrayNestingTooDeep(MAX_ARRAY_DEPTH + 1));
+            }
+            let item = Nesting {
+                arrays: nesting.arrays + 1,
+                in_array: true,
+                ..nesting
+            };
+            type_end(sig, start + 1, item)
+        }
+        Some(b'(' | b'{') if nesting.structs == MAX_STRUCT_DEPTH => {
+            Err(Error::StructNestingTooDeep(MAX_STRUCT_DEPTH + 1))
+        }
+        Some(b'(') => {
+            let field = Nesting {
+                structs: nesting.structs + 1,
+                ..inner
+            };
+            let mut ix = start + 1;
+            loop {
+                match sig.get(ix) {
+                    Some(b')') if ix > start + 1 || !nesting.strict => return Ok(ix + 1),
+                    None | Some(b')') => return Err(Error::MismatchedSignatureBracketing(start)),
+                    Some(_) => ix = type_end(sig, ix, field)?,
+                }
+            }
+        }
+        Some(b'{') => {
+            if nesting.strict && !nesting.in_array {
+                return Err(Error::DictEntryOutsideArray(start));
+            }
+            let field = Nesting {
+                structs: nesting.structs + 1,
+                ..inner
+            };
+            let key_end = type_end(sig, start + 1, field)?;
+            if key_end != start + 2 || !is_basic(sig[start + 1]) {
+                return Err(Error::NonBasicDictKey(sig[start + 1..key_end].to_vec()));
+            }
+            let value_end = type_end(sig, key_end, field)?;
+            match sig.get(value_end) {
+                Some(b'}') => Ok(value_end + 1),
+                _ => Err(Error::MismatchedSignatureBracketing(start)),
+            }
+        }
+        Some(&code) if is_basic(code) || code == b'v' => Ok(start + 1),
+        Some(&other) => Err(Error::UnrecognizedSignatureCharacter(other)),
+    }
+}
+
+// Builds the type tree of a single complete type that has been checked.
+fn build(sig: &[u8]) -> Result<Type> {
+    Ok(match sig[0] {
+        b'y' => Type::Byte,
+        b'b' => Type::Bool,
+        b'n' => Type::Int16,
+        b'q' => Type::UInt16,
+        b'i' => Type::Int32,
+        b'u' => Type::UInt32,
+        b'x' => Type::Int64,
+        b't' => Type::UInt64,
+        b'd' => Type::Double,
+        b's' => Type::String,
+        b'o' => Type::ObjectPath,
+        b'g' => Type::Signature,
+        b'h' => Type::UnixFd,
+        b'v' => Type::Variant,
+        b'a' => Type::Array(Box::new(build(&sig[1..])?)),
+        b'{' => Type::DictEntry(
+            Box::new(build(&sig[1..2])?),
+            Box::new(build(&sig[2..sig.len() - 1])?),
+        ),
+        b'(' => Type::Struct(
+            complete_types(&sig[1..sig.len() - 1])
+                .map(|field| build(field?))
+                .collect::<Result<_>>()?,
+        ),
+        other => return Err(Error::UnrecognizedSignatureCharacter(other)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::{Error, Result};
+    use crate::signature::{complete_types, parse, validate, Type};
+
+    #[test]
+    fn parse_types() -> Result<()> {
+        let types = parse(b"ya{sv}(ia(ss))")?;
+        assert_eq!(
+            types,
+            vec![
+                Type::Byte,
+                Type::Array(Box::new(Type::DictEntry(
+                    Box::new(Type::String),
+                    Box::new(Type::Variant)
+                ))),
+                Type::Struct(vec![
+                    Type::Int32,
+                    Type::Array(Box::new(Type::Struct(vec![Type::String, Type::String])))
+                ]),
+            ]
+        );
+        let sigs: Vec<String> = types.iter().map(Type::to_string).collect();
+        assert_eq!(sigs, vec!["y", "a{sv}", "(ia(ss))"]);
+
+        let sigs = complete_types(b"ya{sv}(ia(ss))").collect::<Result<Vec<_>>>()?;
+        assert_eq!(sigs, vec![&b"y"[..], b"a{sv}", b"(ia(ss))"]);
+
+        assert_eq!(Type::parse(b"as"), Ok(Type::Array(Box::new(Type::String))));
+        assert_eq!(
+            Type::parse(b"ss"),
+            Err(Error::NotSingleType(b"ss".to_vec()))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn alignments_and_sizes() -> Result<()> {
+        let cases: &[(&[u8], usize, Option<usize>)] = &[
+            (b"y", 1, Some(1)),
+            (b"b", 4, Some(4)),
+            (b"q", 2, Some(2)),
+            (b"t", 8, Some(8)),
+            (b"h", 4, Some(4)),
+            (b"s", 4, None),
+            (b"g", 1, None),
+            (b"v", 1, None),
+            (b"ay", 4, None),
+            (b"(yu)", 8, Some(8)),
+            (b"(uy)", 8, Some(5)),
+            (b"(y(nx))", 8, Some(24)),
+            (b"(ys)", 8, None),
+        ];
+        for (sig, alignment, fixed_size) in cases {
+            let ty = Type::parse(sig)?;
+            assert_eq!(ty.alignment(), *alignment, "{}", ty);
+            assert_eq!(ty.fixed_size(), *fixed_size, "{}", ty);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_signatures() {
+        assert_eq!(
+            validate(b"a{sv"),
+            Err(Error::MismatchedSignatureBracketing(1))
+        );
+        assert_eq!(
+            validate(b"()"),
+            Err(Error::MismatchedSignatureBracketing(0))
+        );
+        assert_eq!(validate(b"a"), Err(Error::SignatureExhausted));
+        assert_eq!(
+            validate(b"[s]"),
+            Err(Error::UnrecognizedSignatureCharacter(b'['))
+        );
+        assert_eq!(validate(b"{sv}"), Err(Error::DictEntryOutsideArray(0)));
+        assert_eq!(
+            validate(b"(a{sv}{sv})"),
+            Err(Error::DictEntryOutsideArray(6))
+        );
+        assert_eq!(
+            validate(b"a{vs}"),
+            Err(Error::NonBasicDictKey(b"v".to_vec()))
+        );
+        assert_eq!(
+            validate(b"a{(s)s}"),
+            Err(Error::NonBasicDictKey(b"(s)".to_vec()))
+        );
+        assert_eq!(validate(&[b'y'; 256]), Err(Error::SignatureTooLong(256)));
+
+        let arrays = |n| "a".repeat(n) + "y";
+        assert_eq!(validate(arrays(32).as_bytes()), Ok(()));
+        assert_eq!(
+            validate(arrays(33).as_bytes()),
+            Err(Error::ArrayNestingTooDeep(33))
+        );
+        let structs = |n| "(".repeat(n) + "y" + &")".repeat(n);
+        assert_eq!(validate(structs(32).as_bytes()), Ok(()));
+        assert_eq!(
+            validate(structs(33).as_bytes()),
+            Err(Error::StructNestingTooDeep(33))
+        );
+        let entries = "(".repeat(31) + "a{sy}" + &")".repeat(31);
+        assert_eq!(validate(entries.as_bytes()), Ok(()));
+        let entries = "(".repeat(32) + "a{sy}" + &")".repeat(32);
+        assert_eq!(
+            validate(entries.as_bytes()),
+            Err(Error::StructNestingTooDeep(33))
+        );
+        // Arrays and structs each have their own limit.
+        let both = "a(".repeat(32) + "y" + &")".repeat(32);
+        assert_eq!(validate(both.as_bytes()), Ok(()));
+    }
+}
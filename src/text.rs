@@ -0,0 +1,724 @@
+//! GLib's GVariant text format, as used by `gdbus`.
+//!
+//! Values are written as in `{'a': <'Hi'>, 'b': <0.2>}` or
+//! `(int32 1, @as [])`. Types are given by annotations where they cannot
+//! be told from the value itself: integers are `int32` and floating-point
+//! numbers are `double` unless annotated with a type keyword such as
+//! `uint32`, and empty arrays need an `@` annotation with their
+//! signature, unless the signature is known from elsewhere.
+//!
+//! [`to_text`] prints a message, with annotations wherever they are
+//! needed to parse it back with the same signature. [`from_text`] parses
+//! text into a message, either with a given signature or with one
+//! inferred from the text. Messages with several arguments are printed
+//! and parsed as a tuple of them. Byte strings can be parsed, as in
+//! `b'abc'`, which includes a terminating nul, but maybe types cannot.
+
+use crate::de::from_message;
+use crate::error::{Error, Result};
+use crate::message::{Endianness, Message};
+use crate::ser::serialize;
+use crate::signature::single_type_end;
+use crate::types::{ObjectPath, Signature, UnixFdIndex};
+use crate::value::{Array, Dict, Value};
+
+use std::convert::TryFrom;
+use std::fmt::Write;
+
+/// Prints a message in the GVariant text format.
+pub fn to_text(message: &Message) -> Result<String> {
+    if message.signature.is_empty() {
+        return Ok("()".to_owned());
+    }
+    let value: Value = from_message(message)?;
+    Ok(value_to_text(&value))
+}
+
+/// Prints a value in the GVariant text format.
+pub fn value_to_text(value: &Value) -> String {
+    let mut out = String::new();
+    print_value(value, true, &mut out);
+    out
+}
+
+/// Parses text in the GVariant text format into a message, with the
+/// given signature, or with one inferred from the text. A signature with
+/// several complete types is parsed as a tuple of them.
+pub fn from_text(text: &str, signature: Option<&str>) -> Result<Message> {
+    let value = value_from_text(text, signature)?;
+    if signature == Some("") || (signature.is_none() && value == Value::Struct(Vec::new())) {
+        return Ok(Message {
+            data: Vec::new(),
+            signature: Vec::new(),
+            fds: Vec::new(),
+            endianness: Endianness::Little,
+        });
+    }
+    serialize(&value)
+}
+
+/// Parses a single value in the GVariant text format, as in [`from_text`].
+pub fn value_from_text(text: &str, signature: Option<&str>) -> Result<Value> {
+    let expected = match signature {
+        Some(sig) => {
+            let sig = sig.as_bytes();
+            let mut end = 0;
+            let mut count = 0;
+            while end < sig.len() {
+                end = single_type_end(sig, end)?;
+                count += 1;
+            }
+            Some(match count {
+                1 => sig.to_vec(),
+                _ => [&b"("[..], sig, &b")"[..]].concat(),
+            })
+        }
+        None => None,
+    };
+    let mut parser = Parser { text, pos: 0 };
+    let value = parser.parse_value(expected.as_deref())?;
+    parser.skip_whitespace();
+    if parser.pos != text.len() {
+        return Err(parser.error("unexpected text after value"));
+    }
+    Ok(value)
+}
+
+// Values are annotated with their type where it cannot be told from
+// them, unless `annotate` is false because their type can be told from
+// an earlier item of the same array.
+fn print_value(value: &Value, annotate: bool, out: &mut String) {
+    let keyword = |out: &mut String, keyword: &str| {
+        if annotate {
+            out.push_str(keyword);
+            out.push(' ');
+        }
+    };
+    match value {
+        Value::Bool(v) => out.push_str(if *v { "true" } else { "false" }),
+        Value::Byte(v) => {
+            keyword(out, "byte");
+            write!(out, "0x{:02x}", v).unwrap();
+        }
+        Value::Int16(v) => {
+            keyword(out, "int16");
+            write!(out, "{}", v).unwrap();
+        }
+        Value::UInt16(v) => {
+            keyword(out, "uint16");
+            write!(out, "{}", v).unwrap();
+        }
+        Value::Int32(v) => write!(out, "{}", v).unwrap(),
+        Value::UInt32(v) => {
+            keyword(out, "uint32");
+            write!(out, "{}", v).unwrap();
+        }
+        Value::Int64(v) => {
+            keyword(out, "int64");
+            write!(out, "{}", v).unwrap();
+        }
+        Value::UInt64(v) => {
+            keyword(out, "uint64");
+            write!(out, "{}", v).unwrap();
+        }
+        Value::UnixFd(UnixFdIndex(v)) => {
+            keyword(out, "handle");
+            write!(out, "{}", v).unwrap();
+        }
+        Value::Double(v) => print_double(*v, out),
+        Value::String(v) => print_string(v, out),
+        Value::ObjectPath(v) => {
+            keyword(out, "objectpath");
+            print_string(v.as_str(), out);
+        }
+        Value::Signature(v) => {
+            keyword(out, "signature");
+            print_string(v.as_str(), out);
+        }
+        Value::Array(array) => {
+            if array.items().is_empty() {
+                if annotate {
+                    write!(out, "@a{} ", array.item_signature().as_str()).unwrap();
+                }
+                out.push_str("[]");
+                return;
+            }
+            out.push('[');
+            for (ix, item) in array.items().iter().enumerate() {
+                if ix > 0 {
+                    out.push_str(", ");
+                }
+                print_value(item, annotate && ix == 0, out);
+            }
+            out.push(']');
+        }
+        Value::Dict(dict) => {
+            if dict.entries().is_empty() {
+                if annotate {
+                    write!(
+                        out,
+                        "@a{{{}{}}} ",
+                        dict.key_signature().as_str(),
+                        dict.value_signature().as_str()
+                    )
+                    .unwrap();
+                }
+                out.push_str("{}");
+                return;
+            }
+            out.push('{');
+            for (ix, (key, value)) in dict.entries().iter().enumerate() {
+                if ix > 0 {
+                    out.push_str(", ");
+                }
+                print_value(key, annotate && ix == 0, out);
+                out.push_str(": ");
+                print_value(value, annotate && ix == 0, out);
+            }
+            out.push('}');
+        }
+        Value::Struct(fields) => {
+            out.push('(');
+            for (ix, field) in fields.iter().enumerate() {
+                if ix > 0 {
+                    out.push_str(", ");
+                }
+                print_value(field, annotate, out);
+            }
+            if fields.len() == 1 {
+                out.push(',');
+            }
+            out.push(')');
+        }
+        Value::Variant(inner) => {
+            out.push('<');
+            print_value(inner, true, out);
+            out.push('>');
+        }
+    }
+}
+
+// Doubles always have a decimal point or exponent, to tell them apart
+// from integers.
+fn print_double(v: f64, out: &mut String) {
+    if v.is_nan() {
+        out.push_str("nan");
+    } else if v.is_infinite() {
+        out.push_str(if v > 0.0 { "inf" } else { "-inf" });
+    } else {
+        write!(out, "{:?}", v).unwrap();
+    }
+}
+
+// Strings are quoted with `'`, unless they contain one and no `"`.
+fn print_string(s: &str, out: &mut String) {
+    let quote = if s.contains('\'') && !s.contains('"') {
+        '"'
+    } else {
+        '\''
+    };
+    out.push(quote);
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            '\u{7}' => out.push_str("\\a"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{b}' => out.push_str("\\v"),
+            '\u{c}' => out.push_str("\\f"),
+            c if c == quote => {
+                out.push('\\');
+                out.push(c);
+            }
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push(quote);
+}
+
+const KEYWORDS: &[(&str, u8)] = &[
+    ("boolean", b'b'),
+    ("byte", b'y'),
+    ("int16", b'n'),
+    ("uint16", b'q'),
+    ("int32", b'i'),
+    ("uint32", b'u'),
+    ("int64", b'x'),
+    ("uint64", b't'),
+    ("handle", b'h'),
+    ("double", b'd'),
+    ("string", b's'),
+    ("objectpath", b'o'),
+    ("signature", b'g'),
+];
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &str) -> Error {
+        Error::InvalidText(message.to_owned(), self.pos)
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.text[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.rest().chars().next()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        if !self.eat(c) {
+            return Err(self.error(&format!("expected {:?}", c)));
+        }
+        Ok(())
+    }
+
+    // A run of characters that may make up a keyword or number
+    fn word(&mut self) -> &'a str {
+        self.skip_whitespace();
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || "_+-.".contains(c)))
+            .unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    fn type_error(&self, expected: &[u8], got: &[u8]) -> Error {
+        Error::InvalidText(
+            format!(
+                "expected a value of type {}, found {}",
+                String::from_utf8_lossy(expected),
+                String::from_utf8_lossy(got)
+            ),
+            self.pos,
+        )
+    }
+
+    // Checks that a value of signature `got` can stand where `expected`
+    // is expected.
+    fn check_type(&self, expected: Option<&[u8]>, got: &[u8]) -> Result<()> {
+        match expected {
+            Some(expected) if expected != got => Err(self.type_error(expected, got)),
+            _ => Ok(()),
+        }
+    }
+
+    fn parse_value(&mut self, expected: Option<&[u8]>) -> Result<Value> {
+        match self.peek() {
+            Some('@') => {
+                self.pos += 1;
+                let rest = self.rest().as_bytes();
+                let end = single_type_end(rest, 0)?;
+                let sig = rest[..end].to_vec();
+                self.check_type(expected, &sig)?;
+                self.pos += end;
+                self.parse_value(Some(&sig))
+            }
+            Some('<') => {
+                self.check_type(expected, b"v")?;
+                self.pos += 1;
+                let inner = self.parse_value(None)?;
+                self.expect('>')?;
+                Ok(inner.into_variant())
+            }
+            Some('[') => self.parse_array(expected),
+            Some('{') => self.parse_dict(expected),
+            Some('(') => self.parse_struct(expected),
+            Some('\'') | Some('"') => {
+                let s = self.parse_string()?;
+                self.string_value(s, expected)
+            }
+            Some('b') if self.rest()[1..].starts_with(['\'', '"']) => {
+                self.check_type(expected, b"ay")?;
+                self.pos += 1;
+                let mut items: Vec<Value> = self.parse_string()?.bytes().map(Value::Byte).collect();
+                items.push(Value::Byte(0));
+                Ok(Value::Array(Array::from_items(
+                    Signature::new("y")?,
+                    items,
+                )?))
+            }
+            Some(_) => {
+                let start = self.pos;
+                let word = self.word();
+                match word {
+                    "" => Err(self.error("expected a value")),
+                    "true" | "false" => {
+                        self.check_type(expected, b"b")?;
+                        Ok(Value::Bool(word == "true"))
+                    }
+                    _ => match KEYWORDS.iter().find(|(keyword, _)| *keyword == word) {
+                        Some((_, code)) => {
+                            self.check_type(expected, &[*code])?;
+                            self.parse_value(Some(&[*code]))
+                        }
+                        None => {
+                            self.pos = start;
+                            self.parse_number(word, expected)
+                        }
+                    },
+                }
+            }
+            None => Err(self.error("expected a value")),
+        }
+    }
+
+    fn string_value(&self, s: String, expected: Option<&[u8]>) -> Result<Value> {
+        match expected {
+            None | Some(b"s") => Ok(Value::String(s)),
+            Some(b"o") => Ok(Value::ObjectPath(ObjectPath::new(s)?)),
+            Some(b"g") => Ok(Value::Signature(Signature::new(s)?)),
+            Some(expected) => Err(self.type_error(expected, b"s")),
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String> {
+        let quote = self.peek().expect("checked quote");
+        self.pos += 1;
+        let mut s = String::new();
+        let mut chars = self.rest().char_indices();
+        while let Some((ix, c)) = chars.next() {
+            match c {
+                c if c == quote => {
+                    self.pos += ix + 1;
+                    return Ok(s);
+                }
+                '\\' => {
+                    let escaped = match chars.next() {
+                        Some((_, c)) => c,
+                        None => break,
+                    };
+                    s.push(match escaped {
+                        'n' => '\n',
+                        't' => '\t',
+                        'r' => '\r',
+                        'a' => '\u{7}',
+                        'b' => '\u{8}',
+                        'v' => '\u{b}',
+                        'f' => '\u{c}',
+                        'u' | 'U' => {
+                            let len = if escaped == 'u' { 4 } else { 8 };
+                            let hex: String = (0..len)
+                                .filter_map(|_| chars.next())
+                                .map(|(_, c)| c)
+                                .collect();
+                            u32::from_str_radix(&hex, 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| {
+                                    Error::InvalidText(
+                                        "invalid unicode escape".to_owned(),
+                                        self.pos + ix,
+                                    )
+                                })?
+                        }
+                        c => c,
+                    });
+                }
+                c => s.push(c),
+            }
+        }
+        Err(self.error("unterminated string"))
+    }
+
+    fn parse_number(&mut self, word: &str, expected: Option<&[u8]>) -> Result<Value> {
+        let code = match expected {
+            Some([code]) => *code,
+            Some(_) => return Err(self.error("expected a number")),
+            None if is_float(word) => b'd',
+            None => b'i',
+        };
+        if code == b'd' {
+            let v = match word {
+                "nan" => f64::NAN,
+                "inf" | "+inf" => f64::INFINITY,
+                "-inf" => f64::NEG_INFINITY,
+                _ => word.parse().map_err(|_| self.error("invalid number"))?,
+            };
+            self.pos += word.len();
+            return Ok(Value::Double(v));
+        }
+
+        let (negative, digits) = match word.as_bytes().first() {
+            Some(b'-') => (true, &word[1..]),
+            Some(b'+') => (false, &word[1..]),
+            _ => (false, word),
+        };
+        let magnitude = match digits
+            .strip_prefix("0x")
+            .or_else(|| digits.strip_prefix("0X"))
+        {
+            Some(hex) => i128::from_str_radix(hex, 16),
+            None => digits.parse::<i128>(),
+        }
+        .map_err(|_| self.error("invalid integer"))?;
+        let v = if negative { -magnitude } else { magnitude };
+        let out_of_range = |_| Error::InvalidText(format!("{} out of range", word), self.pos);
+        let value = match code {
+            b'y' => Value::Byte(u8::try_from(v).map_err(out_of_range)?),
+            b'n' => Value::Int16(i16::try_from(v).map_err(out_of_range)?),
+            b'q' => Value::UInt16(u16::try_from(v).map_err(out_of_range)?),
+            b'i' => Value::Int32(i32::try_from(v).map_err(out_of_range)?),
+            b'u' => Value::UInt32(u32::try_from(v).map_err(out_of_range)?),
+            b'x' => Value::Int64(i64::try_from(v).map_err(out_of_range)?),
+            b't' => Value::UInt64(u64::try_from(v).map_err(out_of_range)?),
+            b'h' => Value::UnixFd(UnixFdIndex(u32::try_from(v).map_err(out_of_range)?)),
+            _ => return Err(self.error("expected a number")),
+        };
+        self.pos += word.len();
+        Ok(value)
+    }
+
+    fn parse_array(&mut self, expected: Option<&[u8]>) -> Result<Value> {
+        let mut item_sig = match expected {
+            Some([b'a', item @ ..]) if !item.starts_with(b"{") => Some(item.to_vec()),
+            Some(expected) => return Err(self.type_error(expected, b"a")),
+            None => None,
+        };
+        let start = self.pos;
+        self.expect('[')?;
+        let mut items = Vec::new();
+        while !self.eat(']') {
+            if !items.is_empty() {
+                self.expect(',')?;
+            }
+            let item = self.parse_value(item_sig.as_deref())?;
+            if item_sig.is_none() {
+                item_sig = Some(item.signature().into_bytes());
+            }
+            items.push(item);
+        }
+        let item_sig = match item_sig {
+            Some(item_sig) => item_sig,
+            None => {
+                self.pos = start;
+                return Err(self.error("cannot infer the type of an empty array"));
+            }
+        };
+        Ok(Value::Array(Array::from_items(
+            signature(item_sig)?,
+            items,
+        )?))
+    }
+
+    fn parse_dict(&mut self, expected: Option<&[u8]>) -> Result<Value> {
+        let (mut key_sig, mut value_sig) = match expected {
+            Some(sig @ [b'a', b'{', ..]) => {
+                let key_end = single_type_end(sig, 2)?;
+                let value_end = single_type_end(sig, key_end)?;
+                (
+                    Some(sig[2..key_end].to_vec()),
+                    Some(sig[key_end..value_end].to_vec()),
+                )
+            }
+            None => (None, None),
+            Some(expected) => return Err(self.type_error(expected, b"a{")),
+        };
+        let start = self.pos;
+        self.expect('{')?;
+        let mut entries = Vec::new();
+        while !self.eat('}') {
+            if !entries.is_empty() {
+                self.expect(',')?;
+            }
+            let key = self.parse_value(key_sig.as_deref())?;
+            self.expect(':')?;
+            let value = self.parse_value(value_sig.as_deref())?;
+            key_sig.get_or_insert_with(|| key.signature().into_bytes());
+            value_sig.get_or_insert_with(|| value.signature().into_bytes());
+            entries.push((key, value));
+        }
+        let (key_sig, value_sig) = match (key_sig, value_sig) {
+            (Some(key_sig), Some(value_sig)) => (key_sig, value_sig),
+            _ => {
+                self.pos = start;
+                return Err(self.error("cannot infer the type of an empty dict"));
+            }
+        };
+        let mut dict = Dict::new(signature(key_sig)?, signature(value_sig)?)?;
+        for (key, value) in entries {
+            dict.push(key, value)?;
+        }
+        Ok(Value::Dict(dict))
+    }
+
+    fn parse_struct(&mut self, expected: Option<&[u8]>) -> Result<Value> {
+        let field_sigs = match expected {
+            Some(sig @ [b'(', ..]) => {
+                let mut fields = Vec::new();
+                let mut ix = 1;
+                while ix < sig.len() - 1 {
+                    let end = single_type_end(sig, ix)?;
+                    fields.push(&sig[ix..end]);
+                    ix = end;
+                }
+                Some(fields)
+            }
+            None => None,
+            Some(expected) => return Err(self.type_error(expected, b"(")),
+        };
+        self.expect('(')?;
+        let mut fields = Vec::new();
+        loop {
+            if self.eat(')') {
+                break;
+            }
+            let field_sig = match &field_sigs {
+                Some(sigs) => match sigs.get(fields.len()) {
+                    Some(sig) => Some(*sig),
+                    None => return Err(self.error("too many fields in tuple")),
+                },
+                None => None,
+            };
+            fields.push(self.parse_value(field_sig)?);
+            if !self.eat(',') {
+                self.expect(')')?;
+                break;
+            }
+        }
+        if let Some(sigs) = field_sigs {
+            if sigs.len() != fields.len() {
+                return Err(self.error("too few fields in tuple"));
+            }
+        }
+        Ok(Value::Struct(fields))
+    }
+}
+
+fn signature(sig: Vec<u8>) -> Result<Signature> {
+    let sig = String::from_utf8(sig).map_err(|err| err.utf8_error())?;
+    Signature::new(sig)
+}
+
+fn is_float(word: &str) -> bool {
+    let is_hex = word.trim_start_matches(['-', '+']).starts_with("0x");
+    !is_hex
+        && (word.contains(['.', 'e', 'E'])
+            || word.trim_start_matches(['-', '+']) == "inf"
+            || word == "nan")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::{Error, Result};
+    use crate::ser::{serialize, serialize_with_signature};
+    use crate::text::{from_text, to_text};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn print_messages() -> Result<()> {
+        let mut dict = BTreeMap::new();
+        dict.insert("a", "Hi");
+        let message = serialize_with_signature((&dict, 0.2), "a{sv}v")?;
+        assert_eq!(to_text(&message)?, "({'a': <'Hi'>}, <0.2>)");
+
+        let message = serialize_with_signature((1, Vec::<String>::new()), "ias")?;
+        assert_eq!(to_text(&message)?, "(1, @as [])");
+
+        let message = serialize((vec![1u32, 2], 2u8, -3i64, "it's", 1.0))?;
+        assert_eq!(
+            to_text(&message)?,
+            r#"([uint32 1, 2], byte 0x02, int64 -3, "it's", 1.0)"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_messages() -> Result<()> {
+        let message = from_text("{'a': <'Hi'>, 'b': <0.2>}", None)?;
+        assert_eq!(message.signature, b"a{sv}".to_vec());
+        assert_eq!(to_text(&message)?, "{'a': <'Hi'>, 'b': <0.2>}");
+
+        let message = from_text("(int32 1, @as [])", None)?;
+        assert_eq!(
+            message,
+            serialize_with_signature((1, Vec::<String>::new()), "ias")?
+        );
+
+        let message = from_text("(1, [], '/a', 'x\\ty')", Some("qaoos"))?;
+        assert_eq!(message.signature, b"(qaoos)".to_vec());
+        assert_eq!(
+            to_text(&message)?,
+            "(uint16 1, @ao [], objectpath '/a', 'x\\ty')"
+        );
+
+        let message = from_text(" [ (0x10 , b'ab') ,(-1,[]) ] ", Some("a(nay)"))?;
+        assert_eq!(
+            to_text(&message)?,
+            "[(int16 16, [byte 0x61, 0x62, 0x00]), (-1, [])]"
+        );
+
+        assert_eq!(to_text(&from_text("()", None)?)?, "()");
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip_text() -> Result<()> {
+        for text in [
+            "<<@a{ot} {}>>",
+            "(true, handle 3, signature 'a{sv}', [<1>, <'a'>])",
+            "[{'k': (1,)}, {}]",
+            "(-0.5, 1e100, inf, -inf, 'é\\u0001')",
+        ] {
+            let message = from_text(text, None)?;
+            assert_eq!(to_text(&message)?, text);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(
+            from_text("[]", None),
+            Err(Error::InvalidText(
+                "cannot infer the type of an empty array".to_owned(),
+                0
+            ))
+        );
+        assert_eq!(
+            from_text(" {}", None),
+            Err(Error::InvalidText(
+                "cannot infer the type of an empty dict".to_owned(),
+                1
+            ))
+        );
+        assert_eq!(
+            from_text("300", Some("y")),
+            Err(Error::InvalidText("300 out of range".to_owned(), 0))
+        );
+        assert_eq!(
+            from_text("(1, 'a'", None),
+            Err(Error::InvalidText("expected ')'".to_owned(), 7))
+        );
+        assert_eq!(
+            from_text("'a'", Some("u")),
+            Err(Error::InvalidText(
+                "expected a value of type u, found s".to_owned(),
+                3
+            ))
+        );
+    }
+}
@@ -0,0 +1,407 @@
+//! DBus types with no counterpart in serde's data model.
+//!
+//! Object paths, signatures and Unix file descriptors are strings and
+//! integers to serde, so they need their own types to serialize with
+//! their own type codes, `o`, `g` and `h`. These are validated when they
+//! are constructed and when they are deserialized. With other serde
+//! formats, they serialize as plain strings and integers.
+//!
+//! Unix file descriptors can be sent with [`UnixFd`], which owns its fd,
+//! or [`BorrowedUnixFd`]. These serialize as an index into the
+//! message's [`fds`](crate::message::Message::fds), which holds a
+//! duplicate of the fd, and deserializing a `UnixFd` duplicates the fd
+//! at that index. Only this crate's
+//! deserializer can give a `UnixFd`, because other deserializers have no
+//! way to vouch for the fd. [`UnixFdIndex`] is the index itself.
+
+use crate::error::{Error, Result};
+use crate::signature;
+
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::ser::{self, Serialize, Serializer};
+#[cfg(unix)]
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::fmt::{self, Display, Formatter};
+#[cfg(unix)]
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd};
+
+// These types serialize as newtype structs with these names, which the
+// serializer and deserializer recognize.
+const OBJECT_PATH_NAME: &str = "$serde_dbus::ObjectPath";
+const SIGNATURE_NAME: &str = "$serde_dbus::Signature";
+const UNIX_FD_INDEX_NAME: &str = "$serde_dbus::UnixFdIndex";
+pub(crate) const UNIX_FD_NAME: &str = "$serde_dbus::UnixFd";
+
+// The type code for a newtype struct with the given name, if it is one
+// of the types in this module.
+pub(crate) fn newtype_signature(name: &str) -> Option<u8> {
+    match name {
+        OBJECT_PATH_NAME => Some(b'o'),
+        SIGNATURE_NAME => Some(b'g'),
+        UNIX_FD_INDEX_NAME => Some(b'h'),
+        _ => None,
+    }
+}
+
+/// A DBus object path, such as `/org/freedesktop/DBus`, with signature `o`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ObjectPath(String);
+
+impl ObjectPath {
+    /// Checks that `path` is a valid object path: `/`, or `/` followed by
+    /// elements of ASCII letters, digits and underscores, separated by
+    /// single slashes.
+    pub fn new(path: impl Into<String>) -> Result<Self> {
+        let path = path.into();
+        let valid = match path.strip_prefix('/') {
+            Some("") => true,
+            Some(rest) => rest.split('/').all(|element| {
+                !element.is_empty()
+                    && element
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b == b'_')
+            }),
+            None => false,
+        };
+        if !valid {
+            return Err(Error::InvalidObjectPath(path));
+        }
+        Ok(Self(path))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+/// A DBus signature, such as `a{sv}`, with signature `g`. It can hold
+/// any number of complete types, including none.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Signature(String);
+
+impl Signature {
+    /// Checks that `sig` is a valid signature, as in
+    /// [`signature::validate`].
+    pub fn new(sig: impl Into<String>) -> Result<Self> {
+        let sig = sig.into();
+        signature::validate(sig.as_bytes())?;
+        Ok(Self(sig))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+/// A Unix file descriptor, with signature `h`. In the message, it is an
+/// index into the list of file descriptors sent along with the message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct UnixFdIndex(pub u32);
+
+/// A Unix file descriptor owned by this value, with signature `h`.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct UnixFd(OwnedFd);
+
+#[cfg(unix)]
+impl UnixFd {
+    pub fn new(fd: OwnedFd) -> Self {
+        Self(fd)
+    }
+
+    pub fn into_inner(self) -> OwnedFd {
+        self.0
+    }
+}
+
+/// A Unix file descriptor borrowed for serialization, with signature
+/// `h`. The message it is serialized into holds a duplicate of it.
+#[cfg(unix)]
+#[derive(Clone, Copy, Debug)]
+pub struct BorrowedUnixFd<'a>(BorrowedFd<'a>);
+
+#[cfg(unix)]
+impl<'a> BorrowedUnixFd<'a> {
+    pub fn new(fd: BorrowedFd<'a>) -> Self {
+        Self(fd)
+    }
+}
+
+// A duplicate of the fd being serialized is left here, along with the
+// number of the fd, for the serializer to take and give to the message.
+// Other serializers see only the number.
+#[cfg(unix)]
+thread_local! {
+    static SENT_FD: RefCell<Option<(RawFd, OwnedFd)>> = const { RefCell::new(None) };
+}
+
+// Takes the duplicate of the fd being serialized, as the `UNIX_FD_NAME`
+// newtype struct.
+#[cfg(unix)]
+pub(crate) fn take_sent_fd() -> Option<(RawFd, OwnedFd)> {
+    SENT_FD.with(|slot| slot.borrow_mut().take())
+}
+
+#[cfg(unix)]
+macro_rules! fd_type {
+    ($type:ty) => {
+        impl AsFd for $type {
+            fn as_fd(&self) -> BorrowedFd<'_> {
+                self.0.as_fd()
+            }
+        }
+
+        impl AsRawFd for $type {
+            fn as_raw_fd(&self) -> RawFd {
+                self.0.as_raw_fd()
+            }
+        }
+
+        impl Serialize for $type {
+            fn serialize<S: Serializer>(
+                &self,
+                serializer: S,
+            ) -> std::result::Result<S::Ok, S::Error> {
+                let fd = self
+                    .as_fd()
+                    .try_clone_to_owned()
+                    .map_err(ser::Error::custom)?;
+                SENT_FD.with(|slot| *slot.borrow_mut() = Some((self.as_raw_fd(), fd)));
+                let res = serializer.serialize_newtype_struct(UNIX_FD_NAME, &self.as_raw_fd());
+                take_sent_fd();
+                res
+            }
+        }
+    };
+}
+
+#[cfg(unix)]
+fd_type!(UnixFd);
+#[cfg(unix)]
+fd_type!(BorrowedUnixFd<'_>);
+
+#[cfg(unix)]
+impl From<OwnedFd> for UnixFd {
+    fn from(fd: OwnedFd) -> Self {
+        Self(fd)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixFd> for OwnedFd {
+    fn from(fd: UnixFd) -> Self {
+        fd.0
+    }
+}
+
+#[cfg(unix)]
+impl<'a> From<BorrowedFd<'a>> for BorrowedUnixFd<'a> {
+    fn from(fd: BorrowedFd<'a>) -> Self {
+        Self(fd)
+    }
+}
+
+// The deserializer duplicates the fd from the message's fds itself, and
+// leaves the duplicate here for `UnixFd`'s visitor to take. Other
+// deserializers can't, so they can't make it trust an arbitrary integer
+// as an open fd.
+#[cfg(unix)]
+thread_local! {
+    static RECEIVED_FD: RefCell<Option<OwnedFd>> = const { RefCell::new(None) };
+}
+
+// Gives `fd` to the `UnixFd` deserialized by `visit`, which is called
+// with the visitor of the `UNIX_FD_NAME` newtype struct. The fd is
+// closed if the visitor does not take it.
+#[cfg(unix)]
+pub(crate) fn with_received_fd<T>(fd: OwnedFd, visit: impl FnOnce() -> T) -> T {
+    RECEIVED_FD.with(|slot| *slot.borrow_mut() = Some(fd));
+    let res = visit();
+    RECEIVED_FD.with(|slot| slot.borrow_mut().take());
+    res
+}
+
+#[cfg(unix)]
+impl<'de> Deserialize<'de> for UnixFd {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct FdVisitor;
+
+        impl<'de> Visitor<'de> for FdVisitor {
+            type Value = UnixFd;
+
+            fn expecting(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                f.write_str("a Unix file descriptor")
+            }
+
+            // The fd is only there while serde_dbus is deserializing it.
+            fn visit_unit<E: de::Error>(self) -> std::result::Result<UnixFd, E> {
+                match RECEIVED_FD.with(|slot| slot.borrow_mut().take()) {
+                    Some(fd) => Ok(UnixFd(fd)),
+                    None => Err(E::invalid_type(de::Unexpected::Unit, &self)),
+                }
+            }
+        }
+
+        deserializer.deserialize_newtype_struct(UNIX_FD_NAME, FdVisitor)
+    }
+}
+
+macro_rules! string_type {
+    ($type:ident, $name:expr, $expecting:expr) => {
+        impl TryFrom<String> for $type {
+            type Error = Error;
+
+            fn try_from(value: String) -> Result<Self> {
+                Self::new(value)
+            }
+        }
+
+        impl TryFrom<&str> for $type {
+            type Error = Error;
+
+            fn try_from(value: &str) -> Result<Self> {
+                Self::new(value)
+            }
+        }
+
+        impl AsRef<str> for $type {
+            fn as_ref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl Display for $type {
+            fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl Serialize for $type {
+            fn serialize<S: Serializer>(
+                &self,
+                serializer: S,
+            ) -> std::result::Result<S::Ok, S::Error> {
+                serializer.serialize_newtype_struct($name, self.as_str())
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $type {
+            fn deserialize<D: Deserializer<'de>>(
+                deserializer: D,
+            ) -> std::result::Result<Self, D::Error> {
+                struct TypeVisitor;
+
+                impl<'de> Visitor<'de> for TypeVisitor {
+                    type Value = $type;
+
+                    fn expecting(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                        f.write_str($expecting)
+                    }
+
+                    fn visit_str<E: de::Error>(self, v: &str) -> std::result::Result<$type, E> {
+                        $type::new(v).map_err(E::custom)
+                    }
+
+                    fn visit_newtype_struct<D: Deserializer<'de>>(
+                        self,
+                        deserializer: D,
+                    ) -> std::result::Result<$type, D::Error> {
+                        let v = String::deserialize(deserializer)?;
+                        $type::new(v).map_err(de::Error::custom)
+                    }
+                }
+
+                deserializer.deserialize_newtype_struct($name, TypeVisitor)
+            }
+        }
+    };
+}
+
+string_type!(ObjectPath, OBJECT_PATH_NAME, "a DBus object path");
+string_type!(Signature, SIGNATURE_NAME, "a DBus signature");
+
+impl Serialize for UnixFdIndex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_newtype_struct(UNIX_FD_INDEX_NAME, &self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for UnixFdIndex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct FdVisitor;
+
+        impl<'de> Visitor<'de> for FdVisitor {
+            type Value = UnixFdIndex;
+
+            fn expecting(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                f.write_str("a Unix file descriptor index")
+            }
+
+            fn visit_u32<E: de::Error>(self, v: u32) -> std::result::Result<UnixFdIndex, E> {
+                Ok(UnixFdIndex(v))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> std::result::Result<UnixFdIndex, E> {
+                u32::try_from(v)
+                    .map(UnixFdIndex)
+                    .map_err(|_| E::invalid_value(de::Unexpected::Unsigned(v), &self))
+            }
+
+            fn visit_newtype_struct<D: Deserializer<'de>>(
+                self,
+                deserializer: D,
+            ) -> std::result::Result<UnixFdIndex, D::Error> {
+                u32::deserialize(deserializer).map(UnixFdIndex)
+            }
+        }
+
+        deserializer.deserialize_newtype_struct(UNIX_FD_INDEX_NAME, FdVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::types::{ObjectPath, Signature};
+
+    #[test]
+    fn object_path_validation() {
+        for path in ["/", "/org", "/org/freedesktop/DBus", "/a_1/B2"] {
+            assert!(ObjectPath::new(path).is_ok(), "{}", path);
+        }
+        for path in ["", "org", "//", "/org/", "/org//a", "/org/a-b", "/org.a"] {
+            assert_eq!(
+                ObjectPath::new(path),
+                Err(Error::InvalidObjectPath(path.to_owned()))
+            );
+        }
+    }
+
+    #[test]
+    fn signature_validation() {
+        for sig in ["", "s", "a{sv}", "(ia(ss))ay"] {
+            assert!(Signature::new(sig).is_ok(), "{}", sig);
+        }
+        assert_eq!(
+            Signature::new("a{sv"),
+            Err(Error::MismatchedSignatureBracketing(1))
+        );
+        assert_eq!(
+            Signature::new("z"),
+            Err(Error::UnrecognizedSignatureCharacter(b'z'))
+        );
+        assert_eq!(
+            Signature::new("y".repeat(256)),
+            Err(Error::SignatureTooLong(256))
+        );
+    }
+}
@@ -0,0 +1,866 @@
+//! A dynamically typed DBus value.
+//!
+//! [`Value`] can hold any DBus value, and remembers its exact signature,
+//! so that data whose type is not known in advance, such as the values
+//! of an `a{sv}`, can be deserialized, inspected and serialized again
+//! unchanged. Arrays and dicts keep their item signatures even when they
+//! are empty, and variants keep the signature of their contents.
+//!
+//! With other serde formats, a `Value` serializes as its contents, with
+//! variants left out and empty arrays and dicts as empty sequences and
+//! maps.
+//! It deserializes from other formats by inferring signatures as the
+//! serializer does, with arrays and dicts of mixed types falling back to
+//! variants.
+
+use crate::error::{Error, Result};
+use crate::signature::{is_basic, single_type_end};
+use crate::text::value_to_text;
+use crate::types::{ObjectPath, Signature, UnixFdIndex};
+
+use serde::de::{
+    self, DeserializeSeed, Deserializer, EnumAccess, MapAccess, SeqAccess, VariantAccess, Visitor,
+};
+use serde::ser::{SerializeMap, SerializeSeq, SerializeTuple, Serializer};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::fmt::{self, Formatter};
+
+// These newtype names are recognized by the serializer and
+// deserializer.
+pub(crate) const VALUE_NAME: &str = "$serde_dbus::Value";
+pub(crate) const VARIANT_NAME: &str = "$serde_dbus::Variant";
+pub(crate) const EMPTY_ARRAY_NAME: &str = "$serde_dbus::EmptyArray";
+
+/// Any DBus value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Byte(u8),
+    Bool(bool),
+    Int16(i16),
+    UInt16(u16),
+    Int32(i32),
+    UInt32(u32),
+    Int64(i64),
+    UInt64(u64),
+    Double(f64),
+    String(String),
+    ObjectPath(ObjectPath),
+    Signature(Signature),
+    UnixFd(UnixFdIndex),
+    Array(Array),
+    Dict(Dict),
+    Struct(Vec<Value>),
+    Variant(Box<Value>),
+}
+
+impl Value {
+    /// The signature of this value, which is a single complete type.
+    pub fn signature(&self) -> String {
+        let mut sig = String::new();
+        self.write_signature(&mut sig);
+        sig
+    }
+
+    fn write_signature(&self, sig: &mut String) {
+        match self {
+            Value::Byte(_) => sig.push('y'),
+            Value::Bool(_) => sig.push('b'),
+            Value::Int16(_) => sig.push('n'),
+            Value::UInt16(_) => sig.push('q'),
+            Value::Int32(_) => sig.push('i'),
+            Value::UInt32(_) => sig.push('u'),
+            Value::Int64(_) => sig.push('x'),
+            Value::UInt64(_) => sig.push('t'),
+            Value::Double(_) => sig.push('d'),
+            Value::String(_) => sig.push('s'),
+            Value::ObjectPath(_) => sig.push('o'),
+            Value::Signature(_) => sig.push('g'),
+            Value::UnixFd(_) => sig.push('h'),
+            Value::Array(array) => {
+                sig.push('a');
+                sig.push_str(array.item_signature.as_str());
+            }
+            Value::Dict(dict) => {
+                sig.push_str("a{");
+                sig.push_str(dict.key_signature.as_str());
+                sig.push_str(dict.value_signature.as_str());
+                sig.push('}');
+            }
+            Value::Struct(fields) => {
+                sig.push('(');
+                for field in fields {
+                    field.write_signature(sig);
+                }
+                sig.push(')');
+            }
+            Value::Variant(_) => sig.push('v'),
+        }
+    }
+
+    /// The contents of a string, object path or signature.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            Value::ObjectPath(path) => Some(path.as_str()),
+            Value::Signature(sig) => Some(sig.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Wraps this value in a variant.
+    pub fn into_variant(self) -> Value {
+        Value::Variant(Box::new(self))
+    }
+}
+
+/// Values are displayed in the GVariant text format of the [`text`]
+/// module.
+///
+/// [`text`]: crate::text
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&value_to_text(self))
+    }
+}
+
+// Checks that `sig` is a single complete type.
+fn single_type(sig: &Signature) -> Result<()> {
+    let bytes = sig.as_str().as_bytes();
+    if bytes.is_empty() || single_type_end(bytes, 0)? != bytes.len() {
+        return Err(Error::NotSingleType(bytes.to_vec()));
+    }
+    Ok(())
+}
+
+fn check_signature(expected: &Signature, value: &Value) -> Result<()> {
+    let sig = value.signature();
+    if sig != expected.as_str() {
+        return Err(Error::MismatchSignature(
+            expected.as_str().as_bytes().to_vec(),
+            sig.into_bytes(),
+        ));
+    }
+    Ok(())
+}
+
+/// An array of values of the same signature, which is kept even when the
+/// array is empty.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Array {
+    item_signature: Signature,
+    items: Vec<Value>,
+}
+
+impl Array {
+    /// An empty array of items of `item_signature`, which must be a single
+    /// complete type.
+    pub fn new(item_signature: Signature) -> Result<Array> {
+        single_type(&item_signature)?;
+        Ok(Array {
+            item_signature,
+            items: Vec::new(),
+        })
+    }
+
+    /// An array of `items`, which must all have `item_signature`.
+    pub fn from_items(item_signature: Signature, items: Vec<Value>) -> Result<Array> {
+        let mut array = Array::new(item_signature)?;
+        for item in items {
+            array.push(item)?;
+        }
+        Ok(array)
+    }
+
+    pub fn push(&mut self, item: Value) -> Result<()> {
+        check_signature(&self.item_signature, &item)?;
+        self.items.push(item);
+        Ok(())
+    }
+
+    pub fn item_signature(&self) -> &Signature {
+        &self.item_signature
+    }
+
+    pub fn items(&self) -> &[Value] {
+        &self.items
+    }
+
+    pub fn into_items(self) -> Vec<Value> {
+        self.items
+    }
+}
+
+/// A dict, an array of key-value pairs with keys of a basic type and
+/// values of the same signature. The order of the entries is kept.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dict {
+    key_signature: Signature,
+    value_signature: Signature,
+    entries: Vec<(Value, Value)>,
+}
+
+impl Dict {
+    /// An empty dict. `key_signature` must be a basic type, and
+    /// `value_signature` a single complete type.
+    pub fn new(key_signature: Signature, value_signature: Signature) -> Result<Dict> {
+        let key = key_signature.as_str().as_bytes();
+        if !matches!(key, [code] if is_basic(*code)) {
+            return Err(Error::NonBasicDictKey(key.to_vec()));
+        }
+        single_type(&value_signature)?;
+        Ok(Dict {
+            key_signature,
+            value_signature,
+            entries: Vec::new(),
+        })
+    }
+
+    /// Adds an entry, after any others, even if there is already one with
+    /// the same key.
+    pub fn push(&mut self, key: Value, value: Value) -> Result<()> {
+        check_signature(&self.key_signature, &key)?;
+        check_signature(&self.value_signature, &value)?;
+        self.entries.push((key, value));
+        Ok(())
+    }
+
+    /// The value of the first entry with `key`.
+    pub fn get(&self, key: &Value) -> Option<&Value> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value)
+    }
+
+    pub fn key_signature(&self) -> &Signature {
+        &self.key_signature
+    }
+
+    pub fn value_signature(&self) -> &Signature {
+        &self.value_signature
+    }
+
+    pub fn entries(&self) -> &[(Value, Value)] {
+        &self.entries
+    }
+
+    pub fn into_entries(self) -> Vec<(Value, Value)> {
+        self.entries
+    }
+
+    fn entry_signature(&self) -> String {
+        format!("{{{}{}}}", self.key_signature, self.value_signature)
+    }
+}
+
+macro_rules! value_from {
+    ($($type:ty => $variant:ident),*) => {
+        $(
+            impl From<$type> for Value {
+                fn from(value: $type) -> Value {
+                    Value::$variant(value)
+                }
+            }
+        )*
+    };
+}
+
+value_from!(
+    u8 => Byte,
+    bool => Bool,
+    i16 => Int16,
+    u16 => UInt16,
+    i32 => Int32,
+    u32 => UInt32,
+    i64 => Int64,
+    u64 => UInt64,
+    f64 => Double,
+    String => String,
+    ObjectPath => ObjectPath,
+    Signature => Signature,
+    UnixFdIndex => UnixFd,
+    Array => Array,
+    Dict => Dict,
+    Vec<Value> => Struct
+);
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Value {
+        Value::String(value.to_owned())
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            Value::Byte(v) => serializer.serialize_u8(*v),
+            Value::Bool(v) => serializer.serialize_bool(*v),
+            Value::Int16(v) => serializer.serialize_i16(*v),
+            Value::UInt16(v) => serializer.serialize_u16(*v),
+            Value::Int32(v) => serializer.serialize_i32(*v),
+            Value::UInt32(v) => serializer.serialize_u32(*v),
+            Value::Int64(v) => serializer.serialize_i64(*v),
+            Value::UInt64(v) => serializer.serialize_u64(*v),
+            Value::Double(v) => serializer.serialize_f64(*v),
+            Value::String(v) => serializer.serialize_str(v),
+            Value::ObjectPath(v) => v.serialize(serializer),
+            Value::Signature(v) => v.serialize(serializer),
+            Value::UnixFd(v) => v.serialize(serializer),
+            Value::Array(array) => array.serialize(serializer),
+            Value::Dict(dict) => dict.serialize(serializer),
+            Value::Struct(fields) => {
+                let mut tuple = serializer.serialize_tuple(fields.len())?;
+                for field in fields {
+                    tuple.serialize_element(field)?;
+                }
+                tuple.end()
+            }
+            Value::Variant(value) => serializer.serialize_newtype_struct(VARIANT_NAME, value),
+        }
+    }
+}
+
+// The item signature of an empty array or dict is left here while it
+// is serialized, for the serializer to take. Other serializers see only
+// an empty sequence or map.
+thread_local! {
+    static EMPTY_ARRAY_SIGNATURE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// Takes the item signature of the empty array or dict being serialized,
+// as the `EMPTY_ARRAY_NAME` newtype struct.
+pub(crate) fn take_empty_array_signature() -> Option<String> {
+    EMPTY_ARRAY_SIGNATURE.with(|slot| slot.borrow_mut().take())
+}
+
+struct EmptyArray {
+    dict: bool,
+}
+
+impl Serialize for EmptyArray {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        if self.dict {
+            serializer.serialize_map(Some(0))?.end()
+        } else {
+            serializer.serialize_seq(Some(0))?.end()
+        }
+    }
+}
+
+fn serialize_empty_array<S: Serializer>(
+    serializer: S,
+    item_signature: String,
+    dict: bool,
+) -> std::result::Result<S::Ok, S::Error> {
+    EMPTY_ARRAY_SIGNATURE.with(|slot| *slot.borrow_mut() = Some(item_signature));
+    let res = serializer.serialize_newtype_struct(EMPTY_ARRAY_NAME, &EmptyArray { dict });
+    take_empty_array_signature();
+    res
+}
+
+// The serializer infers the item signatures of arrays and dicts from
+// their items, which all agree, so only empty ones need to be told.
+impl Serialize for Array {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        if self.items.is_empty() {
+            return serialize_empty_array(serializer, self.item_signature.to_string(), false);
+        }
+        let mut seq = serializer.serialize_seq(Some(self.items.len()))?;
+        for item in &self.items {
+            seq.serialize_element(item)?;
+        }
+        seq.end()
+    }
+}
+
+impl Serialize for Dict {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        if self.entries.is_empty() {
+            return serialize_empty_array(serializer, self.entry_signature(), true);
+        }
+        let mut map = serializer.serialize_map(Some(self.entries.len()))?;
+        for (key, value) in &self.entries {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_newtype_struct(VALUE_NAME, ValueVisitor)
+    }
+}
+
+// This crate's deserializer gives a value as an enum variant named by its
+// signature, with the value as its contents. Other formats give the value
+// itself, whose signature is inferred.
+struct ValueVisitor;
+
+macro_rules! infer_value {
+    ($($method:ident($type:ty)),*) => {
+        $(
+            fn $method<E: de::Error>(self, v: $type) -> std::result::Result<Value, E> {
+                InferredValue.$method(v)
+            }
+        )*
+    };
+}
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("a DBus value")
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> std::result::Result<Value, A::Error> {
+        let (sig, contents): (String, _) = data.variant()?;
+        contents.newtype_variant_seed(TypedValue(sig.as_bytes()))
+    }
+
+    infer_value!(
+        visit_bool(bool),
+        visit_i8(i8),
+        visit_i16(i16),
+        visit_i32(i32),
+        visit_i64(i64),
+        visit_u8(u8),
+        visit_u16(u16),
+        visit_u32(u32),
+        visit_u64(u64),
+        visit_f64(f64),
+        visit_str(&str)
+    );
+
+    fn visit_some<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<Value, D::Error> {
+        InferredValue.visit_some(deserializer)
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<Value, D::Error> {
+        InferredValue.visit_newtype_struct(deserializer)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> std::result::Result<Value, A::Error> {
+        InferredValue.visit_seq(seq)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> std::result::Result<Value, A::Error> {
+        InferredValue.visit_map(map)
+    }
+}
+
+// Deserializes a value of the given signature, which is a single complete
+// type.
+struct TypedValue<'s>(&'s [u8]);
+
+impl<'de, 's> DeserializeSeed<'de> for TypedValue<'s> {
+    type Value = Value;
+
+    fn deserialize<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<Value, D::Error> {
+        let sig = self.0;
+        let sig_str = || String::from_utf8_lossy(sig).into_owned();
+        let signature = |sig: &[u8]| {
+            Signature::new(String::from_utf8_lossy(sig).into_owned()).map_err(de::Error::custom)
+        };
+        match sig.first() {
+            Some(b'y') => u8::deserialize(deserializer).map(Value::Byte),
+            Some(b'b') => bool::deserialize(deserializer).map(Value::Bool),
+            Some(b'n') => i16::deserialize(deserializer).map(Value::Int16),
+            Some(b'q') => u16::deserialize(deserializer).map(Value::UInt16),
+            Some(b'i') => i32::deserialize(deserializer).map(Value::Int32),
+            Some(b'u') => u32::deserialize(deserializer).map(Value::UInt32),
+            Some(b'x') => i64::deserialize(deserializer).map(Value::Int64),
+            Some(b't') => u64::deserialize(deserializer).map(Value::UInt64),
+            Some(b'd') => f64::deserialize(deserializer).map(Value::Double),
+            Some(b's') => String::deserialize(deserializer).map(Value::String),
+            Some(b'o') => ObjectPath::deserialize(deserializer).map(Value::ObjectPath),
+            Some(b'g') => Signature::deserialize(deserializer).map(Value::Signature),
+            Some(b'h') => UnixFdIndex::deserialize(deserializer).map(Value::UnixFd),
+            Some(b'v') => deserializer.deserialize_newtype_struct(VARIANT_NAME, VariantVisitor),
+            Some(b'a') if sig.get(1) == Some(&b'{') => {
+                let key_end = single_type_end(sig, 2).map_err(de::Error::custom)?;
+                let value_end = single_type_end(sig, key_end).map_err(de::Error::custom)?;
+                let dict = Dict::new(
+                    signature(&sig[2..key_end])?,
+                    signature(&sig[key_end..value_end])?,
+                )
+                .map_err(de::Error::custom)?;
+                deserializer.deserialize_map(DictVisitor(dict))
+            }
+            Some(b'a') => {
+                let array = Array::new(signature(&sig[1..])?).map_err(de::Error::custom)?;
+                deserializer.deserialize_seq(ArrayVisitor(array))
+            }
+            Some(b'(') => {
+                let mut fields = Vec::new();
+                let mut ix = 1;
+                while ix < sig.len() - 1 {
+                    let end = single_type_end(sig, ix).map_err(de::Error::custom)?;
+                    fields.push(&sig[ix..end]);
+                    ix = end;
+                }
+                deserializer.deserialize_tuple(fields.len(), StructVisitor(fields))
+            }
+            _ => Err(de::Error::custom(format!(
+                "invalid signature {:?}",
+                sig_str()
+            ))),
+        }
+    }
+}
+
+struct VariantVisitor;
+
+impl<'de> Visitor<'de> for VariantVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("a DBus variant")
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<Value, D::Error> {
+        Value::deserialize(deserializer).map(Value::into_variant)
+    }
+}
+
+struct ArrayVisitor(Array);
+
+impl<'de> Visitor<'de> for ArrayVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("a DBus array")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<Value, A::Error> {
+        let ArrayVisitor(mut array) = self;
+        let item_sig = array.item_signature.clone();
+        while let Some(item) = seq.next_element_seed(TypedValue(item_sig.as_str().as_bytes()))? {
+            array.items.push(item);
+        }
+        Ok(Value::Array(array))
+    }
+}
+
+struct DictVisitor(Dict);
+
+impl<'de> Visitor<'de> for DictVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("a DBus dict")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<Value, A::Error> {
+        let DictVisitor(mut dict) = self;
+        let key_sig = dict.key_signature.clone();
+        let value_sig = dict.value_signature.clone();
+        while let Some(key) = map.next_key_seed(TypedValue(key_sig.as_str().as_bytes()))? {
+            let value = map.next_value_seed(TypedValue(value_sig.as_str().as_bytes()))?;
+            dict.entries.push((key, value));
+        }
+        Ok(Value::Dict(dict))
+    }
+}
+
+struct StructVisitor<'s>(Vec<&'s [u8]>);
+
+impl<'de, 's> Visitor<'de> for StructVisitor<'s> {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "a DBus struct of {} fields", self.0.len())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<Value, A::Error> {
+        let mut fields = Vec::new();
+        for (ix, sig) in self.0.iter().enumerate() {
+            match seq.next_element_seed(TypedValue(sig))? {
+                Some(field) => fields.push(field),
+                None => return Err(de::Error::invalid_length(ix, &self)),
+            }
+        }
+        Ok(Value::Struct(fields))
+    }
+}
+
+// Deserializes a value from another format, inferring its signature.
+struct InferredValue;
+
+impl InferredValue {
+    // Items that do not all have the same signature are wrapped in
+    // variants, as the serializer does.
+    fn item_signature(items: &mut [Value], default: &str) -> Signature {
+        let sig = match items.first() {
+            Some(first) => first.signature(),
+            None => default.to_owned(),
+        };
+        if items.iter().all(|item| item.signature() == sig) {
+            if let Ok(sig) = Signature::new(sig) {
+                return sig;
+            }
+        }
+        for item in items.iter_mut() {
+            let inner = std::mem::replace(item, Value::Bool(false));
+            *item = inner.into_variant();
+        }
+        Signature::new("v").expect("valid signature")
+    }
+}
+
+impl<'de> Visitor<'de> for InferredValue {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("a value")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> std::result::Result<Value, E> {
+        Ok(Value::Bool(v))
+    }
+
+    fn visit_i8<E: de::Error>(self, v: i8) -> std::result::Result<Value, E> {
+        Ok(Value::Int16(v.into()))
+    }
+
+    fn visit_i16<E: de::Error>(self, v: i16) -> std::result::Result<Value, E> {
+        Ok(Value::Int16(v))
+    }
+
+    fn visit_i32<E: de::Error>(self, v: i32) -> std::result::Result<Value, E> {
+        Ok(Value::Int32(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> std::result::Result<Value, E> {
+        Ok(Value::Int64(v))
+    }
+
+    fn visit_u8<E: de::Error>(self, v: u8) -> std::result::Result<Value, E> {
+        Ok(Value::Byte(v))
+    }
+
+    fn visit_u16<E: de::Error>(self, v: u16) -> std::result::Result<Value, E> {
+        Ok(Value::UInt16(v))
+    }
+
+    fn visit_u32<E: de::Error>(self, v: u32) -> std::result::Result<Value, E> {
+        Ok(Value::UInt32(v))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> std::result::Result<Value, E> {
+        Ok(Value::UInt64(v))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> std::result::Result<Value, E> {
+        Ok(Value::Double(v))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> std::result::Result<Value, E> {
+        Ok(Value::String(v.to_owned()))
+    }
+
+    fn visit_some<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<Value, A::Error> {
+        let mut items = Vec::new();
+        while let Some(item) = seq.next_element_seed(InferredSeed)? {
+            items.push(item);
+        }
+        let item_signature = InferredValue::item_signature(&mut items, "v");
+        Ok(Value::Array(Array {
+            item_signature,
+            items,
+        }))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<Value, A::Error> {
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+        while let Some((key, value)) = map.next_entry_seed(InferredSeed, InferredSeed)? {
+            keys.push(key);
+            values.push(value);
+        }
+        let key_signature = match keys.first() {
+            Some(first) => first.signature(),
+            None => "s".to_owned(),
+        };
+        let key_signature = Signature::new(key_signature).map_err(de::Error::custom)?;
+        let value_signature = InferredValue::item_signature(&mut values, "v");
+        let mut dict = Dict::new(key_signature, value_signature).map_err(de::Error::custom)?;
+        for (key, value) in keys.into_iter().zip(values) {
+            dict.push(key, value).map_err(de::Error::custom)?;
+        }
+        Ok(Value::Dict(dict))
+    }
+}
+
+struct InferredSeed;
+
+impl<'de> DeserializeSeed<'de> for InferredSeed {
+    type Value = Value;
+
+    fn deserialize<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<Value, D::Error> {
+        deserializer.deserialize_any(InferredValue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::de::from_message;
+    use crate::error::{Error, Result};
+    use crate::ser::{serialize, serialize_with_signature};
+    use crate::types::{ObjectPath, Signature, UnixFdIndex};
+    use crate::value::{Array, Dict, Value};
+    use serde::de::{self, IntoDeserializer};
+    use serde::Deserialize;
+    use std::collections::{BTreeMap, HashMap};
+
+    fn sig(s: &str) -> Signature {
+        Signature::new(s).unwrap()
+    }
+
+    #[test]
+    fn round_trip_exact_signatures() -> Result<()> {
+        let mut props = Dict::new(sig("s"), sig("v"))?;
+        props.push(
+            "empty".into(),
+            Value::Array(Array::new(sig("(nq)"))?).into_variant(),
+        )?;
+        props.push(
+            "none".into(),
+            Value::Dict(Dict::new(sig("o"), sig("ay"))?).into_variant(),
+        )?;
+        props.push(
+            "deep".into(),
+            Value::from(7u16).into_variant().into_variant(),
+        )?;
+        let value = Value::Struct(vec![
+            Value::Byte(1),
+            Value::Bool(true),
+            Value::Int16(-2),
+            Value::Int64(-3),
+            Value::UInt64(4),
+            Value::Double(0.5),
+            Value::ObjectPath(ObjectPath::new("/a")?),
+            Value::Signature(sig("a{sv}")),
+            Value::UnixFd(UnixFdIndex(0)),
+            Value::Array(Array::from_items(
+                sig("ay"),
+                vec![
+                    Value::Array(Array::new(sig("y"))?),
+                    Value::Array(Array::from_items(sig("y"), vec![Value::Byte(5)])?),
+                ],
+            )?),
+            Value::Dict(props),
+        ]);
+        let expected = "(ybnxtdoghaaya{sv})";
+        assert_eq!(value.signature(), expected);
+
+        let message = serialize(&value)?;
+        assert_eq!(message.signature, expected.as_bytes().to_vec());
+        let value2: Value = from_message(&message)?;
+        assert_eq!(value2, value);
+        assert_eq!(serialize(&value2)?, message);
+        Ok(())
+    }
+
+    #[test]
+    fn inspect_unknown_dict() -> Result<()> {
+        let mut sent = HashMap::new();
+        sent.insert("a", vec![1u32, 2]);
+        let message = serialize_with_signature(&sent, "a{sv}")?;
+
+        let value: Value = from_message(&message)?;
+        let dict = match &value {
+            Value::Dict(dict) => dict,
+            other => panic!("not a dict: {:?}", other),
+        };
+        assert_eq!(dict.value_signature().as_str(), "v");
+        match dict.get(&"a".into()) {
+            Some(Value::Variant(inner)) => assert_eq!(inner.signature(), "au"),
+            other => panic!("not a variant: {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn inferred_from_other_formats() -> std::result::Result<(), de::value::Error> {
+        let value = Value::deserialize(vec![1u32, 2].into_deserializer())?;
+        assert_eq!(value.signature(), "au");
+
+        let mut map = BTreeMap::new();
+        map.insert("a".to_owned(), 1i64);
+        let value = Value::deserialize(map.clone().into_deserializer())?;
+        assert_eq!(value.signature(), "a{sx}");
+        let mut mixed = BTreeMap::new();
+        mixed.insert("a", vec![1u8]);
+        mixed.insert("b", vec![]);
+        let value = Value::deserialize(mixed.into_deserializer())?;
+        assert_eq!(value.signature(), "a{sv}");
+        Ok(())
+    }
+
+    #[test]
+    fn serialized_to_other_formats() -> Result<()> {
+        let mut dict = Dict::new(sig("s"), sig("v"))?;
+        dict.push("a".into(), Value::from(1u8).into_variant())?;
+        let value = Value::Struct(vec![
+            Value::Array(Array::new(sig("u"))?),
+            Value::Dict(Dict::new(sig("s"), sig("v"))?),
+            Value::Dict(dict),
+        ]);
+        assert_eq!(serde_json::to_string(&value).unwrap(), r#"[[],{},{"a":1}]"#);
+
+        // This crate's serializer still gets the item signatures.
+        let message = serialize(&value)?;
+        assert_eq!(message.signature, b"(aua{sv}a{sv})".to_vec());
+        assert_eq!(from_message::<Value>(&message)?, value);
+        Ok(())
+    }
+
+    #[test]
+    fn checked_construction() -> Result<()> {
+        assert_eq!(
+            Array::from_items(sig("s"), vec![Value::UInt32(1)]),
+            Err(Error::MismatchSignature(b"s".to_vec(), b"u".to_vec()))
+        );
+        assert_eq!(
+            Array::new(sig("ss")),
+            Err(Error::NotSingleType(b"ss".to_vec()))
+        );
+        assert_eq!(
+            Dict::new(sig("(s)"), sig("v")),
+            Err(Error::NonBasicDictKey(b"(s)".to_vec()))
+        );
+        Ok(())
+    }
+}